@@ -0,0 +1,170 @@
+use crate::sensor::measurement::Measurement;
+use std::time::Duration;
+
+pub const MAX_ALARMS: usize = 4;
+/// u8 stream + u16 threshold + u16 hysteresis + u16 min duration (s) + u8 flags
+pub const ALARM_CONFIG_SIZE: usize = 8;
+const FLAG_LED_ALERT: u8 = 0x01;
+
+/// Threshold on a single stream. Trips once the value stays at or above `threshold`
+/// for `min_duration`, clears once it drops to `threshold - hysteresis` or below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmConfig {
+    pub stream: u8,
+    pub threshold: u16,
+    pub hysteresis: u16,
+    pub min_duration: Duration,
+    pub led_alert: bool,
+}
+
+impl AlarmConfig {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < ALARM_CONFIG_SIZE {
+            return None;
+        }
+        Some(Self {
+            stream: data[0],
+            threshold: u16::from_le_bytes([data[1], data[2]]),
+            hysteresis: u16::from_le_bytes([data[3], data[4]]),
+            min_duration: Duration::from_secs(u16::from_le_bytes([data[5], data[6]]) as u64),
+            led_alert: data[7] & FLAG_LED_ALERT != 0,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.stream;
+        buf[1..3].copy_from_slice(&self.threshold.to_le_bytes());
+        buf[3..5].copy_from_slice(&self.hysteresis.to_le_bytes());
+        buf[5..7].copy_from_slice(&(self.min_duration.as_secs() as u16).to_le_bytes());
+        buf[7] = if self.led_alert { FLAG_LED_ALERT } else { 0 };
+    }
+
+    fn clear_level(&self) -> u16 {
+        self.threshold.saturating_sub(self.hysteresis)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AlarmState {
+    Idle,
+    /// Above threshold since `since`, not yet for `min_duration`.
+    Pending {
+        since: u32,
+    },
+    Active {
+        since: u32,
+        peak: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AlarmEvent {
+    Started {
+        alarm: u8,
+        config: AlarmConfig,
+        since: u32,
+        value: u16,
+    },
+    Ended(AlarmRecord),
+}
+
+/// A finished (or cut short by session end) alarm, as written to storage.
+#[derive(Debug, Clone, Copy)]
+pub struct AlarmRecord {
+    pub alarm: u8,
+    pub stream: u8,
+    pub start: u32,
+    pub end: u32,
+    pub peak: u16,
+}
+
+pub struct AlarmMonitor {
+    alarms: Vec<(AlarmConfig, AlarmState)>,
+}
+
+impl AlarmMonitor {
+    pub fn new(configs: &[AlarmConfig]) -> Self {
+        Self {
+            alarms: configs
+                .iter()
+                .take(MAX_ALARMS)
+                .map(|c| (*c, AlarmState::Idle))
+                .collect(),
+        }
+    }
+
+    /// True while any alarm with an LED alert is tripped.
+    pub fn led_alert(&self) -> bool {
+        self.alarms
+            .iter()
+            .any(|(c, s)| c.led_alert && matches!(s, AlarmState::Active { .. }))
+    }
+
+    pub fn update(&mut self, measurement: &Measurement) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        let now = measurement.timestamp;
+        for (i, (config, state)) in self.alarms.iter_mut().enumerate() {
+            let Some(&value) = measurement.values().get(config.stream as usize) else {
+                continue;
+            };
+            *state = match *state {
+                AlarmState::Idle | AlarmState::Pending { .. } if value < config.threshold => {
+                    AlarmState::Idle
+                }
+                AlarmState::Idle | AlarmState::Pending { .. } => {
+                    let since = match *state {
+                        AlarmState::Pending { since } => since,
+                        _ => now,
+                    };
+                    if now.saturating_sub(since) as u64 >= config.min_duration.as_secs() {
+                        events.push(AlarmEvent::Started {
+                            alarm: i as u8,
+                            config: *config,
+                            since,
+                            value,
+                        });
+                        AlarmState::Active { since, peak: value }
+                    } else {
+                        AlarmState::Pending { since }
+                    }
+                }
+                AlarmState::Active { since, peak } => {
+                    if value <= config.clear_level() {
+                        events.push(AlarmEvent::Ended(AlarmRecord {
+                            alarm: i as u8,
+                            stream: config.stream,
+                            start: since,
+                            end: now,
+                            peak,
+                        }));
+                        AlarmState::Idle
+                    } else {
+                        AlarmState::Active {
+                            since,
+                            peak: peak.max(value),
+                        }
+                    }
+                }
+            };
+        }
+        events
+    }
+
+    /// Close every active alarm at `end`, e.g. when the session stops.
+    pub fn finish(&mut self, end: u32) -> Vec<AlarmRecord> {
+        let mut records = Vec::new();
+        for (i, (config, state)) in self.alarms.iter_mut().enumerate() {
+            if let AlarmState::Active { since, peak } = *state {
+                records.push(AlarmRecord {
+                    alarm: i as u8,
+                    stream: config.stream,
+                    start: since,
+                    end,
+                    peak,
+                });
+            }
+            *state = AlarmState::Idle;
+        }
+        records
+    }
+}
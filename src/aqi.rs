@@ -0,0 +1,229 @@
+use log::info;
+
+/// One linear segment of an air quality index: concentrations in `c_lo..=c_hi`
+/// map onto index values `i_lo..=i_hi`.
+#[derive(Debug, Clone, Copy)]
+pub struct Breakpoint {
+    pub c_lo: f32,
+    pub c_hi: f32,
+    pub i_lo: u16,
+    pub i_hi: u16,
+}
+
+/// A national index for a single pollutant. Concentrations are truncated to
+/// `resolution` before the breakpoint lookup, as the published tables require.
+pub struct AqiScale {
+    pub name: &'static str,
+    pub resolution: f32,
+    pub breakpoints: &'static [Breakpoint],
+}
+
+/// US EPA PM2.5 AQI, 2024 revision (µg/m³, 24 h / NowCast).
+pub const US_EPA_PM2_5: AqiScale = AqiScale {
+    name: "US EPA PM2.5",
+    resolution: 0.1,
+    breakpoints: &[
+        Breakpoint {
+            c_lo: 0.0,
+            c_hi: 9.0,
+            i_lo: 0,
+            i_hi: 50,
+        },
+        Breakpoint {
+            c_lo: 9.1,
+            c_hi: 35.4,
+            i_lo: 51,
+            i_hi: 100,
+        },
+        Breakpoint {
+            c_lo: 35.5,
+            c_hi: 55.4,
+            i_lo: 101,
+            i_hi: 150,
+        },
+        Breakpoint {
+            c_lo: 55.5,
+            c_hi: 125.4,
+            i_lo: 151,
+            i_hi: 200,
+        },
+        Breakpoint {
+            c_lo: 125.5,
+            c_hi: 225.4,
+            i_lo: 201,
+            i_hi: 300,
+        },
+        Breakpoint {
+            c_lo: 225.5,
+            c_hi: 325.4,
+            i_lo: 301,
+            i_hi: 500,
+        },
+    ],
+};
+
+impl AqiScale {
+    /// Index for a concentration. Values past the top breakpoint are reported
+    /// as the top of the scale.
+    pub fn index(&self, concentration: f32) -> u16 {
+        let c = (concentration.max(0.0) / self.resolution + 1e-3).floor() * self.resolution;
+        for bp in self.breakpoints {
+            // Segments are contiguous only at the table resolution, so anything
+            // below the next c_lo still belongs to this segment.
+            if c <= bp.c_hi + self.resolution / 2.0 {
+                let c = c.max(bp.c_lo);
+                let slope = (bp.i_hi - bp.i_lo) as f32 / (bp.c_hi - bp.c_lo);
+                return (slope * (c - bp.c_lo) + bp.i_lo as f32).round() as u16;
+            }
+        }
+        self.breakpoints.last().map_or(0, |bp| bp.i_hi)
+    }
+}
+
+const NOWCAST_HOURS: usize = 12;
+
+#[derive(Clone, Copy, Default)]
+struct HourBucket {
+    hour: u32,
+    sum: u32,
+    count: u32,
+}
+
+/// EPA NowCast over the last 12 clock hours of measurements.
+#[derive(Default)]
+pub struct NowCast {
+    buckets: [HourBucket; NOWCAST_HOURS],
+}
+
+impl NowCast {
+    pub fn push(&mut self, timestamp: u32, concentration: u16) {
+        let hour = timestamp / 3600;
+        let bucket = &mut self.buckets[hour as usize % NOWCAST_HOURS];
+        if bucket.hour != hour || bucket.count == 0 {
+            *bucket = HourBucket {
+                hour,
+                sum: 0,
+                count: 0,
+            };
+        }
+        bucket.sum += concentration as u32;
+        bucket.count += 1;
+    }
+
+    fn hourly_average(&self, hour: u32) -> Option<f32> {
+        let bucket = &self.buckets[hour as usize % NOWCAST_HOURS];
+        (bucket.count > 0 && bucket.hour == hour).then(|| bucket.sum as f32 / bucket.count as f32)
+    }
+
+    /// Weighted concentration as of the hour containing `timestamp`. Needs at least
+    /// two of the three most recent hours, otherwise the value is not defined.
+    pub fn concentration(&self, timestamp: u32) -> Option<f32> {
+        let current = timestamp / 3600;
+        let hours: Vec<Option<f32>> = (0..NOWCAST_HOURS as u32)
+            .map(|i| current.checked_sub(i).and_then(|h| self.hourly_average(h)))
+            .collect();
+        if hours[..3].iter().filter(|h| h.is_some()).count() < 2 {
+            return None;
+        }
+
+        let present = hours.iter().flatten();
+        let max = present.clone().fold(f32::MIN, |a, &b| a.max(b));
+        let min = present.fold(f32::MAX, |a, &b| a.min(b));
+        let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+
+        let mut numerator = 0.0;
+        let mut denominator = 0.0;
+        let mut factor = 1.0;
+        for c in &hours {
+            if let Some(c) = c {
+                numerator += factor * c;
+                denominator += factor;
+            }
+            factor *= weight;
+        }
+        Some(numerator / denominator)
+    }
+}
+
+/// AQI values carried alongside each live measurement.
+#[derive(Debug, Clone, Copy)]
+pub struct AqiReading {
+    pub instant: u16,
+    pub nowcast: Option<u16>,
+}
+
+/// Tracks PM2.5 for the running session and turns it into index values.
+pub struct AqiTracker {
+    scale: &'static AqiScale,
+    nowcast: NowCast,
+}
+
+impl AqiTracker {
+    pub fn new(scale: &'static AqiScale) -> Self {
+        info!("AQI scale: {}", scale.name);
+        Self {
+            scale,
+            nowcast: NowCast::default(),
+        }
+    }
+
+    pub fn update(&mut self, timestamp: u32, pm2_5: u16) -> AqiReading {
+        self.nowcast.push(timestamp, pm2_5);
+        AqiReading {
+            instant: self.scale.index(pm2_5 as f32),
+            nowcast: self
+                .nowcast
+                .concentration(timestamp)
+                .map(|c| self.scale.index(c)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of an arbitrary clock hour.
+    const HOUR: u32 = 480_000 * 3600;
+
+    fn nowcast(hours: &[(u32, u16)]) -> NowCast {
+        let mut nowcast = NowCast::default();
+        for &(hours_ago, concentration) in hours {
+            nowcast.push(HOUR - hours_ago * 3600, concentration);
+        }
+        nowcast
+    }
+
+    #[test]
+    fn epa_breakpoint_edges() {
+        let index = |c| US_EPA_PM2_5.index(c);
+        assert_eq!(index(0.0), 0);
+        assert_eq!(index(9.0), 50);
+        assert_eq!(index(9.09), 50);
+        assert_eq!(index(9.1), 51);
+        assert_eq!(index(35.4), 100);
+        assert_eq!(index(35.5), 101);
+        assert_eq!(index(325.4), 500);
+        assert_eq!(index(325.5), 500);
+        assert_eq!(index(1000.0), 500);
+    }
+
+    #[test]
+    fn nowcast_weight_is_at_least_one_half() {
+        // Hourly averages of 30 and 20 weigh the older one by 20 / 30
+        let c = nowcast(&[(1, 20), (0, 30)]).concentration(HOUR).unwrap();
+        assert!((c - 26.0).abs() < 1e-3, "{}", c);
+        // 10 / 100 is below the floor, so the older hour still weighs one half
+        let c = nowcast(&[(1, 10), (0, 100)]).concentration(HOUR).unwrap();
+        assert!((c - 70.0).abs() < 1e-3, "{}", c);
+    }
+
+    #[test]
+    fn nowcast_needs_two_of_the_last_three_hours() {
+        assert_eq!(nowcast(&[(0, 10)]).concentration(HOUR), None);
+        assert_eq!(nowcast(&[(3, 10), (0, 10)]).concentration(HOUR), None);
+        assert_eq!(nowcast(&[(2, 10), (0, 10)]).concentration(HOUR), Some(10.0));
+        // The current hour need not be one of them
+        assert_eq!(nowcast(&[(2, 10), (1, 10)]).concentration(HOUR), Some(10.0));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::ble::sync_batch_capacity;
 use crate::sensor::measurement::Measurement;
 use crate::storage::session_config::{SessionConfig, SessionType};
 use crate::storage::storage_controller::StorageManager;
@@ -9,10 +10,10 @@ pub fn sync_from_storage<F>(
     mut send_fn: F,
 ) -> Result<(), SyncError>
 where
-    F: FnMut(&Vec<Measurement>) -> Result<(), SendingError>,
+    F: FnMut(&[Measurement], u16) -> Result<(), SendingError>,
 {
     let batch_size = if let SessionType::MOBILE = config.session_type {
-        30
+        sync_batch_capacity(storage.stream_count())
     } else {
         let free = unsafe { esp_idf_svc::sys::esp_get_free_heap_size() } as usize;
         let per_record = size_of::<Measurement>() + 4;
@@ -23,22 +24,41 @@ where
         batch.clamp(10, 500)
     };
 
-    let iter = storage.iter_measurements().ok_or(SyncError::GetStorage)?;
+    let iter = storage
+        .iter_forward(&config.session_uuid)
+        .ok_or(SyncError::GetStorage)?;
+    if iter
+        .header()
+        .is_some_and(|header| header.session != config.session_uuid)
+    {
+        return Err(SyncError::SessionMismatch);
+    }
 
+    let sync = storage.session_sync(&config.session_uuid);
+    // A batch left pending by a reset is sent again exactly as before.
+    let pending_end = sync.pending_end();
     let mut measurements: Vec<Measurement> = Vec::with_capacity(batch_size);
-    let mut bytes_to_remove = 0;
+    let mut synced = 0;
 
+    // Oldest first, so whatever is left after a failure is the newest data
     for line in iter {
-        if measurements.len() + line.measurements.len() > batch_size {
+        if measurements.len() + line.measurements.len() > batch_size || pending_end == Some(synced)
+        {
             break;
         }
         measurements.extend(line.measurements);
-        bytes_to_remove = line.offset_from_end
+        synced = line.end_offset
+    }
+    if measurements.is_empty() {
+        return Ok(());
     }
 
-    match send_fn(&measurements) {
+    let batch = sync
+        .begin(synced, &measurements)
+        .map_err(|_| SyncError::PersistBatch)?;
+    match send_fn(&measurements, batch) {
         Ok(()) => {
-            if let Ok(()) = storage.remove_last(bytes_to_remove as usize) {
+            if let Ok(()) = sync.commit() {
                 Ok(())
             } else {
                 Err(SyncError::RemoveStorage)
@@ -51,6 +71,8 @@ where
 pub enum SyncError {
     GetStorage,
     RemoveStorage,
+    PersistBatch,
     Send,
     NoHeapSpace,
+    SessionMismatch,
 }
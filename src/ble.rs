@@ -1,12 +1,18 @@
 pub mod ble_protocol;
 
-use crate::ble::ble_protocol::{AppCommand, DeviceResponse, DeviceStatus, ErrorCode};
+use crate::aqi::AqiReading;
+use crate::ble::ble_protocol::{
+    AppCommand, DeviceResponse, DeviceStatus, ErrorCode, MAX_LISTED_SESSIONS,
+};
 use crate::led::led_thread::LedStates;
 use crate::sensor::measurement::Measurement;
 use crate::storage::session_config::{SessionConfig, SessionType};
-use crate::storage::storage_iterator::MeasurementIter;
+use crate::storage::stats::StorageStats;
+use crate::storage::storage_controller::{SessionSync, StoredSession};
+use crate::storage::storage_iterator::{ForwardMeasurementIter, TimeRangeIter};
+use crate::storage::sync_state::RANGE_BATCH;
 use crate::wifi::wifi_manager::SyncStatus;
-use crate::{LoopEvent, SendingError};
+use crate::{set_clock, LoopEvent, SendingError};
 use esp32_nimble::enums::AuthReq;
 use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
 use esp32_nimble::utilities::BleUuid;
@@ -14,9 +20,8 @@ use esp32_nimble::{
     enums::{ConnMode, DiscMode},
     uuid128, BLEAdvertisementData, BLECharacteristic, BLEDevice, NimbleProperties, NotifyTxStatus,
 };
-use esp_idf_svc::sys::{settimeofday, timeval};
 use log::{error, info, warn};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
@@ -31,6 +36,18 @@ const RESPONSE_CHAR_UUID: BleUuid = uuid128!("a0e1f000-0004-4b3c-8e9a-1f2d3c4b5a
 const MEASUREMENT_CHAR_UUID: BleUuid = uuid128!("a0e1f000-0005-4b3c-8e9a-1f2d3c4b5a60");
 const SYNC_CHAR_UUID: BleUuid = uuid128!("a0e1f000-0006-4b3c-8e9a-1f2d3c4b5a60");
 const FIXED_SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+const INDICATION_SIZE: usize = 244;
+const SYNC_HEADER_SIZE: usize = 3; // u8 count + u16 batch id
+
+/// Number of records that fit in a single sync indication for the given stream count,
+/// each with its flag byte.
+pub fn sync_batch_capacity(stream_count: usize) -> usize {
+    (INDICATION_SIZE - SYNC_HEADER_SIZE) / (record_size(stream_count) + 1)
+}
+
+fn record_size(stream_count: usize) -> usize {
+    4 + 2 * stream_count // u32 timestamp + u16 per stream
+}
 #[derive(Debug)]
 pub enum SetupResult {
     Continue,
@@ -49,6 +66,8 @@ pub struct BleManager {
     cmd_rx: std::sync::mpsc::Receiver<AppCommand>,
     // true while run_setup is active; gates connect/disconnect LED side-effects
     setup_active: Arc<AtomicBool>,
+    // STATUS_FLAG_* bits appended to Idle/HasSavedSession/Running statuses
+    status_flags: AtomicU8,
     // keep server alive
     _ble_device: &'static BLEDevice,
 }
@@ -179,33 +198,43 @@ impl BleManager {
             notify_status_sync: notify_status_sync_rx,
             cmd_rx,
             setup_active,
+            status_flags: AtomicU8::new(0),
             _ble_device: ble_device,
         })
     }
 
     /// Run the setup handshake. Blocks the calling thread until a config is obtained.
-    pub fn run_setup<F0, F1, F2, F3, F4, F5, W>(
+    /// The saved session is only continued if `can_continue`, see
+    /// `StorageManager::can_continue`.
+    pub fn run_setup<'s, F0, F1, F2, F3, F4, F5, L, W, D>(
         &mut self,
         saved_config: Option<SessionConfig>,
+        can_continue: bool,
+        sensor_info: &str,
         has_measurements: bool,
         file_size: Option<u64>,
+        stats: Option<StorageStats>,
         mut battery_level: F0,
-        clear_storage: F1,
+        delete_session: F1,
         start_wifi_sync: F2,
         stop_wifi_sync: F3,
         get_measurements_iter: F4,
-        delete_measurements: F5,
+        session_sync: F5,
+        list_sessions: L,
         connect_to_wifi: W,
+        mut device_command: D,
         led_command: Sender<LedStates>,
     ) -> anyhow::Result<SetupResult>
     where
         F0: FnMut() -> i8,
-        F1: Fn() -> anyhow::Result<()>,
+        F1: Fn(&Uuid) -> anyhow::Result<()>,
         F2: Fn() -> anyhow::Result<Receiver<SyncStatus>>,
         F3: Fn(),
-        F4: Fn() -> Option<MeasurementIter>,
-        F5: Fn(usize) -> anyhow::Result<()>,
+        F4: Fn(&Uuid) -> Option<ForwardMeasurementIter>,
+        F5: Fn(&Uuid) -> SessionSync<'s>,
+        L: Fn() -> Vec<StoredSession>,
         W: Fn(&str, &str) -> anyhow::Result<()>,
+        D: FnMut(AppCommand) -> DeviceResponse,
     {
         struct SetupGuard<'a>(&'a AtomicBool);
         impl<'a> Drop for SetupGuard<'a> {
@@ -218,7 +247,7 @@ impl BleManager {
 
         //reconnect wifi on fixed session after timeout
         if !self.wait_for_connection(Self::get_timeout(saved_config.clone()))? {
-            if let Some(config) = saved_config.clone() {
+            if let Some(config) = saved_config.clone().filter(|_| can_continue) {
                 if let SessionType::FIXED {
                     wifi_ssid,
                     wifi_password,
@@ -245,9 +274,14 @@ impl BleManager {
                 } else {
                     0
                 },
+                flags: self.status_flags(),
+                stats,
             }
         } else {
-            DeviceStatus::Idle(battery_level())
+            DeviceStatus::Idle {
+                battery_level: battery_level(),
+                flags: self.status_flags(),
+            }
         };
 
         self.notify_status(&status)?;
@@ -257,27 +291,26 @@ impl BleManager {
             let cmd = self.cmd_rx.recv()?;
 
             match cmd {
-                AppCommand::ContinueSession => {
-                    if has_measurements {
-                        self.send_response(DeviceResponse::Nack(
-                            ErrorCode::StorageHasMeasurements,
-                        ))?;
-                    } else {
-                        match saved_config {
-                            Some(_) => {
-                                self.send_response(DeviceResponse::Ack)?;
-                                return Ok(SetupResult::Continue);
-                            }
-                            None => {
-                                self.send_response(DeviceResponse::Nack(ErrorCode::NoSession))?;
-                            }
-                        }
+                // Measurements already stored for the session are kept, new ones are appended.
+                AppCommand::ContinueSession => match saved_config {
+                    Some(_) if can_continue => {
+                        self.send_response(DeviceResponse::Ack)?;
+                        return Ok(SetupResult::Continue);
                     }
-                }
+                    Some(_) => {
+                        self.send_response(DeviceResponse::Nack(ErrorCode::StreamsChanged))?;
+                    }
+                    None => {
+                        self.send_response(DeviceResponse::Nack(ErrorCode::NoSession))?;
+                    }
+                },
 
                 AppCommand::DiscardSession => {
                     self.send_response(DeviceResponse::Ack)?;
-                    match clear_storage() {
+                    let result = saved_config
+                        .as_ref()
+                        .map_or(Ok(()), |config| delete_session(&config.session_uuid));
+                    match result {
                         Ok(()) => self.send_response(DeviceResponse::Ready)?,
                         Err(_) => {
                             self.send_response(DeviceResponse::Nack(ErrorCode::ClearStorageFailed))?
@@ -316,49 +349,58 @@ impl BleManager {
                     }
                 }
 
-                AppCommand::StartBleSync => {
+                cmd @ (AppCommand::StartBleSync | AppCommand::SyncSession(_)) => {
+                    let session = match cmd {
+                        AppCommand::SyncSession(session) => Some(session),
+                        _ => saved_config.as_ref().map(|config| config.session_uuid),
+                    };
+                    let Some((session, iter)) = session
+                        .and_then(|session| Some((session, get_measurements_iter(&session)?)))
+                    else {
+                        self.send_response(DeviceResponse::Nack(ErrorCode::UnknownSession))?;
+                        continue;
+                    };
+                    self.send_response(DeviceResponse::Ack)?;
+                    let _ = led_command.send(LedStates::BleSync);
+                    self.sync_session(iter, session_sync(&session))?;
+                    let _ = led_command.send(LedStates::BleConnected);
+                }
+
+                AppCommand::SyncRange { session, from, to } => {
+                    let Some(iter) = get_measurements_iter(&session) else {
+                        self.send_response(DeviceResponse::Nack(ErrorCode::UnknownSession))?;
+                        continue;
+                    };
                     self.send_response(DeviceResponse::Ack)?;
-                    self.notify_status(&DeviceStatus::ReadyToSync {
-                        file_size: file_size.unwrap_or(1),
-                        password: "".to_string(),
-                    })?;
                     let _ = led_command.send(LedStates::BleSync);
-                    std::thread::sleep(Duration::from_millis(100)); //let app prepare for sync
-                    let measurements_iter = get_measurements_iter().unwrap();
-                    let mut measurements: Vec<Measurement> = Vec::with_capacity(30);
-                    for line in measurements_iter {
-                        let new = line.measurements;
-                        if new.len() + measurements.len() > 30 {
-                            if let Err(_) = self.send_measurements(&measurements) {
-                                self.send_response(DeviceResponse::Nack(ErrorCode::SyncFailed))?;
-                                break;
-                            } else {
-                                let _ = delete_measurements(measurements.len());
-                            }
-                            measurements.clear();
-                        }
-                        measurements.extend(new);
-                    }
-                    if !measurements.is_empty() {
-                        if self.send_measurements(&measurements).is_err() {
-                            self.send_response(DeviceResponse::Nack(ErrorCode::SyncFailed))?;
-                        } else {
-                            if clear_storage().is_err() {
-                                self.send_response(DeviceResponse::Nack(
-                                    ErrorCode::ClearStorageFailed,
-                                ))?;
-                            };
-                        }
-                    }
+                    self.sync_range(iter.time_range(from, to))?;
                     let _ = led_command.send(LedStates::BleConnected);
-                    self.send_response(DeviceResponse::Ready)?;
                 }
 
+                AppCommand::ListSessions(first) => {
+                    let sessions = list_sessions();
+                    self.send_response(DeviceResponse::Sessions {
+                        total: sessions.len().min(u16::MAX as usize) as u16,
+                        first,
+                        sessions: sessions
+                            .into_iter()
+                            .skip(first as usize)
+                            .take(MAX_LISTED_SESSIONS)
+                            .collect(),
+                    })?;
+                }
+
+                AppCommand::DeleteSession(session) => match delete_session(&session) {
+                    Ok(()) => self.send_response(DeviceResponse::Ack)?,
+                    Err(_) => {
+                        self.send_response(DeviceResponse::Nack(ErrorCode::ClearStorageFailed))?
+                    }
+                },
+
                 AppCommand::NewSessionConfig(config) => {
                     self.send_response(DeviceResponse::Ack)?;
                     if let SessionType::FIXED {
-                        pm1_index: _,
-                        pm2_5_index: _,
+                        stream_indices: _,
                         token: _,
                         wifi_ssid,
                         wifi_password,
@@ -378,24 +420,44 @@ impl BleManager {
                     }
                 }
                 AppCommand::GetSensors => {
-                    self.send_response(DeviceResponse::SensorInfo)?;
+                    self.send_response(DeviceResponse::SensorInfo(sensor_info.to_string()))?;
                     info!("BLE: Return sensors");
                 }
                 AppCommand::SetTime(time_epoch) => {
-                    let tv = timeval {
-                        tv_sec: time_epoch,
-                        tv_usec: 0,
-                    };
-                    unsafe { settimeofday(&tv, std::ptr::null()) };
+                    set_clock(time_epoch);
                     info!("BLE: Set time to {}", time_epoch);
                 }
+                // Device settings and counters handled by the owner of NVS
+                cmd @ (AppCommand::SetAirQualityLed(_)
+                | AppCommand::SetAlarms(_)
+                | AppCommand::GetSensorUsage
+                | AppCommand::SetSensorLifetime(_)
+                | AppCommand::SetStoragePolicy(_)
+                | AppCommand::GetStorageUsage
+                | AppCommand::ScanSession { .. }
+                | AppCommand::GetStorageStats(_)
+                | AppCommand::GetEvents(_)
+                | AppCommand::SetFlushPolicy(_)
+                | AppCommand::GetStorageErrors
+                | AppCommand::GetDigest(_)
+                | AppCommand::SetAggregationPolicy(_)
+                | AppCommand::GetMountIncident(_)
+                | AppCommand::ReformatStorage
+                | AppCommand::SetMountRecovery(_)
+                | AppCommand::GetSalvage(_)) => {
+                    let response = device_command(cmd);
+                    self.send_response(response)?;
+                }
             }
         }
     }
 
+    /// Live record followed by u16 AQI, u16 NowCast AQI (0xFFFF until enough hours are in)
+    /// and the record's u8 quality flags.
     pub fn send_measurement(
         &self,
         measurement: &Measurement,
+        aqi: AqiReading,
         battery_level: i8,
         session: Uuid,
     ) -> Result<(), SendingError> {
@@ -403,41 +465,136 @@ impl BleManager {
             return Err(SendingError::ConnectionError);
         }
 
-        let mut buf = [0u8; 9];
+        let mut buf = [0u8; INDICATION_SIZE];
         buf[0] = 1_u8;
-        buf[1..5].copy_from_slice(measurement.timestamp.to_le_bytes().as_slice());
-        buf[5..7].copy_from_slice(measurement.pm1_0_avg.to_le_bytes().as_slice());
-        buf[7..9].copy_from_slice(measurement.pm2_5_avg.to_le_bytes().as_slice());
+        let mut len = 1 + encode_record(measurement, &mut buf[1..]);
+        buf[len..len + 2].copy_from_slice(&aqi.instant.to_le_bytes());
+        buf[len + 2..len + 4].copy_from_slice(&aqi.nowcast.unwrap_or(u16::MAX).to_le_bytes());
+        buf[len + 4] = measurement.flags;
+        len += 5;
 
-        match self.indicate_measurement_chr(&buf, false) {
+        match self.indicate_measurement_chr(&buf[..len], false) {
             Ok(()) => {
-                let mut status = [0u8; 18];
-                DeviceStatus::Running {
+                let mut status = [0u8; 19];
+                let len = DeviceStatus::Running {
                     battery_level,
                     session,
+                    flags: self.status_flags(),
                 }
                 .encode(&mut status);
-                self.status_chr.lock().set_value(&status).notify();
+                self.status_chr.lock().set_value(&status[..len]).notify();
                 Ok(())
             }
             Err(e) => Err(e),
         }
     }
 
-    pub fn send_measurements(&self, measurements: &[Measurement]) -> Result<(), SendingError> {
-        let mut buf = [0u8; 244];
+    /// Send a session oldest first, removing every batch from its head once the app
+    /// has acknowledged it, so an interrupted sync resumes where it stopped. Batches
+    /// carry their id from `sync`; one the app has seen last already is a resend, which
+    /// it must acknowledge without storing it again.
+    pub fn sync_session(
+        &self,
+        iter: ForwardMeasurementIter,
+        sync: SessionSync,
+    ) -> anyhow::Result<()> {
+        self.notify_status(&DeviceStatus::ReadyToSync {
+            file_size: iter.file_len(),
+            password: "".to_string(),
+        })?;
+        std::thread::sleep(Duration::from_millis(100)); //let app prepare for sync
+        let batch_size = sync_batch_capacity(iter.stream_count());
+        let mut measurements: Vec<Measurement> = Vec::with_capacity(batch_size);
+        // A batch left pending by a reset is sent again exactly as before.
+        let pending_end = sync.pending_end();
+        // Err if the batch was not delivered, Ok(false) if it was but stays on flash.
+        let send_batch = |measurements: &[Measurement], end_offset: u64| {
+            let batch = sync.begin(end_offset, measurements)?;
+            self.send_measurements(measurements, batch)
+                .map_err(|e| anyhow::Error::msg(format!("{:?}", e)))?;
+            anyhow::Ok(sync.commit().is_ok())
+        };
+        let mut synced: u64 = 0;
+        let mut failed = false;
+        for line in iter {
+            if !measurements.is_empty()
+                && (line.measurements.len() + measurements.len() > batch_size
+                    || pending_end == Some(synced))
+            {
+                if send_batch(&measurements, synced).is_err() {
+                    failed = true;
+                    break;
+                }
+                measurements.clear();
+            }
+            synced = line.end_offset;
+            measurements.extend(line.measurements);
+        }
+        if !failed && !measurements.is_empty() {
+            match send_batch(&measurements, synced) {
+                Err(_) => failed = true,
+                Ok(false) => {
+                    self.send_response(DeviceResponse::Nack(ErrorCode::ClearStorageFailed))?
+                }
+                Ok(true) => {}
+            }
+        }
+        if failed {
+            self.send_response(DeviceResponse::Nack(ErrorCode::SyncFailed))?;
+        }
+        self.send_response(DeviceResponse::Ready)
+    }
+
+    /// Send the records of a time range oldest first, leaving the session as it is.
+    /// Every packet carries `RANGE_BATCH` as its batch id: none is committed, and the
+    /// app must store them all rather than dedup them as resends.
+    pub fn sync_range(&self, iter: TimeRangeIter) -> anyhow::Result<()> {
+        self.notify_status(&DeviceStatus::ReadyToSync {
+            file_size: iter.span(),
+            password: "".to_string(),
+        })?;
+        std::thread::sleep(Duration::from_millis(100)); //let app prepare for sync
+        let batch_size = sync_batch_capacity(iter.stream_count());
+        let mut measurements: Vec<Measurement> = Vec::with_capacity(batch_size);
+        let mut failed = false;
+        for measurement in iter.flat_map(|line| line.measurements) {
+            measurements.push(measurement);
+            if measurements.len() == batch_size {
+                if self.send_measurements(&measurements, RANGE_BATCH).is_err() {
+                    failed = true;
+                    break;
+                }
+                measurements.clear();
+            }
+        }
+        if !failed && !measurements.is_empty() {
+            failed = self.send_measurements(&measurements, RANGE_BATCH).is_err();
+        }
+        if failed {
+            self.send_response(DeviceResponse::Nack(ErrorCode::SyncFailed))?;
+        }
+        self.send_response(DeviceResponse::Ready)
+    }
+
+    /// Header, the records, then a u8 of quality flags per record in the same order.
+    pub fn send_measurements(
+        &self,
+        measurements: &[Measurement],
+        batch: u16,
+    ) -> Result<(), SendingError> {
+        let mut buf = [0u8; INDICATION_SIZE];
         let count = measurements.len() as u8;
         buf[0] = count;
-        for (i, measurement) in measurements.iter().enumerate() {
-            let offset = 3 + i * 8;
-            if offset + 8 > buf.len() {
+        buf[1..3].copy_from_slice(&batch.to_le_bytes());
+        let mut offset = SYNC_HEADER_SIZE;
+        for measurement in measurements {
+            if offset + record_size(measurement.values().len()) + measurements.len() > buf.len() {
                 return Err(SendingError::Overflow);
             }
-            buf[offset..offset + 4].copy_from_slice(measurement.timestamp.to_le_bytes().as_slice());
-            buf[offset + 4..offset + 6]
-                .copy_from_slice(measurement.pm1_0_avg.to_le_bytes().as_slice());
-            buf[offset + 6..offset + 8]
-                .copy_from_slice(measurement.pm2_5_avg.to_le_bytes().as_slice());
+            offset += encode_record(measurement, &mut buf[offset..]);
+        }
+        for (flags, measurement) in buf[offset..].iter_mut().zip(measurements) {
+            *flags = measurement.flags;
         }
         self.indicate_measurement_chr(&buf, true)
     }
@@ -513,8 +670,22 @@ impl BleManager {
         }
     }
 
+    /// Set or clear one of the flags reported with the next Idle, HasSavedSession and
+    /// Running statuses.
+    pub fn set_status_flag(&self, flag: u8, set: bool) {
+        if set {
+            self.status_flags.fetch_or(flag, Ordering::Relaxed);
+        } else {
+            self.status_flags.fetch_and(!flag, Ordering::Relaxed);
+        }
+    }
+
+    fn status_flags(&self) -> u8 {
+        self.status_flags.load(Ordering::Relaxed)
+    }
+
     pub fn notify_status(&self, status: &DeviceStatus) -> anyhow::Result<()> {
-        let mut buf = [0u8; 32];
+        let mut buf = [0u8; 48];
         let len = status.encode(&mut buf);
         self.status_chr.lock().set_value(&buf[..len]).notify();
         Ok(())
@@ -530,3 +701,14 @@ impl BleManager {
         self._ble_device.get_server().connected_count() > 0
     }
 }
+
+/// u32 timestamp followed by one u16 per stream, all little endian. Returns bytes written.
+fn encode_record(measurement: &Measurement, buf: &mut [u8]) -> usize {
+    buf[0..4].copy_from_slice(measurement.timestamp.to_le_bytes().as_slice());
+    let mut offset = 4;
+    for value in measurement.values() {
+        buf[offset..offset + 2].copy_from_slice(value.to_le_bytes().as_slice());
+        offset += 2;
+    }
+    offset
+}
@@ -1,19 +1,53 @@
+use crate::alarm::{AlarmConfig, ALARM_CONFIG_SIZE, MAX_ALARMS};
+use crate::led::air_quality::AirQualityLed;
+use crate::sensor::measurement::MAX_STREAMS;
+use crate::sensor::sensor_usage::{SensorUsage, SENSOR_USAGE_SIZE};
+use crate::storage::aggregation::AggregationPolicy;
+use crate::storage::capacity::{StoragePolicy, StorageUsage};
+use crate::storage::digest::{DigestAlgorithm, DigestRequest, RangeDigest};
+use crate::storage::event_log::{Event, ENCODED_EVENT_SIZE};
+use crate::storage::flush_policy::FlushPolicy;
+use crate::storage::mount_recovery::{MountIncident, MountRecovery, MOUNT_INCIDENT_SIZE};
+use crate::storage::repair::ScanReport;
 use crate::storage::session_config::{SessionConfig, SessionType};
+use crate::storage::stats::StorageStats;
+use crate::storage::storage_controller::StoredSession;
+use crate::storage::storage_error::{ErrorCounts, StorageErrorKind};
 use crate::LoopEvent;
 use uuid::Uuid;
 
-pub const SENSOR_INFO: &str = "PM1,μg/m3;PM2.5,μg/m3";
 /// Commands the app writes to the device
 /// All data in LowEndian
 #[derive(Debug, Clone)]
 pub enum AppCommand {
-    ContinueSession,                 // 0x10
-    DiscardSession,                  // 0x11 (end session without syncing)
-    StartWiFiSync,                   // 0x12 (end session when running)
-    NewSessionConfig(SessionConfig), // 0x13 + 16B - uuid + u16 interval + u8 session_type (optional: + u8 pm1 index + u8 pm2 index + 32B wifi_ssid + 64B wifi_pass)
+    ContinueSession,                                 // 0x10
+    DiscardSession,                                  // 0x11 (end session without syncing)
+    StartWiFiSync,                                   // 0x12 (end session when running)
+    NewSessionConfig(SessionConfig), // 0x13 + 16B - uuid + u16 interval + u8 session_type (optional: + u8 server index of stream 0 + u8 of stream 1 + 16B token + 32B wifi_ssid + 64B wifi_pass + u8 per further stream in GetSensors order, up to MAX_STREAMS in all + optional u8 index for quality flags)
     GetSensors,                      // 0x14
     SetTime(i64),                    // 0x15 + i64
     StartBleSync,                    // 0x16 (BLE only)
+    SetAirQualityLed(AirQualityLed), // 0x17 + u8 enabled + 4 * u16 PM2.5 thresholds
+    SetAlarms(Vec<AlarmConfig>), // 0x18 + u8 count + count * (u8 stream + u16 threshold + u16 hysteresis + u16 min duration s + u8 flags)
+    GetSensorUsage,              // 0x19
+    SetSensorLifetime(u32),      // 0x1A + u32 fan-on hours
+    ListSessions(u16),           // 0x1B + u16 index of the first session (oldest is 0, optional)
+    SyncSession(Uuid),           // 0x1C + 16B uuid (BLE sync of a stored session)
+    DeleteSession(Uuid),         // 0x1D + 16B uuid
+    SetStoragePolicy(StoragePolicy), // 0x1E + u8 policy (0 stop, 1 drop oldest, 2 downsample)
+    GetStorageUsage,             // 0x1F
+    ScanSession { session: Uuid, repair: bool }, // 0x30 + 16B uuid + u8 repair (rewrite the session without its corrupt data)
+    SyncRange { session: Uuid, from: u32, to: u32 }, // 0x31 + 16B uuid + u32 from + u32 to (BLE sync of the records in [from, to), nothing removed)
+    GetStorageStats(Uuid),                           // 0x32 + 16B uuid
+    GetEvents(u16),              // 0x33 + u16 index of the first event (oldest is 0)
+    SetFlushPolicy(FlushPolicy), // 0x34 + u16 min interval s + u16 max record age s + u8 low battery %
+    GetStorageErrors,            // 0x35
+    GetDigest(DigestRequest), // 0x36 + 16B uuid + u8 algorithm (0 CRC-32, 1 SHA-256) + u32 offset + u32 length (0 to the end), of the session as /sync sends it
+    SetAggregationPolicy(AggregationPolicy), // 0x37 + u8 enabled + u8 fill % to start at + u8 keep min/max
+    GetMountIncident(bool), // 0x38 + u8 clear (forget the incident once reported, unless storage is still unmounted)
+    ReformatStorage, // 0x39 (confirms the reformat of a storage partition that failed to mount)
+    SetMountRecovery(MountRecovery), // 0x3A + u8 policy (0 await ReformatStorage, 1 reformat at boot)
+    GetSalvage(u32),                 // 0x3B + u32 offset into the data salvaged at boot
 }
 
 impl AppCommand {
@@ -31,8 +65,7 @@ impl AppCommand {
                         if data.len() < 118 {
                             return None;
                         }
-                        let pm1_index = data[20];
-                        let pm2_5_index = data[21];
+                        let mut stream_indices = vec![data[20], data[21]];
                         let token = u128::from_le_bytes(data[22..38].try_into().ok()?);
                         let wifi_ssid = String::from_utf8(
                             data[38..70]
@@ -50,9 +83,10 @@ impl AppCommand {
                                 .collect(),
                         )
                         .ok()?;
+                        // Indices of further streams, then optionally one for the quality flags
+                        stream_indices.extend(data.iter().skip(134).take(MAX_STREAMS - 1).copied());
                         SessionType::FIXED {
-                            pm1_index,
-                            pm2_5_index,
+                            stream_indices,
                             token,
                             wifi_ssid,
                             wifi_password,
@@ -73,6 +107,80 @@ impl AppCommand {
                 Some(Self::SetTime(epoch))
             }
             0x16 => Some(Self::StartBleSync),
+            0x17 if data.len() >= 10 => {
+                let mut thresholds = [0u16; 4];
+                for (threshold, b) in thresholds.iter_mut().zip(data[2..10].chunks_exact(2)) {
+                    *threshold = u16::from_le_bytes([b[0], b[1]]);
+                }
+                Some(Self::SetAirQualityLed(AirQualityLed {
+                    enabled: data[1] != 0,
+                    thresholds,
+                }))
+            }
+            0x18 if data.len() >= 2 => {
+                let count = data[1] as usize;
+                if count > MAX_ALARMS || data.len() < 2 + count * ALARM_CONFIG_SIZE {
+                    return None;
+                }
+                let alarms = data[2..2 + count * ALARM_CONFIG_SIZE]
+                    .chunks_exact(ALARM_CONFIG_SIZE)
+                    .filter_map(AlarmConfig::decode)
+                    .collect();
+                Some(Self::SetAlarms(alarms))
+            }
+            0x19 => Some(Self::GetSensorUsage),
+            0x1A if data.len() >= 5 => {
+                let hours = u32::from_le_bytes(data[1..5].try_into().ok()?);
+                Some(Self::SetSensorLifetime(hours))
+            }
+            0x1B => Some(Self::ListSessions(
+                data.get(1..3)
+                    .map_or(0, |first| u16::from_le_bytes([first[0], first[1]])),
+            )),
+            0x1C if data.len() >= 17 => {
+                Some(Self::SyncSession(Uuid::from_slice_le(&data[1..17]).ok()?))
+            }
+            0x1D if data.len() >= 17 => {
+                Some(Self::DeleteSession(Uuid::from_slice_le(&data[1..17]).ok()?))
+            }
+            0x1E if data.len() >= 2 => {
+                Some(Self::SetStoragePolicy(StoragePolicy::from_u8(data[1])?))
+            }
+            0x1F => Some(Self::GetStorageUsage),
+            0x30 if data.len() >= 18 => Some(Self::ScanSession {
+                session: Uuid::from_slice_le(&data[1..17]).ok()?,
+                repair: data[17] != 0,
+            }),
+            0x31 if data.len() >= 25 => Some(Self::SyncRange {
+                session: Uuid::from_slice_le(&data[1..17]).ok()?,
+                from: u32::from_le_bytes(data[17..21].try_into().ok()?),
+                to: u32::from_le_bytes(data[21..25].try_into().ok()?),
+            }),
+            0x32 if data.len() >= 17 => Some(Self::GetStorageStats(
+                Uuid::from_slice_le(&data[1..17]).ok()?,
+            )),
+            0x33 if data.len() >= 3 => Some(Self::GetEvents(u16::from_le_bytes(
+                data[1..3].try_into().ok()?,
+            ))),
+            0x34 => Some(Self::SetFlushPolicy(FlushPolicy::decode(&data[1..])?)),
+            0x35 => Some(Self::GetStorageErrors),
+            0x36 if data.len() >= 26 => Some(Self::GetDigest(DigestRequest {
+                session: Uuid::from_slice_le(&data[1..17]).ok()?,
+                algorithm: DigestAlgorithm::from_u8(data[17])?,
+                offset: u32::from_le_bytes(data[18..22].try_into().ok()?) as u64,
+                length: u32::from_le_bytes(data[22..26].try_into().ok()?) as u64,
+            })),
+            0x37 => Some(Self::SetAggregationPolicy(AggregationPolicy::decode(
+                &data[1..],
+            )?)),
+            0x38 if data.len() >= 2 => Some(Self::GetMountIncident(data[1] != 0)),
+            0x39 => Some(Self::ReformatStorage),
+            0x3A if data.len() >= 2 => {
+                Some(Self::SetMountRecovery(MountRecovery::from_u8(data[1])?))
+            }
+            0x3B if data.len() >= 5 => Some(Self::GetSalvage(u32::from_le_bytes(
+                data[1..5].try_into().ok()?,
+            ))),
             _ => None,
         }
     }
@@ -99,11 +207,47 @@ impl AppCommand {
 /// Device responses back to the app
 #[derive(Debug, Clone)]
 pub enum DeviceResponse {
-    Ack,             // 0x20
-    Nack(ErrorCode), // 0x21
-    Ready,           // 0x22
-    SensorInfo,      // 0x23
+    Ack,                // 0x20
+    Nack(ErrorCode),    // 0x21
+    Ready,              // 0x22
+    SensorInfo(String), // 0x23 + "name,unit;name,unit" UTF-8, as many whole streams as fit
+    SensorUsage {
+        usage: SensorUsage,
+        lifetime_hours: u32,
+    }, // 0x24 + u32 fan-on s + u32 wake cycles + u32 frames + u32 lifetime hours
+    Sessions {
+        total: u16,
+        first: u16,
+        sessions: Vec<StoredSession>,
+    }, // 0x25 + u16 total + u16 first index + u8 count + count * (16B uuid + u32 size + u32 first + u32 last)
+    StorageUsage(StorageUsage), // 0x26 + u8 policy + u8 fill % + u32 used bytes + u32 total bytes
+    StorageReport(ScanReport), // 0x27 + u32 lines + u32 records + u32 corrupt bytes + u32 out of order + u32 duplicates + u8 repaired + u8 count + count * (u32 offset + u32 length)
+    StorageStats(StorageStats), // 0x28 + u32 records + u32 first + u32 last + u32 corrupt bytes + u32 used bytes + u32 free bytes + u8 fill % + u32 remaining hours + u32 records before clock set + u8 clock jumps
+    Events {
+        total: u16,
+        first: u16,
+        events: Vec<Event>,
+    }, // 0x29 + u16 total + u16 first index + u8 count + count * (u32 timestamp + u8 kind + u32 detail)
+    StorageErrors(ErrorCounts), // 0x2A + u8 error code of the last error (0 if none) + u32 count per kind (full, open failed, write failed, corrupt data, mount failed), since boot
+    Digest(RangeDigest), // 0x2B + u8 algorithm + u32 offset + u32 length covered + digest (4B CRC-32 or 32B SHA-256)
+    MountIncident {
+        incident: Option<MountIncident>,
+        mounted: bool,
+    }, // 0x2C + u8 mounted + u8 present + u8 state (0 recovered by a retry, 1 awaiting ReformatStorage, 2 reformatted) + i32 esp_err_t + u8 failed attempts + u32 salvaged bytes
+    Salvage {
+        total: u32,
+        offset: u32,
+        bytes: Vec<u8>,
+    }, // 0x2D + u32 total salvaged bytes + u32 offset + up to MAX_SALVAGE_CHUNK bytes
 }
+
+/// Sessions that fit in one Sessions response.
+pub const MAX_LISTED_SESSIONS: usize = 8;
+/// Events that fit in one Events response.
+pub const MAX_LISTED_EVENTS: usize = 26;
+/// Salvaged bytes that fit in one Salvage response.
+pub const MAX_SALVAGE_CHUNK: usize = 232;
+const STORED_SESSION_SIZE: usize = 28;
 impl DeviceResponse {
     pub fn encode(&self, buf: &mut [u8]) -> usize {
         match self {
@@ -120,63 +264,224 @@ impl DeviceResponse {
                 buf[0] = 0x22;
                 1
             }
-            Self::SensorInfo => {
+            Self::SensorInfo(info) => {
                 buf[0] = 0x23;
-                let info = SENSOR_INFO.as_bytes();
+                let info = whole_streams(info, buf.len() - 1).as_bytes();
                 buf[1..1 + info.len()].copy_from_slice(info);
                 info.len() + 1
             }
+            Self::SensorUsage {
+                usage,
+                lifetime_hours,
+            } => {
+                buf[0] = 0x24;
+                usage.encode(&mut buf[1..1 + SENSOR_USAGE_SIZE]);
+                buf[1 + SENSOR_USAGE_SIZE..5 + SENSOR_USAGE_SIZE]
+                    .copy_from_slice(&lifetime_hours.to_le_bytes());
+                5 + SENSOR_USAGE_SIZE
+            }
+            Self::Sessions {
+                total,
+                first,
+                sessions,
+            } => {
+                buf[0] = 0x25;
+                let sessions = &sessions[..sessions.len().min(MAX_LISTED_SESSIONS)];
+                buf[1..3].copy_from_slice(&total.to_le_bytes());
+                buf[3..5].copy_from_slice(&first.to_le_bytes());
+                buf[5] = sessions.len() as u8;
+                for (chunk, stored) in buf[6..].chunks_exact_mut(STORED_SESSION_SIZE).zip(sessions)
+                {
+                    chunk[0..16].copy_from_slice(&stored.session.to_bytes_le());
+                    chunk[16..20].copy_from_slice(&(stored.size as u32).to_le_bytes());
+                    // 0 when the file holds no readable record
+                    chunk[20..24].copy_from_slice(&stored.first.unwrap_or(0).to_le_bytes());
+                    chunk[24..28].copy_from_slice(&stored.last.unwrap_or(0).to_le_bytes());
+                }
+                6 + sessions.len() * STORED_SESSION_SIZE
+            }
+            Self::StorageUsage(usage) => {
+                buf[0] = 0x26;
+                buf[1] = usage.policy as u8;
+                buf[2] = usage.fill_percent();
+                buf[3..7].copy_from_slice(&(usage.used_bytes as u32).to_le_bytes());
+                buf[7..11].copy_from_slice(&(usage.total_bytes as u32).to_le_bytes());
+                11
+            }
+            Self::StorageReport(report) => {
+                buf[0] = 0x27;
+                buf[1..5].copy_from_slice(&report.lines.to_le_bytes());
+                buf[5..9].copy_from_slice(&report.records.to_le_bytes());
+                buf[9..13].copy_from_slice(&(report.corrupt_bytes as u32).to_le_bytes());
+                buf[13..17].copy_from_slice(&report.out_of_order.to_le_bytes());
+                buf[17..21].copy_from_slice(&report.duplicates.to_le_bytes());
+                buf[21] = report.repaired as u8;
+                buf[22] = report.corrupt_ranges.len() as u8;
+                for (chunk, (offset, len)) in
+                    buf[23..].chunks_exact_mut(8).zip(&report.corrupt_ranges)
+                {
+                    chunk[0..4].copy_from_slice(&(*offset as u32).to_le_bytes());
+                    chunk[4..8].copy_from_slice(&(*len as u32).to_le_bytes());
+                }
+                23 + report.corrupt_ranges.len() * 8
+            }
+            Self::StorageStats(stats) => {
+                buf[0] = 0x28;
+                encode_stored_range(Some(stats), &mut buf[1..13]);
+                let (used, free) = stats.usage.map_or((0, 0), |usage| {
+                    (
+                        usage.used_bytes,
+                        usage.total_bytes.saturating_sub(usage.used_bytes),
+                    )
+                });
+                buf[13..17].copy_from_slice(&(stats.session.corrupt_bytes as u32).to_le_bytes());
+                buf[17..21].copy_from_slice(&(used as u32).to_le_bytes());
+                buf[21..25].copy_from_slice(&(free as u32).to_le_bytes());
+                buf[25] = fill_percent(Some(stats));
+                // u32::MAX when the interval or the partition usage is unknown
+                let hours = stats.remaining_hours.unwrap_or(u32::MAX);
+                buf[26..30].copy_from_slice(&hours.to_le_bytes());
+                buf[30..34].copy_from_slice(&stats.session.unset_clock.to_le_bytes());
+                buf[34] = stats.clock_jumps.min(u8::MAX as usize) as u8;
+                35
+            }
+            Self::Events {
+                total,
+                first,
+                events,
+            } => {
+                buf[0] = 0x29;
+                let events = &events[..events.len().min(MAX_LISTED_EVENTS)];
+                buf[1..3].copy_from_slice(&total.to_le_bytes());
+                buf[3..5].copy_from_slice(&first.to_le_bytes());
+                buf[5] = events.len() as u8;
+                for (chunk, event) in buf[6..].chunks_exact_mut(ENCODED_EVENT_SIZE).zip(events) {
+                    chunk.copy_from_slice(&event.encode());
+                }
+                6 + events.len() * ENCODED_EVENT_SIZE
+            }
+            Self::StorageErrors(errors) => {
+                buf[0] = 0x2A;
+                buf[1] = errors.last.map_or(0, |kind| ErrorCode::from(kind) as u8);
+                for (chunk, count) in buf[2..].chunks_exact_mut(4).zip(errors.counts) {
+                    chunk.copy_from_slice(&count.to_le_bytes());
+                }
+                2 + errors.counts.len() * 4
+            }
+            Self::Digest(range) => {
+                buf[0] = 0x2B;
+                buf[1] = range.digest.algorithm() as u8;
+                buf[2..6].copy_from_slice(&(range.offset as u32).to_le_bytes());
+                buf[6..10].copy_from_slice(&(range.length as u32).to_le_bytes());
+                let digest = range.digest.to_bytes();
+                buf[10..10 + digest.len()].copy_from_slice(&digest);
+                10 + digest.len()
+            }
+            Self::MountIncident { incident, mounted } => {
+                buf[0] = 0x2C;
+                buf[1] = *mounted as u8;
+                buf[2] = incident.is_some() as u8;
+                if let Some(incident) = incident {
+                    incident.encode(&mut buf[3..3 + MOUNT_INCIDENT_SIZE]);
+                }
+                3 + MOUNT_INCIDENT_SIZE
+            }
+            Self::Salvage {
+                total,
+                offset,
+                bytes,
+            } => {
+                buf[0] = 0x2D;
+                let bytes = &bytes[..bytes.len().min(MAX_SALVAGE_CHUNK)];
+                buf[1..5].copy_from_slice(&total.to_le_bytes());
+                buf[5..9].copy_from_slice(&offset.to_le_bytes());
+                buf[9..9 + bytes.len()].copy_from_slice(bytes);
+                9 + bytes.len()
+            }
         }
     }
 }
 
+/// Bits of the `flags` byte of the Idle, HasSavedSession and Running statuses.
+pub const STATUS_FLAG_SENSOR_END_OF_LIFE: u8 = 0x01;
+/// The storage overflow policy could not make room; new measurements are being lost.
+pub const STATUS_FLAG_STORAGE_FULL: u8 = 0x02;
+/// The last measurement could not be stored; GetStorageErrors tells why.
+pub const STATUS_FLAG_STORAGE_ERROR: u8 = 0x04;
+
 pub enum DeviceStatus {
-    Idle(i8),
+    Idle {
+        battery_level: i8,
+        flags: u8,
+    },
     HasSavedSession {
         battery_level: i8,
         session: Uuid,
         has_measurements: bool,
         file_size: u64,
+        flags: u8,
+        /// `None` if the session has no file.
+        stats: Option<StorageStats>,
     },
     Running {
         battery_level: i8,
         session: Uuid,
+        flags: u8,
     },
     ReadyToSync {
         file_size: u64,
         password: String,
     },
+    Alarm {
+        alarm: u8,
+        stream: u8,
+        active: bool,
+        value: u16,
+        timestamp: u32,
+    },
 }
 
 impl DeviceStatus {
     pub fn encode(&self, buf: &mut [u8]) -> usize {
         match self {
-            Self::Idle(battery_level) => {
+            Self::Idle {
+                battery_level,
+                flags,
+            } => {
                 buf[0] = 0x00;
                 buf[1] = *battery_level as u8;
-                2
+                buf[2] = *flags;
+                3
             }
             Self::HasSavedSession {
                 battery_level,
                 session,
                 has_measurements,
                 file_size,
+                flags,
+                stats,
             } => {
                 buf[0] = 0x01;
                 buf[1] = *battery_level as u8;
                 buf[2..18].copy_from_slice(&session.to_bytes_le());
                 buf[18] = *has_measurements as u8;
                 buf[19..27].copy_from_slice(&file_size.to_le_bytes());
-                27
+                buf[27] = *flags;
+                // Appended after the flags so older apps still find the fields above
+                encode_stored_range(stats.as_ref(), &mut buf[28..40]);
+                buf[40] = fill_percent(stats.as_ref());
+                41
             }
             Self::Running {
                 battery_level,
                 session,
+                flags,
             } => {
                 buf[0] = 0x02;
                 buf[1] = *battery_level as u8;
                 buf[2..18].copy_from_slice(&session.to_bytes_le());
-                18
+                buf[18] = *flags;
+                19
             }
             Self::ReadyToSync {
                 file_size,
@@ -188,17 +493,82 @@ impl DeviceStatus {
                 buf[9..9 + password_bytes.len()].copy_from_slice(password_bytes);
                 9 + password_bytes.len()
             }
+            Self::Alarm {
+                alarm,
+                stream,
+                active,
+                value,
+                timestamp,
+            } => {
+                buf[0] = 0x04;
+                buf[1] = *alarm;
+                buf[2] = *stream;
+                buf[3] = *active as u8;
+                buf[4..6].copy_from_slice(&value.to_le_bytes());
+                buf[6..10].copy_from_slice(&timestamp.to_le_bytes());
+                10
+            }
         }
     }
 }
 
+/// The "name,unit" streams of `info` that fit in `len` bytes, cut before the first
+/// that does not.
+fn whole_streams(info: &str, len: usize) -> &str {
+    if info.len() <= len {
+        return info;
+    }
+    // `;` is ASCII, so never inside a character
+    let end = info.as_bytes()[..=len]
+        .iter()
+        .rposition(|&b| b == b';')
+        .unwrap_or(0);
+    &info[..end]
+}
+
+/// u32 record count + u32 first + u32 last timestamp, 0 for none.
+fn encode_stored_range(stats: Option<&StorageStats>, buf: &mut [u8]) {
+    let session = stats.map(|stats| stats.session).unwrap_or_default();
+    buf[0..4].copy_from_slice(&session.records.to_le_bytes());
+    buf[4..8].copy_from_slice(&session.first.unwrap_or(0).to_le_bytes());
+    buf[8..12].copy_from_slice(&session.last.unwrap_or(0).to_le_bytes());
+}
+
+/// Fill level of the partition, 0xFF if unknown.
+fn fill_percent(stats: Option<&StorageStats>) -> u8 {
+    stats
+        .and_then(|stats| stats.usage)
+        .map_or(u8::MAX, |usage| usage.fill_percent())
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum ErrorCode {
     NoSession = 0x01,
     InvalidConfig = 0x02,
-    StorageHasMeasurements = 0x03,
+    // 0x03 (StorageHasMeasurements) is no longer sent: sessions are stored side by side
     ClearStorageFailed = 0x04,
     InvalidWifiCredentials = 0x05,
     SyncFailed = 0x06,
+    UnknownSession = 0x07,
+    // The saved session was recorded with other sensors; start a new one instead
+    StreamsChanged = 0x08,
+    StorageUnavailable = 0x09,
+    StorageFull = 0x0A,
+    StorageOpenFailed = 0x0B,
+    StorageWriteFailed = 0x0C,
+    StorageCorrupt = 0x0D,
+    StorageMountFailed = 0x0E,
+}
+
+impl From<StorageErrorKind> for ErrorCode {
+    fn from(kind: StorageErrorKind) -> Self {
+        match kind {
+            StorageErrorKind::Full => Self::StorageFull,
+            StorageErrorKind::OpenFailed => Self::StorageOpenFailed,
+            StorageErrorKind::WriteFailed => Self::StorageWriteFailed,
+            StorageErrorKind::CorruptData => Self::StorageCorrupt,
+            StorageErrorKind::MountFailed => Self::StorageMountFailed,
+        }
+    }
 }
@@ -1,2 +1,3 @@
+pub mod air_quality;
 pub mod led_control;
 pub mod led_thread;
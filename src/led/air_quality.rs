@@ -0,0 +1,45 @@
+/// PM2.5 bands shown by the LED in air quality mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AirQualityLevel {
+    Good,
+    Moderate,
+    UnhealthySensitive,
+    Unhealthy,
+    VeryUnhealthy,
+}
+
+/// Per-device setting for the running-session LED.
+#[derive(Debug, Clone, Copy)]
+pub struct AirQualityLed {
+    pub enabled: bool,
+    /// Upper PM2.5 bound (µg/m³, inclusive) of Good, Moderate, Unhealthy for Sensitive
+    /// Groups and Unhealthy; anything above the last one is Very Unhealthy.
+    pub thresholds: [u16; 4],
+}
+
+impl Default for AirQualityLed {
+    /// Off, with the US EPA 2024 PM2.5 breakpoints.
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            thresholds: [9, 35, 55, 125],
+        }
+    }
+}
+
+impl AirQualityLed {
+    pub fn level(&self, pm2_5: u16) -> AirQualityLevel {
+        match self.thresholds.iter().position(|&t| pm2_5 <= t) {
+            Some(0) => AirQualityLevel::Good,
+            Some(1) => AirQualityLevel::Moderate,
+            Some(2) => AirQualityLevel::UnhealthySensitive,
+            Some(_) => AirQualityLevel::Unhealthy,
+            None => AirQualityLevel::VeryUnhealthy,
+        }
+    }
+
+    /// Thresholds must be strictly increasing to describe distinct bands.
+    pub fn is_valid(&self) -> bool {
+        self.thresholds.windows(2).all(|w| w[0] < w[1])
+    }
+}
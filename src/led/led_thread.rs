@@ -1,4 +1,6 @@
+use crate::led::air_quality::AirQualityLevel;
 use crate::led::led_control::RgbLed;
+use crate::storage::storage_error::StorageErrorKind;
 use esp_idf_svc::hal::ledc::config::TimerConfig;
 use esp_idf_svc::hal::ledc::{LedcTimerDriver, Resolution};
 use std::sync::mpsc;
@@ -8,6 +10,11 @@ use std::time::Duration;
 use esp_idf_svc::hal::gpio::OutputPin;
 use esp_idf_svc::hal::ledc::{LedcChannel, LedcTimer, LowSpeed};
 
+/// On and off time of each blink of a blink code.
+const CODE_BLINK: Duration = Duration::from_millis(300);
+/// Dark time between repetitions of a blink code.
+const CODE_PAUSE: Duration = Duration::from_secs(3);
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum LedStates {
     Off,
@@ -19,7 +26,10 @@ pub enum LedStates {
     LowBattery,
     Syncing,
     BleSync,
-    StorageError,
+    /// Blinks the kind's number in red.
+    StorageError(StorageErrorKind),
+    AirQuality(AirQualityLevel),
+    Alarm,
 }
 
 #[derive(Clone, Copy)]
@@ -49,12 +59,24 @@ impl Color {
     pub const CYAN: Color = Color { r: 255, g: 0, b: 0 };
     pub const YELLOW: Color = Color { r: 0, g: 0, b: 255 };
     pub const WHITE: Color = Color { r: 0, g: 0, b: 0 };
+    pub const ORANGE: Color = Color {
+        r: 0,
+        g: 90,
+        b: 255,
+    };
+    pub const PURPLE: Color = Color {
+        r: 127,
+        g: 255,
+        b: 127,
+    };
 }
 
 enum LedCommand {
     Off,
     Continuous(Color),
     Blinking(Color, Duration),
+    /// `count` short blinks, then a pause.
+    Code(Color, u8),
 }
 
 pub struct LedPins<T, C0, C1, C2, R, G, B> {
@@ -80,7 +102,18 @@ fn get_command(status: LedStates) -> LedCommand {
         LedStates::LowBattery => LedCommand::Blinking(Color::MAGENTA, Duration::from_secs(9)),
         LedStates::Syncing => LedCommand::Continuous(Color::CYAN),
         LedStates::BleSync => LedCommand::Continuous(Color::CYAN),
-        LedStates::StorageError => LedCommand::Continuous(Color::RED),
+        LedStates::StorageError(kind) => LedCommand::Code(Color::RED, kind as u8),
+        LedStates::Alarm => LedCommand::Blinking(Color::RED, Duration::from_secs(1)),
+        LedStates::AirQuality(level) => {
+            let color = match level {
+                AirQualityLevel::Good => Color::GREEN,
+                AirQualityLevel::Moderate => Color::YELLOW,
+                AirQualityLevel::UnhealthySensitive => Color::ORANGE,
+                AirQualityLevel::Unhealthy => Color::RED,
+                AirQualityLevel::VeryUnhealthy => Color::PURPLE,
+            };
+            LedCommand::Blinking(color, Duration::from_secs(9))
+        }
     }
 }
 
@@ -119,6 +152,7 @@ where
         // --- Logic Loop (Worker Thread) ---
         let mut current_mode = LedCommand::Off;
         let mut blink_is_on = true;
+        let mut code_step = 0u8;
 
         loop {
             match current_mode {
@@ -132,6 +166,7 @@ where
                         Ok(cmd) => {
                             current_mode = get_command(cmd);
                             blink_is_on = true; // Reset phase for new command
+                            code_step = 0;
                         }
                         Err(_) => {
                             log::info!("LED Control Channel closed, exiting thread");
@@ -149,6 +184,7 @@ where
                         Ok(cmd) => {
                             current_mode = get_command(cmd);
                             blink_is_on = true;
+                            code_step = 0;
                         }
                         Err(_) => {
                             log::info!("LED Control Channel closed, exiting thread");
@@ -181,6 +217,7 @@ where
                         Ok(cmd) => {
                             current_mode = get_command(cmd);
                             blink_is_on = true; // Reset phase
+                            code_step = 0;
                         }
                         Err(mpsc::RecvTimeoutError::Timeout) => {
                             blink_is_on = !blink_is_on;
@@ -191,6 +228,40 @@ where
                         }
                     }
                 }
+
+                // CASE 4: LED is BLINKING A CODE
+                LedCommand::Code(color, count) => {
+                    // Even steps are lit, odd ones dark; the last one is the pause
+                    let set_result = if code_step % 2 == 0 {
+                        led.set_color(color.r, color.g, color.b, Some(70))
+                    } else {
+                        led.off()
+                    };
+                    let timeout = if code_step + 1 >= 2 * count {
+                        CODE_PAUSE
+                    } else {
+                        CODE_BLINK
+                    };
+
+                    if let Err(e) = set_result {
+                        log::error!("Failed to update blink code state: {:?}", e);
+                    }
+
+                    match rx.recv_timeout(timeout) {
+                        Ok(cmd) => {
+                            current_mode = get_command(cmd);
+                            blink_is_on = true;
+                            code_step = 0;
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            code_step = (code_step + 1) % (2 * count).max(1);
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            log::info!("LED Control Channel closed, exiting thread");
+                            break;
+                        }
+                    }
+                }
             }
         }
     });
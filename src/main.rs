@@ -1,3 +1,5 @@
+mod alarm;
+mod aqi;
 mod autosync;
 mod battery;
 mod ble;
@@ -6,19 +8,31 @@ mod sensor;
 mod storage;
 mod wifi;
 
+use crate::alarm::{AlarmEvent, AlarmMonitor};
+use crate::aqi::{AqiReading, AqiTracker, US_EPA_PM2_5};
 use crate::autosync::sync_from_storage;
 use crate::battery::BatteryMonitor;
-use crate::ble::ble_protocol::{DeviceResponse, DeviceStatus, ErrorCode};
+use crate::ble::ble_protocol::{
+    AppCommand, DeviceResponse, DeviceStatus, ErrorCode, MAX_LISTED_EVENTS, MAX_SALVAGE_CHUNK,
+    STATUS_FLAG_SENSOR_END_OF_LIFE, STATUS_FLAG_STORAGE_ERROR, STATUS_FLAG_STORAGE_FULL,
+};
 use crate::ble::SetupResult;
 use crate::led::led_thread::{start_led_thread, LedPins, LedStates};
-use crate::sensor::measurement::Measurement;
-use crate::sensor::sensor_thread::SensorDriver;
+use crate::sensor::measurement::{Measurement, FLAG_CLOCK_UNSET};
+use crate::sensor::sensor_registry::SensorRegistry;
+use crate::sensor::sensor_thread::{SensorDriver, PM2_5_STREAM, PMS_STREAMS};
+use crate::sensor::sensor_usage::SensorUsage;
+use crate::storage::aggregation::AGGREGATION_INTERVAL;
+use crate::storage::clock::{self, ClockJump};
+use crate::storage::device_settings::DeviceSettings;
+use crate::storage::event_log::{self, EventKind};
+use crate::storage::mount_recovery::{self, IncidentState, MountIncident, MountedStorage};
 use crate::storage::nvs_manager::NvsManager;
 use crate::storage::session_config::SessionType;
-use crate::storage::storage_controller::{StorageManager, MOUNT_POINT};
+use crate::storage::storage_controller::StorageManager;
+use crate::storage::storage_error::StorageErrorKind;
 use crate::wifi::wifi_manager::{SyncStatus, WifiManager};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::fs::littlefs::Littlefs;
 use esp_idf_svc::hal::adc::attenuation::DB_12;
 use esp_idf_svc::hal::adc::oneshot::config::{AdcChannelConfig, Calibration};
 use esp_idf_svc::hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
@@ -28,15 +42,17 @@ use esp_idf_svc::hal::peripherals::Peripherals;
 use esp_idf_svc::hal::uart::config::{DataBits, StopBits};
 use esp_idf_svc::hal::uart::{UartConfig, UartDriver};
 use esp_idf_svc::hal::units::Hertz;
-use esp_idf_svc::io::vfs::MountedLittlefs;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sys::{esp, esp_pm_config_t, esp_pm_configure, settimeofday, timeval};
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
-use log::{error, info};
+use log::{error, info, warn};
 use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// How often the PMS wear counters are written to NVS while a session runs.
+const SENSOR_USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -57,14 +73,35 @@ fn main() -> anyhow::Result<()> {
         "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
         mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
     );
-    let lfs = unsafe { Littlefs::<()>::new_partition("storage") }?;
-    let mounted = MountedLittlefs::mount(lfs, MOUNT_POINT).unwrap_or_else(|e| {
-        log::error!("Formatting storage, Failed to mount filesystem: {:?}", e);
-        let mut lfs = unsafe { Littlefs::<()>::new_partition("storage") }.unwrap();
-        let _ = lfs.format();
-        MountedLittlefs::mount(lfs, MOUNT_POINT).unwrap()
-    }); //this MUST be kept alive, otherwise filesystem will unmount
-    info!("Filesystem info: {:?}", mounted.info());
+    let mut nvs_manager = NvsManager::new(nvs.clone())?;
+    let mut settings = nvs_manager.get_device_settings().unwrap_or_else(|e| {
+        error!("Failed to get device settings: {:?}", e);
+        DeviceSettings::default()
+    });
+    // Salvaged if the partition only mounts read-only
+    let saved_session = nvs_manager
+        .get_session_config()
+        .ok()
+        .flatten()
+        .map(|config| config.session_uuid);
+    //this MUST be kept alive, otherwise filesystem will unmount
+    let (mut mounted, mount_incident) =
+        mount_recovery::mount(settings.mount_recovery, saved_session);
+    match &mounted {
+        Some(mounted) => info!("Filesystem info: {:?}", mounted.info()),
+        None => error!("Storage not mounted, waiting for the app to confirm a reformat"),
+    }
+    if let Some(incident) = mount_incident {
+        if let Err(e) = nvs_manager.set_mount_incident(&incident) {
+            error!("Failed to save mount incident: {:?}", e);
+        }
+        if mounted.is_some() {
+            event_log::record(EventKind::MountFailed, incident.error as u32);
+        }
+    }
+    event_log::record(EventKind::Boot, unsafe {
+        esp_idf_svc::sys::esp_reset_reason()
+    });
     let led_pins = LedPins {
         timer: peripherals.ledc.timer0,
         channel_r: peripherals.ledc.channel0,
@@ -104,14 +141,42 @@ fn main() -> anyhow::Result<()> {
         &config,
     )?;
 
+    let mut sensors = SensorRegistry::new(&PMS_STREAMS);
+    #[cfg(feature = "i2c-sensors")]
+    register_i2c_sensors(
+        &mut sensors,
+        peripherals.i2c0,
+        peripherals.pins.gpio5,
+        peripherals.pins.gpio8,
+    )?;
+    for stream in sensors.streams() {
+        info!("Stream {}: {} [{}]", stream.index, stream.name, stream.unit);
+    }
+
+    let sensor_usage = nvs_manager.get_sensor_usage().unwrap_or_else(|e| {
+        error!("Failed to get sensor usage: {:?}", e);
+        SensorUsage::default()
+    });
+    info!("Sensor usage: {:?}", sensor_usage);
+
     let (event_tx, event_rx) = mpsc::channel();
-    let sensor = SensorDriver::new(uart);
+    let sensor = SensorDriver::new(uart, sensor_usage);
     // Kick off PMS warmup in parallel with the rest of boot so the first
     // measurement after a session starts is available without the 15 s delay.
     sensor.pre_warm();
     let led_command = start_led_thread(led_pins)?;
-    let mut storage = StorageManager::new();
-    let mut nvs_manager = NvsManager::new(nvs.clone())?;
+    let mut storage = StorageManager::new(
+        sensors.stream_count(),
+        mac,
+        nvs_manager.get_uuid().ok().flatten(),
+        unclean_reset(),
+    );
+    if mount_incident.is_some_and(|incident| incident.state != IncidentState::Recovered) {
+        storage.count_error(StorageErrorKind::MountFailed);
+    }
+    storage.set_policy(settings.storage_policy);
+    storage.set_flush_policy(settings.flush_policy);
+    storage.set_aggregation_policy(settings.aggregation_policy);
     let name = format!("AirBeamMini:{}", mac_str);
     let mut ble = ble::BleManager::new(name.as_str(), event_tx.clone(), led_command.clone())?;
     let esp_wifi = EspWifi::new(peripherals.modem.split().0, sys_loop.clone(), Some(nvs))?;
@@ -132,23 +197,43 @@ fn main() -> anyhow::Result<()> {
         // ended with CMD_SLEEP, so we must wake the sensor again. No-op if a
         // prior pre_warm is still in flight or already completed.
         sensor.pre_warm();
+        save_sensor_usage(&mut nvs_manager, sensor.usage(), &settings, &ble);
         let config = nvs_manager.get_session_config().unwrap_or_else(|e| {
             nvs_manager.clear_session_config();
             error!("Failed to get session config: {:?}", e);
             None
         });
 
+        let saved_session = config.as_ref().map(|c| c.session_uuid);
+        let saved_stats = config
+            .as_ref()
+            .and_then(|c| storage.stats(&c.session_uuid, c.interval));
         let result = ble.run_setup(
             config,
-            storage.has_measurements(),
-            storage.get_file_size(),
+            saved_session.is_none_or(|s| storage.can_continue(&s)),
+            &sensors.sensor_info(),
+            saved_session.is_some_and(|s| storage.has_measurements(&s)),
+            saved_session.and_then(|s| storage.get_file_size(&s)),
+            saved_stats,
             || batt.read(&adc, &mut vbat_pin).signed_percent,
-            || storage.clear_measurements(),
-            || wifi_manager.manual_sync(),
+            |session| storage.delete_session(session),
+            || wifi_manager.manual_sync(saved_session, storage.files_lock()),
             || wifi_manager.cancel_manual_sync(),
-            || storage.iter_measurements(),
-            |to_remove| storage.remove_last(to_remove),
+            |session| storage.iter_forward(session),
+            |session| storage.session_sync(session),
+            || storage.sessions(),
             |ssid, password| wifi_manager.connect(ssid, password),
+            |cmd| {
+                handle_device_command(
+                    cmd,
+                    &mut nvs_manager,
+                    &mut settings,
+                    &storage,
+                    &mut mounted,
+                    sensors.stream_count(),
+                    sensor.usage(),
+                )
+            },
             led_command.clone(),
         )?;
         info!("BLE setup result: {:?}", result);
@@ -160,30 +245,36 @@ fn main() -> anyhow::Result<()> {
             &nvs_manager.get_session_config()?.unwrap()
         };
         let domain = nvs_manager.get_domain()?;
+        if let Err(e) = storage.start_session(config.session_uuid) {
+            error!("Failed to prepare storage for session: {:?}", e);
+        }
+        event_log::record(EventKind::SessionStarted, config.session_uuid.as_fields().0);
 
-        let send_measurement = |m: Measurement, battery: i8| -> Result<(), SendingError> {
-            match &config.session_type {
-                SessionType::MOBILE => ble.send_measurement(&m, battery, config.session_uuid),
+        let send_measurement =
+            |m: Measurement, aqi: AqiReading, battery: i8| match &config.session_type {
+                SessionType::MOBILE => ble.send_measurement(&m, aqi, battery, config.session_uuid),
                 _ => wifi_manager.send_measurements(
                     &[m],
+                    None,
                     domain.as_str(),
                     config.clone(),
                     event_tx.clone(),
                 ),
-            }
-        };
+            };
 
-        let send_measurements = |measurements: &[Measurement]| -> Result<(), SendingError> {
-            match &config.session_type {
-                SessionType::MOBILE => ble.send_measurements(measurements),
-                _ => wifi_manager.send_measurements(
-                    measurements,
-                    domain.as_str(),
-                    config.clone(),
-                    event_tx.clone(),
-                ),
-            }
-        };
+        let send_measurements =
+            |measurements: &[Measurement], batch: u16| -> Result<(), SendingError> {
+                match &config.session_type {
+                    SessionType::MOBILE => ble.send_measurements(measurements, batch),
+                    _ => wifi_manager.send_measurements(
+                        measurements,
+                        Some(batch),
+                        domain.as_str(),
+                        config.clone(),
+                        event_tx.clone(),
+                    ),
+                }
+            };
 
         let connected = || match &config.session_type {
             SessionType::MOBILE => ble.is_connected(),
@@ -245,11 +336,30 @@ fn main() -> anyhow::Result<()> {
         let mut current_led: Option<LedStates> = None;
         let mut last_wifi_reconnect: Option<Instant> = None;
         let mut battery = 100_i8;
-        let mut storage_error = false;
+        // Also shows errors met at boot, such as a failed mount
+        let mut storage_error = storage.errors().last;
+        let mut aqi_tracker = AqiTracker::new(&US_EPA_PM2_5);
+        let mut latest_pm2_5: Option<u16> = None;
+        let mut alarms = AlarmMonitor::new(&settings.alarms);
+        let mut last_usage_save = Instant::now();
+        let mut last_aggregation = Instant::now();
+        let mut clock_set: Option<ClockJump> = None;
         let _ = slow_cpu_freq();
         loop {
             let event = event_rx.recv_timeout(Duration::from_millis(100));
 
+            if last_usage_save.elapsed() >= SENSOR_USAGE_SAVE_INTERVAL {
+                save_sensor_usage(&mut nvs_manager, sensor.usage(), &settings, &ble);
+                last_usage_save = Instant::now();
+            }
+
+            if last_aggregation.elapsed() >= AGGREGATION_INTERVAL {
+                if let Err(e) = storage.aggregate() {
+                    error!("Failed to aggregate old data: {:?}", e);
+                }
+                last_aggregation = Instant::now();
+            }
+
             if (-20..=20).contains(&battery) && !low_bat_flag {
                 low_bat_flag = true;
             } else if !(-25..=25).contains(&battery) && low_bat_flag {
@@ -261,6 +371,9 @@ fn main() -> anyhow::Result<()> {
             if is_mobile && now_connected && !was_connected {
                 reconnect_until = Some(Instant::now() + Duration::from_secs(120));
             }
+            if !is_mobile && !now_connected && was_connected {
+                event_log::record(EventKind::WifiDisconnected, 0);
+            }
             was_connected = now_connected;
             if let Some(t) = reconnect_until {
                 if Instant::now() >= t {
@@ -268,12 +381,19 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            let desired = if storage_error {
-                LedStates::StorageError
+            let air_quality = latest_pm2_5
+                .filter(|_| settings.air_quality_led.enabled)
+                .map(|pm2_5| settings.air_quality_led.level(pm2_5));
+            let desired = if let Some(kind) = storage_error {
+                LedStates::StorageError(kind)
             } else if low_bat_flag {
                 LedStates::LowBattery
+            } else if alarms.led_alert() {
+                LedStates::Alarm
             } else if reconnect_until.is_some() {
                 LedStates::Reconnected
+            } else if let Some(level) = air_quality {
+                LedStates::AirQuality(level)
             } else if !is_mobile {
                 LedStates::Off
             } else if now_connected {
@@ -288,24 +408,47 @@ fn main() -> anyhow::Result<()> {
 
             if let Ok(event) = event {
                 match event {
-                    LoopEvent::Measurement(m) => {
+                    LoopEvent::Measurement(mut m) => {
+                        // Taken before the clock was set, but only received after
+                        if let Some(timestamp) = clock_set.and_then(|jump| jump.rebase(m.timestamp))
+                        {
+                            m.timestamp = timestamp;
+                        }
+                        if !clock::is_set(m.timestamp) {
+                            m.flags |= FLAG_CLOCK_UNSET;
+                        }
+                        sensors.sample_into(&mut m);
+                        let pm2_5 = m.values()[PM2_5_STREAM];
+                        let aqi = aqi_tracker.update(m.timestamp, pm2_5);
+                        latest_pm2_5 = Some(pm2_5);
+                        for alarm_event in alarms.update(&m) {
+                            handle_alarm_event(alarm_event, &ble, &storage);
+                        }
                         let notify = on_wifi_error.take();
-                        info!("Got measurement: {:?}", m);
-                        if send_measurement(m, battery).is_err() {
+                        info!("Got measurement: {:?}, {:?}", m, aqi);
+                        if send_measurement(m, aqi, battery).is_err() {
                             if let Some(f) = notify {
                                 f();
                                 break;
                             }
-                            storage_error = storage.save_measurement(m).is_err();
-                            let flush_immediately = match config.session_type {
-                                SessionType::FIXED { .. } => true,
-                                SessionType::MOBILE => config.interval >= Duration::from_secs(60),
+                            let saved = storage.save_measurement(m);
+                            let flushed = match config.session_type {
+                                // Only records that failed to upload get here, flushed
+                                // one by one as before
+                                SessionType::FIXED { .. } => storage.flush(),
+                                SessionType::MOBILE => {
+                                    battery = batt.read(&adc, &mut vbat_pin).signed_percent;
+                                    storage.flush_if_due(config.interval, battery).map(|_| ())
+                                }
                             };
-                            if flush_immediately {
-                                let _ = storage.flush();
-                            }
+                            storage_error = saved.and(flushed).err().map(|e| {
+                                error!("Failed to store measurement: {}", e);
+                                e.kind()
+                            });
+                            ble.set_status_flag(STATUS_FLAG_STORAGE_FULL, storage.is_full());
+                            ble.set_status_flag(STATUS_FLAG_STORAGE_ERROR, storage_error.is_some());
                         } else {
-                            storage_error = false;
+                            storage_error = None;
                             if ble.is_connected() {
                                 battery = batt.read(&adc, &mut vbat_pin).signed_percent;
                                 let _ = ble.send_response(DeviceResponse::Ready);
@@ -317,13 +460,15 @@ fn main() -> anyhow::Result<()> {
                     }
 
                     LoopEvent::TimeUpdate(time_epoch) => {
-                        let tv = timeval {
-                            tv_sec: time_epoch,
-                            tv_usec: 0,
-                        };
                         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
                         if now != time_epoch && time_epoch - last_time_update >= 60 {
-                            unsafe { settimeofday(&tv, std::ptr::null()) };
+                            let jump = set_clock(time_epoch);
+                            if jump.sets_clock() {
+                                clock_set = Some(jump);
+                            }
+                            if let Err(e) = storage.rebase(jump) {
+                                error!("Failed to mark clock jump: {:?}", e);
+                            }
                             last_time_update = time_epoch;
                             info!("Set time to {}", time_epoch);
                         }
@@ -335,12 +480,26 @@ fn main() -> anyhow::Result<()> {
                     } => {
                         let _ = max_cpu_freq();
                         let _ = stop_tx.send(());
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+                        for record in alarms.finish(now) {
+                            handle_alarm_event(AlarmEvent::Ended(record), &ble, &storage);
+                        }
+                        if let Err(e) = storage.end_session() {
+                            error!("Failed to flush storage: {:?}", e);
+                        }
+                        event_log::record(
+                            EventKind::SessionEnded,
+                            config.session_uuid.as_fields().0,
+                        );
+                        let session = config.session_uuid;
                         if start_wifi_sync {
-                            let sync_status = wifi_manager.manual_sync()?;
+                            let sync_status =
+                                wifi_manager.manual_sync(Some(session), storage.files_lock())?;
                             loop {
                                 match sync_status.recv()? {
                                     SyncStatus::Ready { password } => {
-                                        let file_size = storage.get_file_size().unwrap_or(1);
+                                        let file_size =
+                                            storage.get_file_size(&session).unwrap_or(1);
                                         let _ = ble.notify_status(&DeviceStatus::ReadyToSync {
                                             file_size,
                                             password,
@@ -356,46 +515,18 @@ fn main() -> anyhow::Result<()> {
                             }
                         }
                         if start_ble_sync {
-                            let file_size = storage.get_file_size().unwrap_or(1);
-                            let _ = ble.notify_status(&DeviceStatus::ReadyToSync {
-                                file_size,
-                                password: "".to_string(),
-                            });
                             let _ = led_command.send(LedStates::BleSync);
-                            thread::sleep(Duration::from_millis(100)); //let app prepare for sync
-                            let measurements_iter = storage.iter_measurements().unwrap();
-                            let mut measurements: Vec<Measurement> = Vec::with_capacity(30);
-                            for line in measurements_iter {
-                                let new = line.measurements;
-                                if new.len() + measurements.len() > 30 {
-                                    if ble.send_measurements(&measurements).is_err() {
-                                        ble.send_response(DeviceResponse::Nack(
-                                            ErrorCode::SyncFailed,
-                                        ))?;
-                                        break;
-                                    } else {
-                                        let _ = storage.remove_last(measurements.len());
-                                    }
-                                    measurements.clear();
+                            match storage.iter_forward(&session) {
+                                Some(iter) => {
+                                    ble.sync_session(iter, storage.session_sync(&session))?
                                 }
-                                measurements.extend(new);
+                                None => ble.send_response(DeviceResponse::Nack(
+                                    ErrorCode::UnknownSession,
+                                ))?,
                             }
-                            if !measurements.is_empty() {
-                                if ble.send_measurements(&measurements).is_err() {
-                                    ble.send_response(DeviceResponse::Nack(ErrorCode::SyncFailed))?;
-                                } else {
-                                    if storage.clear_measurements().is_err() {
-                                        ble.send_response(DeviceResponse::Nack(
-                                            ErrorCode::ClearStorageFailed,
-                                        ))?;
-                                    };
-                                }
-                            }
-                            ble.send_response(DeviceResponse::Ready)?;
                         }
 
                         info!("Stopping");
-                        let _ = storage.clear_measurements();
                         nvs_manager.clear_session_config();
                         break;
                     }
@@ -416,8 +547,8 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            if storage.has_measurements() && connected() {
-                let _ = sync_from_storage(config, &storage, |m| send_measurements(m));
+            if storage.has_measurements(&config.session_uuid) && connected() {
+                let _ = sync_from_storage(config, &storage, |m, batch| send_measurements(m, batch));
             }
         }
     }
@@ -433,6 +564,12 @@ pub enum LoopEvent {
     },
 }
 
+impl From<Measurement> for LoopEvent {
+    fn from(value: Measurement) -> Self {
+        LoopEvent::Measurement(value)
+    }
+}
+
 #[derive(Debug)]
 enum SendingError {
     ConfigError,
@@ -441,6 +578,318 @@ enum SendingError {
     Overflow,
 }
 
+/// A reset that may have cut storage writes short, after which storage is checked.
+/// A power cut also boots with a power-on reset, so those are caught by the journal
+/// instead (see `StorageManager::new`).
+fn unclean_reset() -> bool {
+    use esp_idf_svc::sys::{
+        esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_INT_WDT,
+        esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_TASK_WDT,
+        esp_reset_reason_t_ESP_RST_WDT,
+    };
+    let reason = unsafe { esp_reset_reason() };
+    matches!(
+        reason,
+        esp_reset_reason_t_ESP_RST_PANIC
+            | esp_reset_reason_t_ESP_RST_INT_WDT
+            | esp_reset_reason_t_ESP_RST_TASK_WDT
+            | esp_reset_reason_t_ESP_RST_WDT
+            | esp_reset_reason_t_ESP_RST_BROWNOUT
+    )
+}
+
+/// Set the clock to `epoch`, recording a jump in the event log.
+pub fn set_clock(epoch: i64) -> ClockJump {
+    let jump = ClockJump {
+        from: clock::now(),
+        to: epoch as u32,
+    };
+    let tv = timeval {
+        tv_sec: epoch,
+        tv_usec: 0,
+    };
+    unsafe { settimeofday(&tv, std::ptr::null()) };
+    if jump.is_jump() {
+        event_log::record(EventKind::TimeJump, jump.delta());
+    }
+    jump
+}
+
+/// BLE setup commands that change persistent device settings.
+fn handle_device_command(
+    cmd: AppCommand,
+    nvs_manager: &mut NvsManager,
+    settings: &mut DeviceSettings,
+    storage: &StorageManager,
+    mounted: &mut Option<MountedStorage>,
+    stream_count: usize,
+    sensor_usage: SensorUsage,
+) -> DeviceResponse {
+    let saved = match cmd {
+        AppCommand::SetAirQualityLed(config) => {
+            if !config.is_valid() {
+                return DeviceResponse::Nack(ErrorCode::InvalidConfig);
+            }
+            nvs_manager
+                .set_air_quality_led(&config)
+                .map(|()| settings.air_quality_led = config)
+        }
+        AppCommand::SetAlarms(alarms) => {
+            if alarms.iter().any(|a| a.stream as usize >= stream_count) {
+                return DeviceResponse::Nack(ErrorCode::InvalidConfig);
+            }
+            nvs_manager
+                .set_alarms(&alarms)
+                .map(|()| settings.alarms = alarms)
+        }
+        AppCommand::GetSensorUsage => {
+            return DeviceResponse::SensorUsage {
+                usage: sensor_usage,
+                lifetime_hours: settings.sensor_lifetime_hours,
+            };
+        }
+        AppCommand::SetSensorLifetime(hours) => {
+            if hours == 0 {
+                return DeviceResponse::Nack(ErrorCode::InvalidConfig);
+            }
+            nvs_manager
+                .set_sensor_lifetime_hours(hours)
+                .map(|()| settings.sensor_lifetime_hours = hours)
+        }
+        AppCommand::SetStoragePolicy(policy) => nvs_manager.set_storage_policy(policy).map(|()| {
+            settings.storage_policy = policy;
+            storage.set_policy(policy);
+        }),
+        AppCommand::SetFlushPolicy(policy) => {
+            if !policy.is_valid() {
+                return DeviceResponse::Nack(ErrorCode::InvalidConfig);
+            }
+            nvs_manager.set_flush_policy(&policy).map(|()| {
+                settings.flush_policy = policy;
+                storage.set_flush_policy(policy);
+            })
+        }
+        AppCommand::SetAggregationPolicy(policy) => {
+            if !policy.is_valid() {
+                return DeviceResponse::Nack(ErrorCode::InvalidConfig);
+            }
+            nvs_manager.set_aggregation_policy(&policy).map(|()| {
+                settings.aggregation_policy = policy;
+                storage.set_aggregation_policy(policy);
+            })
+        }
+        AppCommand::GetStorageUsage => {
+            return match storage.usage() {
+                Some(usage) => DeviceResponse::StorageUsage(usage),
+                None => DeviceResponse::Nack(ErrorCode::StorageUnavailable),
+            };
+        }
+        AppCommand::ScanSession { session, repair } => {
+            if storage.get_file_size(&session).is_none() {
+                return DeviceResponse::Nack(ErrorCode::UnknownSession);
+            }
+            return match storage.scan_session(&session, repair) {
+                Ok(report) => DeviceResponse::StorageReport(report),
+                Err(e) => {
+                    error!("Failed to scan session {}: {:?}", session, e);
+                    DeviceResponse::Nack(ErrorCode::StorageUnavailable)
+                }
+            };
+        }
+        AppCommand::GetStorageStats(session) => {
+            // Remaining time is estimated at the interval of the saved session
+            let interval = nvs_manager
+                .get_session_config()
+                .ok()
+                .flatten()
+                .map_or(Duration::ZERO, |config| config.interval);
+            return match storage.stats(&session, interval) {
+                Some(stats) => DeviceResponse::StorageStats(stats),
+                None => DeviceResponse::Nack(ErrorCode::UnknownSession),
+            };
+        }
+        AppCommand::GetStorageErrors => return DeviceResponse::StorageErrors(storage.errors()),
+        AppCommand::GetDigest(request) => {
+            if storage.get_file_size(&request.session).is_none() {
+                return DeviceResponse::Nack(ErrorCode::UnknownSession);
+            }
+            return match storage.digest(&request) {
+                Ok(digest) => DeviceResponse::Digest(digest),
+                Err(e) => {
+                    error!("Failed to digest session {}: {:?}", request.session, e);
+                    DeviceResponse::Nack(ErrorCode::StorageUnavailable)
+                }
+            };
+        }
+        AppCommand::GetMountIncident(clear) => {
+            let incident = nvs_manager.get_mount_incident().unwrap_or_else(|e| {
+                error!("Failed to get mount incident: {:?}", e);
+                None
+            });
+            if clear && incident.is_some() && mounted.is_some() {
+                if let Err(e) = nvs_manager.clear_mount_incident() {
+                    error!("Failed to clear mount incident: {:?}", e);
+                }
+            }
+            return DeviceResponse::MountIncident {
+                incident,
+                mounted: mounted.is_some(),
+            };
+        }
+        AppCommand::ReformatStorage => {
+            if mounted.is_some() {
+                return DeviceResponse::Nack(ErrorCode::InvalidConfig);
+            }
+            let formatted = match mount_recovery::reformat() {
+                Ok(formatted) => formatted,
+                Err(e) => {
+                    error!("Failed to reformat storage: {:?}", e);
+                    return DeviceResponse::Nack(ErrorCode::StorageMountFailed);
+                }
+            };
+            info!("Filesystem info: {:?}", formatted.info());
+            *mounted = Some(formatted);
+            storage.reformatted();
+            let incident = nvs_manager.get_mount_incident().ok().flatten();
+            if let Some(incident) = incident {
+                let incident = MountIncident {
+                    state: IncidentState::Reformatted,
+                    ..incident
+                };
+                if let Err(e) = nvs_manager.set_mount_incident(&incident) {
+                    error!("Failed to save mount incident: {:?}", e);
+                }
+            }
+            event_log::record(
+                EventKind::MountFailed,
+                incident.map_or(0, |incident| incident.error as u32),
+            );
+            return DeviceResponse::Ack;
+        }
+        AppCommand::SetMountRecovery(policy) => nvs_manager
+            .set_mount_recovery(policy)
+            .map(|()| settings.mount_recovery = policy),
+        AppCommand::GetSalvage(offset) => {
+            return mount_recovery::with_salvaged(|salvaged| {
+                let start = (offset as usize).min(salvaged.len());
+                let end = (start + MAX_SALVAGE_CHUNK).min(salvaged.len());
+                DeviceResponse::Salvage {
+                    total: salvaged.len() as u32,
+                    offset,
+                    bytes: salvaged[start..end].to_vec(),
+                }
+            });
+        }
+        AppCommand::GetEvents(first) => {
+            let events = event_log::read();
+            let listed = events
+                .iter()
+                .skip(first as usize)
+                .take(MAX_LISTED_EVENTS)
+                .copied()
+                .collect();
+            return DeviceResponse::Events {
+                total: events.len().min(u16::MAX as usize) as u16,
+                first,
+                events: listed,
+            };
+        }
+        _ => return DeviceResponse::Nack(ErrorCode::InvalidConfig),
+    };
+    match saved {
+        Ok(()) => DeviceResponse::Ack,
+        Err(e) => {
+            error!("Failed to save device settings: {:?}", e);
+            DeviceResponse::Nack(ErrorCode::InvalidConfig)
+        }
+    }
+}
+
+/// Persist the PMS wear counters and refresh the end-of-life flag reported in the status.
+fn save_sensor_usage(
+    nvs_manager: &mut NvsManager,
+    usage: SensorUsage,
+    settings: &DeviceSettings,
+    ble: &ble::BleManager,
+) {
+    if let Err(e) = nvs_manager.set_sensor_usage(&usage) {
+        error!("Failed to save sensor usage: {:?}", e);
+    }
+    let end_of_life = usage.is_end_of_life(settings.sensor_lifetime_hours);
+    if end_of_life {
+        warn!(
+            "Sensor nearing end of life: {} h fan-on time",
+            usage.fan_on_secs / 3600
+        );
+    }
+    ble.set_status_flag(STATUS_FLAG_SENSOR_END_OF_LIFE, end_of_life);
+}
+
+/// Notify the app about an alarm transition and log finished alarms to storage.
+fn handle_alarm_event(event: AlarmEvent, ble: &ble::BleManager, storage: &StorageManager) {
+    let status = match event {
+        AlarmEvent::Started {
+            alarm,
+            config,
+            since,
+            value,
+        } => {
+            warn!(
+                "Alarm {} tripped on stream {} since {}",
+                alarm, config.stream, since
+            );
+            DeviceStatus::Alarm {
+                alarm,
+                stream: config.stream,
+                active: true,
+                value,
+                timestamp: since,
+            }
+        }
+        AlarmEvent::Ended(record) => {
+            if let Err(e) = storage.save_alarm(&record) {
+                error!("Failed to save alarm: {:?}", e);
+            }
+            DeviceStatus::Alarm {
+                alarm: record.alarm,
+                stream: record.stream,
+                active: false,
+                value: record.peak,
+                timestamp: record.end,
+            }
+        }
+    };
+    if ble.is_connected() {
+        let _ = ble.notify_status(&status);
+    }
+}
+
+/// Probe the I2C header and register every sensor that answers. Boards without
+/// the header simply end up with the Plantower streams only.
+#[cfg(feature = "i2c-sensors")]
+fn register_i2c_sensors(
+    registry: &mut SensorRegistry,
+    i2c: esp_idf_svc::hal::i2c::I2C0<'static>,
+    sda: impl gpio::InputPin + gpio::OutputPin + 'static,
+    scl: impl gpio::InputPin + gpio::OutputPin + 'static,
+) -> anyhow::Result<()> {
+    use crate::sensor::scd4x::Scd4x;
+    use crate::sensor::sht4x::Sht4x;
+    use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let config = I2cConfig::new().baudrate(Hertz(100_000));
+    let bus = Rc::new(RefCell::new(I2cDriver::new(i2c, sda, scl, &config)?));
+    if let Some(sht) = Sht4x::probe(bus.clone()) {
+        registry.register(Box::new(sht))?;
+    }
+    if let Some(scd) = Scd4x::probe(bus) {
+        registry.register(Box::new(scd))?;
+    }
+    Ok(())
+}
+
 fn max_cpu_freq() -> anyhow::Result<()> {
     unsafe {
         let pm = esp_pm_config_t {
@@ -1,3 +1,11 @@
+#[cfg(feature = "i2c-sensors")]
+pub mod i2c_bus;
 pub mod measurement;
+#[cfg(feature = "i2c-sensors")]
+pub mod scd4x;
 pub mod sensor_parser;
+pub mod sensor_registry;
 pub mod sensor_thread;
+pub mod sensor_usage;
+#[cfg(feature = "i2c-sensors")]
+pub mod sht4x;
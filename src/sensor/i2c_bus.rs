@@ -0,0 +1,176 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::delay::TickType;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::i2c::I2cDriver;
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+#[cfg(target_os = "espidf")]
+const I2C_TIMEOUT: u32 = TickType::new_millis(100).ticks();
+
+/// Minimal bus interface the I2C sensor drivers are written against, so they can
+/// run on `FakeBus` off-target.
+pub trait I2cBus {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> anyhow::Result<()>;
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> anyhow::Result<()>;
+}
+
+#[cfg(target_os = "espidf")]
+impl I2cBus for I2cDriver<'_> {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> anyhow::Result<()> {
+        I2cDriver::write(self, address, bytes, I2C_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> anyhow::Result<()> {
+        I2cDriver::read(self, address, buffer, I2C_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+/// Several drivers share one bus; the registry polls them from a single thread.
+impl<B: I2cBus> I2cBus for Rc<RefCell<B>> {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> anyhow::Result<()> {
+        self.borrow_mut().write(address, bytes)
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> anyhow::Result<()> {
+        self.borrow_mut().read(address, buffer)
+    }
+}
+
+/// Sensirion CRC-8 (poly 0x31, init 0xFF) protecting every 16-bit word on the wire.
+pub fn sensirion_crc(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Split a Sensirion response into its CRC-checked 16-bit words.
+pub fn sensirion_words<const N: usize>(buf: &[u8]) -> anyhow::Result<[u16; N]> {
+    let mut words = [0u16; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        let chunk = &buf[i * 3..i * 3 + 3];
+        if sensirion_crc(&chunk[..2]) != chunk[2] {
+            return Err(anyhow::Error::msg("I2C word checksum mismatch"));
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(words)
+}
+
+/// Scripted bus for host tests. Only the `devices` addresses acknowledge; reads
+/// return the queued responses in order and writes are recorded.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeBus {
+    pub devices: Vec<u8>,
+    pub reads: VecDeque<(u8, Vec<u8>)>,
+    pub writes: Vec<(u8, Vec<u8>)>,
+}
+
+#[cfg(test)]
+impl FakeBus {
+    pub fn with_devices(devices: &[u8]) -> Self {
+        Self {
+            devices: devices.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Queue `words` as a Sensirion device sends them, each followed by its CRC.
+    pub fn respond_words(&mut self, address: u8, words: &[u16]) {
+        let bytes = words
+            .iter()
+            .flat_map(|word| {
+                let [hi, lo] = word.to_be_bytes();
+                [hi, lo, sensirion_crc(&[hi, lo])]
+            })
+            .collect();
+        self.reads.push_back((address, bytes));
+    }
+
+    /// Commands written to `address`, in order.
+    pub fn commands(&self, address: u8) -> Vec<&[u8]> {
+        self.writes
+            .iter()
+            .filter(|(to, _)| *to == address)
+            .map(|(_, bytes)| bytes.as_slice())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl I2cBus for FakeBus {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> anyhow::Result<()> {
+        if !self.devices.contains(&address) {
+            return Err(anyhow::Error::msg("I2C address not acknowledged"));
+        }
+        self.writes.push((address, bytes.to_vec()));
+        Ok(())
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> anyhow::Result<()> {
+        if !self.devices.contains(&address) {
+            return Err(anyhow::Error::msg("I2C address not acknowledged"));
+        }
+        match self.reads.pop_front() {
+            Some((from, bytes)) if from == address && bytes.len() == buffer.len() => {
+                buffer.copy_from_slice(&bytes);
+                Ok(())
+            }
+            other => Err(anyhow::Error::msg(format!(
+                "Unexpected read of {} bytes from {:#04x}, scripted {:?}",
+                buffer.len(),
+                address,
+                other
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_datasheet_example() {
+        assert_eq!(sensirion_crc(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn words_are_split_and_checked() {
+        let words = sensirion_words::<2>(&[0xBE, 0xEF, 0x92, 0x00, 0x00, 0x81]).unwrap();
+        assert_eq!(words, [0xBEEF, 0x0000]);
+    }
+
+    #[test]
+    fn crc_mismatch_is_an_error() {
+        let err = sensirion_words::<2>(&[0xBE, 0xEF, 0x92, 0x00, 0x00, 0x80]).unwrap_err();
+        assert_eq!(err.to_string(), "I2C word checksum mismatch");
+    }
+
+    #[test]
+    fn shared_bus_reaches_the_same_fake() {
+        let bus = Rc::new(RefCell::new(FakeBus::with_devices(&[0x44])));
+        bus.borrow_mut().respond_words(0x44, &[0x1234]);
+        let mut shared = bus.clone();
+        shared.write(0x44, &[0x89]).unwrap();
+        let mut buf = [0u8; 3];
+        shared.read(0x44, &mut buf).unwrap();
+        assert_eq!(sensirion_words::<1>(&buf).unwrap(), [0x1234]);
+        assert_eq!(bus.borrow().commands(0x44), [&[0x89][..]]);
+        assert!(shared.write(0x62, &[0x00]).is_err());
+    }
+}
@@ -1,23 +1,43 @@
 use crate::sensor::sensor_parser::PmsMeasurement;
-use crate::LoopEvent;
+
+/// Upper bound on streams a single record can carry across all registered sensors.
+pub const MAX_STREAMS: usize = 8;
+
+// Bits of `Measurement::flags`, kept with the record wherever it goes.
+/// Taken less than 30 s after the PMS woke up, before its readings are stable.
+pub const FLAG_WARMUP: u8 = 0x01;
+/// Averaged from fewer than half the frames expected over its period.
+pub const FLAG_FEW_FRAMES: u8 = 0x02;
+/// A sensor reported an error or could not be read; its values may be 0.
+pub const FLAG_SENSOR_FAULT: u8 = 0x04;
+/// Timestamped before the clock was set and not rebased since, see `storage::clock`.
+pub const FLAG_CLOCK_UNSET: u8 = 0x08;
+/// An average over a bucket of older records, see `storage::aggregation`.
+pub const FLAG_AGGREGATED: u8 = 0x10;
+/// With `FLAG_AGGREGATED`: per-stream minimum of the bucket, 1 s after its average.
+pub const FLAG_MIN: u8 = 0x20;
+/// With `FLAG_AGGREGATED`: per-stream maximum of the bucket, 2 s after its average.
+pub const FLAG_MAX: u8 = 0x40;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Measurement {
-    pub pm1_0_avg: u16,
-    pub pm2_5_avg: u16,
     pub timestamp: u32,
-}
-impl From<Measurement> for LoopEvent {
-    fn from(value: Measurement) -> Self {
-        LoopEvent::Measurement(value)
-    }
+    values: [u16; MAX_STREAMS],
+    len: u8,
+    /// `FLAG_*` bits, 0 for a record nothing is known to be wrong with.
+    pub flags: u8,
 }
 impl Measurement {
-    pub fn new(pm1_0_avg: u16, pm2_5_avg: u16, timestamp: u32) -> Self {
+    /// Values beyond `MAX_STREAMS` are dropped.
+    pub fn new(values: &[u16], timestamp: u32) -> Self {
+        let len = values.len().min(MAX_STREAMS);
+        let mut buf = [0u16; MAX_STREAMS];
+        buf[..len].copy_from_slice(&values[..len]);
         Measurement {
-            pm1_0_avg,
-            pm2_5_avg,
             timestamp,
+            values: buf,
+            len: len as u8,
+            flags: 0,
         }
     }
 
@@ -25,7 +45,28 @@ impl Measurement {
         let pm2_5 = (1.23345 + 0.005157 * (pms.c03 as f32) + 0.211782 * (pms.c1 as f32)).max(0.0);
         let pm1 = (pm2_5 * (0.855 - 0.818 * (-pm2_5 / 6.12_f32).exp())).max(0.0);
 
-        Measurement::new(pm1.round() as u16, pm2_5.round() as u16, timestamp)
+        let mut measurement =
+            Measurement::new(&[pm1.round() as u16, pm2_5.round() as u16], timestamp);
+        if pms.fault {
+            measurement.flags |= FLAG_SENSOR_FAULT;
+        }
+        measurement
+    }
+
+    pub fn values(&self) -> &[u16] {
+        &self.values[..self.len as usize]
+    }
+
+    /// Set the number of streams carried by this record. Slots past the new
+    /// length are cleared so a later grow never exposes stale values.
+    pub fn resize(&mut self, len: usize) {
+        let len = len.min(MAX_STREAMS);
+        self.values[len..].fill(0);
+        self.len = len as u8;
+    }
+
+    pub fn values_mut(&mut self) -> &mut [u16] {
+        &mut self.values[..self.len as usize]
     }
 }
 
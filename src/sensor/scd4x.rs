@@ -0,0 +1,133 @@
+use crate::sensor::i2c_bus::{sensirion_words, I2cBus};
+use crate::sensor::sensor_registry::{Sensor, Stream};
+use std::thread;
+use std::time::Duration;
+
+const ADDRESS: u8 = 0x62;
+const CMD_START_PERIODIC: [u8; 2] = [0x21, 0xB1];
+const CMD_STOP_PERIODIC: [u8; 2] = [0x3F, 0x86];
+const CMD_READ_MEASUREMENT: [u8; 2] = [0xEC, 0x05];
+const CMD_DATA_READY: [u8; 2] = [0xE4, 0xB8];
+const CMD_DELAY: Duration = Duration::from_millis(1);
+
+const STREAMS: [Stream; 1] = [Stream {
+    name: "CO2",
+    unit: "ppm",
+}];
+
+/// Sensirion SCD4x CO2 sensor in periodic (5 s) measurement mode. Each sample
+/// returns the most recent periodic reading.
+pub struct Scd4x<B: I2cBus> {
+    bus: B,
+    last_co2: u16,
+}
+
+impl<B: I2cBus> Scd4x<B> {
+    /// Returns `None` when nothing answers at the SCD4x address.
+    pub fn probe(mut bus: B) -> Option<Self> {
+        // Sensor may still be in periodic mode after a warm reset; it only accepts
+        // commands once stopped (500 ms).
+        bus.write(ADDRESS, &CMD_STOP_PERIODIC).ok()?;
+        thread::sleep(Duration::from_millis(500));
+        bus.write(ADDRESS, &CMD_START_PERIODIC).ok()?;
+        Some(Self { bus, last_co2: 0 })
+    }
+}
+
+impl<B: I2cBus> Sensor for Scd4x<B> {
+    fn name(&self) -> &'static str {
+        "SCD4x"
+    }
+
+    fn streams(&self) -> &'static [Stream] {
+        &STREAMS
+    }
+
+    fn sample(&mut self, out: &mut [u16]) -> anyhow::Result<()> {
+        let mut ready = [0u8; 3];
+        self.bus.write(ADDRESS, &CMD_DATA_READY)?;
+        thread::sleep(CMD_DELAY);
+        self.bus.read(ADDRESS, &mut ready)?;
+        let [status] = sensirion_words::<1>(&ready)?;
+        // Lower 11 bits are zero while no new reading is available.
+        if status & 0x07FF != 0 {
+            let mut buf = [0u8; 9];
+            self.bus.write(ADDRESS, &CMD_READ_MEASUREMENT)?;
+            thread::sleep(CMD_DELAY);
+            self.bus.read(ADDRESS, &mut buf)?;
+            let [co2, _, _] = sensirion_words::<3>(&buf)?;
+            self.last_co2 = co2;
+        }
+        out[0] = self.last_co2;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::i2c_bus::{sensirion_crc, FakeBus};
+
+    fn probed() -> Scd4x<FakeBus> {
+        Scd4x::probe(FakeBus::with_devices(&[ADDRESS])).expect("SCD4x should answer")
+    }
+
+    fn sample(sensor: &mut Scd4x<FakeBus>) -> anyhow::Result<u16> {
+        let mut out = [0u16; 1];
+        sensor.sample(&mut out).map(|()| out[0])
+    }
+
+    #[test]
+    fn probe_restarts_periodic_measurement() {
+        let sensor = probed();
+        assert_eq!(
+            sensor.bus.commands(ADDRESS),
+            [&CMD_STOP_PERIODIC[..], &CMD_START_PERIODIC[..]]
+        );
+    }
+
+    #[test]
+    fn probe_without_a_device_fails() {
+        assert!(Scd4x::probe(FakeBus::default()).is_none());
+    }
+
+    #[test]
+    fn reads_co2_once_ready() {
+        let mut sensor = probed();
+        sensor.bus.respond_words(ADDRESS, &[0x8006]);
+        sensor.bus.respond_words(ADDRESS, &[850, 0x6667, 0x5EB9]);
+        assert_eq!(sample(&mut sensor).unwrap(), 850);
+        assert_eq!(
+            sensor.bus.commands(ADDRESS)[2..],
+            [&CMD_DATA_READY[..], &CMD_READ_MEASUREMENT[..]]
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_reading_until_the_next_is_ready() {
+        let mut sensor = probed();
+        sensor.bus.respond_words(ADDRESS, &[0x8000]);
+        assert_eq!(sample(&mut sensor).unwrap(), 0);
+        sensor.bus.respond_words(ADDRESS, &[0x0001]);
+        sensor.bus.respond_words(ADDRESS, &[612, 0, 0]);
+        assert_eq!(sample(&mut sensor).unwrap(), 612);
+        sensor.bus.respond_words(ADDRESS, &[0xF800]);
+        assert_eq!(sample(&mut sensor).unwrap(), 612);
+        assert!(sensor.bus.reads.is_empty());
+    }
+
+    #[test]
+    fn crc_mismatch_keeps_the_last_reading() {
+        let mut sensor = probed();
+        sensor.bus.respond_words(ADDRESS, &[0x8006]);
+        sensor.bus.respond_words(ADDRESS, &[700, 0, 0]);
+        assert_eq!(sample(&mut sensor).unwrap(), 700);
+
+        sensor.bus.respond_words(ADDRESS, &[0x8006]);
+        let mut response = vec![0x03, 0x20, 0x00, 0x00, 0x00, 0x81, 0x00, 0x00, 0x81];
+        response[2] = sensirion_crc(&response[..2]) ^ 0xFF;
+        sensor.bus.reads.push_back((ADDRESS, response));
+        assert!(sample(&mut sensor).is_err());
+        assert_eq!(sensor.last_co2, 700);
+    }
+}
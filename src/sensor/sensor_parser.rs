@@ -4,6 +4,8 @@ use byteorder::{BigEndian, ByteOrder};
 pub struct PmsMeasurement {
     pub(crate) c03: u16,
     pub(crate) c1: u16,
+    /// The frame carried a non-zero error code.
+    pub(crate) fault: bool,
 }
 pub fn parse_sensor(buffer: &[u8; 32]) -> Option<PmsMeasurement> {
     // Checksum is the last 2 bytes. It should be equal to the sum of the first 30 bytes.
@@ -16,5 +18,7 @@ pub fn parse_sensor(buffer: &[u8; 32]) -> Option<PmsMeasurement> {
 
     let c03 = BigEndian::read_u16(&buffer[16..18]);
     let c1 = BigEndian::read_u16(&buffer[22..24]);
-    Some(PmsMeasurement { c03, c1 })
+    // Byte 28 is the firmware version, 29 the error code
+    let fault = buffer[29] != 0;
+    Some(PmsMeasurement { c03, c1, fault })
 }
@@ -0,0 +1,271 @@
+use crate::sensor::measurement::{Measurement, FLAG_SENSOR_FAULT, MAX_STREAMS};
+use log::warn;
+
+/// A single value a sensor produces on every record, as declared by its driver.
+#[derive(Debug, Clone, Copy)]
+pub struct Stream {
+    pub name: &'static str,
+    pub unit: &'static str,
+}
+
+/// A registered stream together with its slot in `Measurement::values`.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamInfo {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub index: u8,
+}
+
+/// Secondary sensors polled once per emitted record (I2C temperature/RH, CO2, ...).
+/// The Plantower is not one of these: its streams are averaged by the sensor
+/// thread and always occupy the first slots of a record.
+pub trait Sensor {
+    fn name(&self) -> &'static str;
+    fn streams(&self) -> &'static [Stream];
+    /// Fill `out` (exactly `streams().len()` long) with a fresh reading.
+    fn sample(&mut self, out: &mut [u16]) -> anyhow::Result<()>;
+}
+
+struct Registered {
+    first_index: usize,
+    sensor: Box<dyn Sensor>,
+}
+
+pub struct SensorRegistry {
+    streams: Vec<StreamInfo>,
+    primary_len: usize,
+    sensors: Vec<Registered>,
+}
+
+impl SensorRegistry {
+    /// `primary` are the streams already present in every record coming from the sensor thread.
+    pub fn new(primary: &'static [Stream]) -> Self {
+        let mut registry = Self {
+            streams: Vec::with_capacity(MAX_STREAMS),
+            primary_len: primary.len(),
+            sensors: Vec::new(),
+        };
+        registry.push_streams(primary);
+        registry
+    }
+
+    fn push_streams(&mut self, streams: &'static [Stream]) {
+        for stream in streams {
+            self.streams.push(StreamInfo {
+                name: stream.name,
+                unit: stream.unit,
+                index: self.streams.len() as u8,
+            });
+        }
+    }
+
+    #[cfg_attr(not(feature = "i2c-sensors"), allow(dead_code))]
+    pub fn register(&mut self, sensor: Box<dyn Sensor>) -> anyhow::Result<()> {
+        let streams = sensor.streams();
+        if self.streams.len() + streams.len() > MAX_STREAMS {
+            return Err(anyhow::Error::msg(format!(
+                "Sensor {} does not fit in {} streams",
+                sensor.name(),
+                MAX_STREAMS
+            )));
+        }
+        let first_index = self.streams.len();
+        self.push_streams(streams);
+        self.sensors.push(Registered {
+            first_index,
+            sensor,
+        });
+        Ok(())
+    }
+
+    pub fn streams(&self) -> &[StreamInfo] {
+        &self.streams
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// "name,unit;name,unit" as returned by the GetSensors BLE command.
+    pub fn sensor_info(&self) -> String {
+        self.streams
+            .iter()
+            .map(|s| format!("{},{}", s.name, s.unit))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Append readings from all secondary sensors to a record produced by the sensor thread.
+    /// A sensor that fails to read leaves zeros in its slots and flags the record rather
+    /// than dropping it.
+    pub fn sample_into(&mut self, measurement: &mut Measurement) {
+        // Drop anything past the primary streams before growing to the full record.
+        measurement.resize(self.primary_len);
+        measurement.resize(self.streams.len());
+        for registered in &mut self.sensors {
+            let len = registered.sensor.streams().len();
+            let out =
+                &mut measurement.values_mut()[registered.first_index..registered.first_index + len];
+            if let Err(e) = registered.sensor.sample(out) {
+                warn!("Sensor {} read failed: {:?}", registered.sensor.name(), e);
+                out.fill(0);
+                measurement.flags |= FLAG_SENSOR_FAULT;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIMARY: [Stream; 2] = [
+        Stream {
+            name: "PM1",
+            unit: "μg/m3",
+        },
+        Stream {
+            name: "PM2.5",
+            unit: "μg/m3",
+        },
+    ];
+    const CLIMATE: [Stream; 2] = [
+        Stream {
+            name: "Temperature",
+            unit: "F",
+        },
+        Stream {
+            name: "Humidity",
+            unit: "%",
+        },
+    ];
+    const GASES: [Stream; 3] = [
+        Stream {
+            name: "CO2",
+            unit: "ppm",
+        },
+        Stream {
+            name: "NO2",
+            unit: "ppb",
+        },
+        Stream {
+            name: "O3",
+            unit: "ppb",
+        },
+    ];
+
+    /// Reads `values`, or fails with `None`.
+    struct Scripted {
+        streams: &'static [Stream],
+        values: Option<Vec<u16>>,
+    }
+
+    impl Sensor for Scripted {
+        fn name(&self) -> &'static str {
+            "Scripted"
+        }
+
+        fn streams(&self) -> &'static [Stream] {
+            self.streams
+        }
+
+        fn sample(&mut self, out: &mut [u16]) -> anyhow::Result<()> {
+            let values = self
+                .values
+                .as_ref()
+                .ok_or_else(|| anyhow::Error::msg("no reading"))?;
+            out.copy_from_slice(values);
+            Ok(())
+        }
+    }
+
+    fn scripted(streams: &'static [Stream], values: Option<&[u16]>) -> Box<dyn Sensor> {
+        Box::new(Scripted {
+            streams,
+            values: values.map(<[u16]>::to_vec),
+        })
+    }
+
+    #[test]
+    fn primary_streams_only() {
+        let registry = SensorRegistry::new(&PRIMARY);
+        assert_eq!(registry.stream_count(), 2);
+        assert_eq!(registry.sensor_info(), "PM1,μg/m3;PM2.5,μg/m3");
+    }
+
+    #[test]
+    fn registered_streams_follow_the_primary_ones() {
+        let mut registry = SensorRegistry::new(&PRIMARY);
+        registry
+            .register(scripted(&CLIMATE, Some(&[72, 40])))
+            .unwrap();
+        assert_eq!(
+            registry.sensor_info(),
+            "PM1,μg/m3;PM2.5,μg/m3;Temperature,F;Humidity,%"
+        );
+        let indices: Vec<u8> = registry.streams().iter().map(|s| s.index).collect();
+        assert_eq!(indices, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sample_into_fills_each_sensor_slot() {
+        let mut registry = SensorRegistry::new(&PRIMARY);
+        registry
+            .register(scripted(&CLIMATE, Some(&[72, 40])))
+            .unwrap();
+        registry
+            .register(scripted(&GASES, Some(&[800, 12, 30])))
+            .unwrap();
+        let mut measurement = Measurement::new(&[5, 9], 1000);
+        registry.sample_into(&mut measurement);
+        assert_eq!(measurement.values(), [5, 9, 72, 40, 800, 12, 30]);
+        assert_eq!(measurement.flags, 0);
+    }
+
+    #[test]
+    fn sample_into_drops_values_past_the_primary_streams() {
+        let mut registry = SensorRegistry::new(&PRIMARY);
+        registry
+            .register(scripted(&CLIMATE, Some(&[72, 40])))
+            .unwrap();
+        let mut measurement = Measurement::new(&[5, 9, 1, 2, 3], 1000);
+        registry.sample_into(&mut measurement);
+        assert_eq!(measurement.values(), [5, 9, 72, 40]);
+    }
+
+    #[test]
+    fn failed_read_leaves_zeros_and_flags_the_record() {
+        let mut registry = SensorRegistry::new(&PRIMARY);
+        registry.register(scripted(&CLIMATE, None)).unwrap();
+        registry
+            .register(scripted(&GASES, Some(&[800, 12, 30])))
+            .unwrap();
+        let mut measurement = Measurement::new(&[5, 9, 7, 7], 1000);
+        registry.sample_into(&mut measurement);
+        assert_eq!(measurement.values(), [5, 9, 0, 0, 800, 12, 30]);
+        assert_eq!(measurement.flags, FLAG_SENSOR_FAULT);
+    }
+
+    #[test]
+    fn registration_past_max_streams_is_refused() {
+        let mut registry = SensorRegistry::new(&PRIMARY);
+        registry
+            .register(scripted(&GASES, Some(&[1, 2, 3])))
+            .unwrap();
+        registry
+            .register(scripted(&GASES, Some(&[4, 5, 6])))
+            .unwrap();
+        assert_eq!(registry.stream_count(), MAX_STREAMS);
+        let err = registry
+            .register(scripted(&CLIMATE, Some(&[72, 40])))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Sensor Scripted does not fit in 8 streams");
+
+        // The refused sensor takes no slots and is never sampled.
+        assert_eq!(registry.stream_count(), MAX_STREAMS);
+        assert!(!registry.sensor_info().contains("Temperature"));
+        let mut measurement = Measurement::new(&[5, 9], 1000);
+        registry.sample_into(&mut measurement);
+        assert_eq!(measurement.values(), [5, 9, 1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -1,5 +1,7 @@
-use crate::sensor::measurement::Measurement;
+use crate::sensor::measurement::{Measurement, FLAG_FEW_FRAMES, FLAG_WARMUP};
 use crate::sensor::sensor_parser::{parse_sensor, PmsMeasurement};
+use crate::sensor::sensor_registry::Stream;
+use crate::sensor::sensor_usage::{SensorUsage, UsageCounters};
 use crate::LoopEvent;
 use esp_idf_svc::hal::delay::TickType;
 use esp_idf_svc::hal::uart::UartDriver;
@@ -18,6 +20,23 @@ const CMD_READ: [u8; 7] = [0x42, 0x4D, 0xE2, 0x00, 0x00, 0x01, 0x71];
 const CMD_SLEEP: [u8; 7] = [0x42, 0x4D, 0xE4, 0x00, 0x00, 0x01, 0x73];
 const CMD_WAKE: [u8; 7] = [0x42, 0x4D, 0xE4, 0x00, 0x01, 0x01, 0x74];
 const WAKE_UP_SECONDS: u64 = 15;
+/// The PMS streams frames `WAKE_UP_SECONDS` after waking, but the datasheet only
+/// gives stable readings 30 s after it; records taken before are flagged.
+const STABLE_AFTER_WAKE: Duration = Duration::from_secs(30);
+/// When readings are steady the PMS slows active-mode frames down to one per 2.3 s.
+const MAX_FRAME_INTERVAL: Duration = Duration::from_millis(2300);
+pub const PM2_5_STREAM: usize = 1;
+/// Streams every record from this driver starts with, in record order.
+pub const PMS_STREAMS: [Stream; 2] = [
+    Stream {
+        name: "PM1",
+        unit: "μg/m3",
+    },
+    Stream {
+        name: "PM2.5",
+        unit: "μg/m3",
+    },
+];
 const PASSIVE_THRESHOLD: u64 = 3;
 /// Number of raw frames averaged for the initial emit of a fixed-minute
 /// session. A single post-warmup frame is statistically noisier (and biased
@@ -36,28 +55,86 @@ const SENSOR_READOUT_TIMEOUT: u32 = TickType::new_millis(2300).ticks();
 enum WarmupState {
     Cold,
     Warming { since: Instant },
-    Warm,
+    Warm { since: Instant },
+}
+
+/// Raw frames summed up for an averaged record.
+#[derive(Default)]
+struct FrameAverage {
+    sum_c03: u32,
+    sum_c1: u32,
+    count: u32,
+    fault: bool,
+}
+
+impl FrameAverage {
+    fn add(&mut self, frame: &PmsMeasurement) {
+        self.sum_c03 += frame.c03 as u32;
+        self.sum_c1 += frame.c1 as u32;
+        self.count += 1;
+        self.fault |= frame.fault;
+    }
+
+    /// Averages the calibration inputs (`c03`, `c1`) so the nonlinear pm1/pm2.5
+    /// equation is applied once on smoothed counts. Flagged if fewer than half of
+    /// `expected` frames came in; `None` without any.
+    fn measurement(&self, timestamp: u32, expected: u32) -> Option<Measurement> {
+        if self.count == 0 {
+            return None;
+        }
+        let avg_pms = PmsMeasurement {
+            c03: (self.sum_c03 / self.count) as u16,
+            c1: (self.sum_c1 / self.count) as u16,
+            fault: self.fault,
+        };
+        let mut m = Measurement::from_pms_measurement(avg_pms, timestamp);
+        if self.count * 2 < expected {
+            m.flags |= FLAG_FEW_FRAMES;
+        }
+        Some(m)
+    }
+}
+
+/// Frames the PMS sends at least within `window` in active mode.
+fn frames_within(window: Duration) -> u32 {
+    (window.as_millis() / MAX_FRAME_INTERVAL.as_millis()) as u32
+}
+
+/// Flag `m` if the PMS woke up at `woke_at` too recently for stable readings.
+fn flag_warmup(mut m: Measurement, woke_at: Instant) -> Measurement {
+    if woke_at.elapsed() < STABLE_AFTER_WAKE {
+        m.flags |= FLAG_WARMUP;
+    }
+    m
 }
 
 pub struct SensorDriver {
     uart: Arc<Mutex<UartDriver<'static>>>,
     warmup: Arc<Mutex<WarmupState>>,
+    usage: Arc<UsageCounters>,
 }
 
 impl SensorDriver {
-    pub fn new(uart: UartDriver<'static>) -> Self {
+    /// `usage` are the counters saved in NVS, they keep growing from there.
+    pub fn new(uart: UartDriver<'static>, usage: SensorUsage) -> Self {
         Self {
             uart: Arc::new(Mutex::new(uart)),
             warmup: Arc::new(Mutex::new(WarmupState::Cold)),
+            usage: Arc::new(UsageCounters::new(usage)),
         }
     }
 
+    pub fn usage(&self) -> SensorUsage {
+        self.usage.snapshot()
+    }
+
     /// Kick off PMS warmup in the background so the first measurement is
     /// available without paying the 15 s wake delay when a session starts.
     /// Idempotent: a call while already Warming/Warm is a no-op.
     pub fn pre_warm(&self) {
         let uart = self.uart.clone();
         let warmup = self.warmup.clone();
+        let usage = self.usage.clone();
         thread::spawn(move || {
             {
                 let mut w = match warmup.lock() {
@@ -76,38 +153,43 @@ impl SensorDriver {
                 uart.write(&CMD_ACTIVE).ok();
                 thread::sleep(Duration::from_millis(100));
                 uart.write(&CMD_WAKE).ok();
+                usage.woke();
             }
             thread::sleep(Duration::from_secs(WAKE_UP_SECONDS));
             if let Ok(mut w) = warmup.lock() {
-                if matches!(*w, WarmupState::Warming { .. }) {
-                    *w = WarmupState::Warm;
+                if let WarmupState::Warming { since } = *w {
+                    *w = WarmupState::Warm { since };
                 }
             }
             info!("Sensor pre-warm: complete.");
         });
     }
 
+    /// Remaining settle time, and when the sensor woke up.
     fn consume_warmup(
         uart_shared: &Arc<Mutex<UartDriver<'static>>>,
         warmup: &Arc<Mutex<WarmupState>>,
-    ) -> Duration {
+        usage: &UsageCounters,
+    ) -> (Duration, Instant) {
         let mut w = match warmup.lock() {
             Ok(g) => g,
-            Err(_) => return Duration::from_secs(WAKE_UP_SECONDS),
+            Err(_) => return (Duration::from_secs(WAKE_UP_SECONDS), Instant::now()),
         };
         let remaining = match *w {
-            WarmupState::Warm => Duration::ZERO,
-            WarmupState::Warming { since } => {
-                Duration::from_secs(WAKE_UP_SECONDS).saturating_sub(since.elapsed())
-            }
+            WarmupState::Warm { since } => (Duration::ZERO, since),
+            WarmupState::Warming { since } => (
+                Duration::from_secs(WAKE_UP_SECONDS).saturating_sub(since.elapsed()),
+                since,
+            ),
             WarmupState::Cold => {
                 if let Ok(uart) = uart_shared.lock() {
                     let _ = uart.clear_rx();
                     uart.write(&CMD_ACTIVE).ok();
                     thread::sleep(Duration::from_millis(100));
                     uart.write(&CMD_WAKE).ok();
+                    usage.woke();
                 }
-                Duration::from_secs(WAKE_UP_SECONDS)
+                (Duration::from_secs(WAKE_UP_SECONDS), Instant::now())
             }
         };
         *w = WarmupState::Cold;
@@ -121,9 +203,17 @@ impl SensorDriver {
         let (stop_tx, stop_rx) = mpsc::channel();
         let uart_shared = self.uart.clone();
         let warmup = self.warmup.clone();
+        let usage = self.usage.clone();
 
         if period == Duration::from_secs(60) {
-            return Self::start_fixed_minute_task(uart_shared, warmup, event_tx, stop_tx, stop_rx);
+            return Self::start_fixed_minute_task(
+                uart_shared,
+                warmup,
+                usage,
+                event_tx,
+                stop_tx,
+                stop_rx,
+            );
         }
 
         let (sleep_time, averaging_time) = Self::get_loop_durations(period);
@@ -133,7 +223,7 @@ impl SensorDriver {
             info!("Sensor Thread: Started.");
             // Sensor wake commands are issued by pre_warm() in the background; here we
             // only sleep for any remaining settle time before reading the first frame.
-            let remaining = Self::consume_warmup(&uart_shared, &warmup);
+            let (remaining, mut woke_at) = Self::consume_warmup(&uart_shared, &warmup, &usage);
             if remaining > Duration::ZERO {
                 thread::sleep(remaining);
             }
@@ -151,10 +241,10 @@ impl SensorDriver {
                         _ => None,
                     }
                 };
-                if let Some(m) = Self::read_uart(read_byte, Duration::from_secs(5)) {
+                if let Some(m) = Self::read_uart(read_byte, &usage, Duration::from_secs(5)) {
                     info!("Read successful. Sending inital measurement.");
                     last_emitted_ts = m.timestamp;
-                    let _ = event_tx.send(m.into());
+                    let _ = event_tx.send(flag_warmup(m, woke_at).into());
                 }
                 // In the 1 s active-mode path the sensor is already awake and
                 // streaming from pre_warm, so re-issuing CMD_ACTIVE/CMD_WAKE
@@ -170,8 +260,10 @@ impl SensorDriver {
                     }
                     if should_sleep {
                         let _ = uart.write(&CMD_SLEEP);
+                        usage.slept();
                     } else {
                         let _ = uart.write(&CMD_WAKE);
+                        usage.woke();
                     }
                     thread::sleep(Duration::from_millis(100));
                 }
@@ -198,6 +290,8 @@ impl SensorDriver {
                     //wake up sensor for passive mode
                     if should_sleep {
                         let _ = uart.write(&CMD_WAKE);
+                        usage.woke();
+                        woke_at = Instant::now();
                         thread::sleep(Duration::from_secs(WAKE_UP_SECONDS));
                         let _ = uart.write(&CMD_PASSIVE);
                     }
@@ -218,12 +312,18 @@ impl SensorDriver {
                             if stop_rx.try_recv().is_ok() {
                                 (None, true)
                             } else {
-                                let m = Self::read_uart(read_byte, Duration::from_secs(5));
+                                let m = Self::read_uart(read_byte, &usage, Duration::from_secs(5));
                                 (m, false)
                             }
                         } else {
                             //for averaging_time <= 3 seconds, we read in active mode
-                            Self::averaging_loop(averaging_time, read_byte, read_command, &stop_rx)
+                            Self::averaging_loop(
+                                averaging_time,
+                                read_byte,
+                                read_command,
+                                &usage,
+                                &stop_rx,
+                            )
                         };
 
                     if is_stopped {
@@ -235,6 +335,7 @@ impl SensorDriver {
                     }
                     if should_sleep {
                         let _ = uart.write(&CMD_SLEEP);
+                        usage.slept();
                     }
 
                     if let Some(measurement) = measurement {
@@ -245,6 +346,7 @@ impl SensorDriver {
                             continue;
                         }
                         last_emitted_ts = measurement.timestamp;
+                        let measurement = flag_warmup(measurement, woke_at);
                         event_tx.send(measurement.into()).unwrap_or_else(|e| {
                             log::error!("Error sending measurement: {:?}", e);
                         });
@@ -255,6 +357,7 @@ impl SensorDriver {
             // When loop breaks due to stop command, put sensor to sleep
             if let Ok(uart) = uart_shared.lock() {
                 let _ = uart.write(&CMD_SLEEP);
+                usage.slept();
                 info!("Sensor command: SLEEP sent.");
             }
         });
@@ -264,13 +367,14 @@ impl SensorDriver {
     fn start_fixed_minute_task(
         uart_shared: Arc<Mutex<UartDriver<'static>>>,
         warmup: Arc<Mutex<WarmupState>>,
+        usage: Arc<UsageCounters>,
         event_tx: Sender<LoopEvent>,
         stop_tx: Sender<()>,
         stop_rx: Receiver<()>,
     ) -> Sender<()> {
         thread::spawn(move || {
             info!("Sensor Thread: Started (fixed-minute mode).");
-            let remaining = Self::consume_warmup(&uart_shared, &warmup);
+            let (remaining, woke_at) = Self::consume_warmup(&uart_shared, &warmup, &usage);
             if remaining > Duration::ZERO {
                 thread::sleep(remaining);
             }
@@ -286,6 +390,7 @@ impl SensorDriver {
                 };
                 let initial_avg = Self::read_initial_avg_pms(
                     read_byte,
+                    &usage,
                     FIXED_INITIAL_FRAME_COUNT,
                     Duration::from_secs(5),
                 );
@@ -294,16 +399,14 @@ impl SensorDriver {
                     .map(|d| d.as_secs() / 60)
                     .unwrap_or(0);
                 let initial_minute = current_minute;
-                if let Some(avg_pms) = initial_avg {
+                if let Some(m) =
+                    initial_avg.measurement((current_minute * 60) as u32, FIXED_INITIAL_FRAME_COUNT)
+                {
                     info!("Read successful. Sending initial measurement.");
-                    let m =
-                        Measurement::from_pms_measurement(avg_pms, (current_minute * 60) as u32);
-                    let _ = event_tx.send(m.into());
+                    let _ = event_tx.send(flag_warmup(m, woke_at).into());
                 }
 
-                let mut sum_c03: u32 = 0;
-                let mut sum_c10: u32 = 0;
-                let mut count: u32 = 0;
+                let mut average = FrameAverage::default();
 
                 let read_byte_loop = || {
                     let mut byte_buf = [0u8; 1];
@@ -318,7 +421,7 @@ impl SensorDriver {
                         break;
                     }
                     if let Some(frame) =
-                        Self::read_raw_frame(read_byte_loop, Duration::from_secs(5))
+                        Self::read_raw_frame(read_byte_loop, &usage, Duration::from_secs(5))
                     {
                         let now_min = SystemTime::now()
                             .duration_since(UNIX_EPOCH)
@@ -329,34 +432,27 @@ impl SensorDriver {
                             continue;
                         }
                         if now_min != current_minute {
-                            if count > 0 {
-                                let avg_pms = PmsMeasurement {
-                                    c03: (sum_c03 / count) as u16,
-                                    c1: (sum_c10 / count) as u16,
-                                };
-                                let m = Measurement::from_pms_measurement(
-                                    avg_pms,
-                                    (current_minute * 60) as u32,
-                                );
+                            if let Some(m) = average.measurement(
+                                (current_minute * 60) as u32,
+                                frames_within(Duration::from_secs(60)),
+                            ) {
+                                let m = flag_warmup(m, woke_at);
                                 event_tx.send(m.into()).unwrap_or_else(|e| {
                                     log::error!("Error sending measurement: {:?}", e);
                                 });
                             } else if current_minute != initial_minute {
                                 warn!("No samples in minute {}, skipping emit.", current_minute);
                             }
-                            sum_c03 = 0;
-                            sum_c10 = 0;
-                            count = 0;
+                            average = FrameAverage::default();
                             current_minute = now_min;
                         }
-                        sum_c03 += frame.c03 as u32;
-                        sum_c10 += frame.c1 as u32;
-                        count += 1;
+                        average.add(&frame);
                     }
                 }
 
                 info!("Sensor Thread: Loop stopped.");
                 let _ = uart.write(&CMD_SLEEP);
+                usage.slept();
                 info!("Sensor command: SLEEP sent.");
             }
         });
@@ -367,24 +463,23 @@ impl SensorDriver {
         duration: Duration,
         mut read_byte: F,
         read_command: G,
+        usage: &UsageCounters,
         stop: &Receiver<()>,
     ) -> (Option<Measurement>, bool)
     where
         F: FnMut() -> Option<[u8; 1]>,
         G: Fn() -> Option<usize>,
     {
-        let mut c03_sum = 0_u32;
-        let mut c1_sum = 0_u32;
-        let mut count = 0_u32;
+        let mut average = FrameAverage::default();
         let instant = Instant::now();
         let mut stopped = false;
 
         while duration > instant.elapsed() {
             let is_passive = read_command().is_some();
-            if let Some(parsed) = Self::read_raw_frame(&mut read_byte, Duration::from_secs(5)) {
-                c03_sum += parsed.c03 as u32;
-                c1_sum += parsed.c1 as u32;
-                count += 1;
+            if let Some(parsed) =
+                Self::read_raw_frame(&mut read_byte, usage, Duration::from_secs(5))
+            {
+                average.add(&parsed);
             }
             if stop.try_recv().is_ok() {
                 //break the loop if stop signal is received
@@ -395,29 +490,27 @@ impl SensorDriver {
                 thread::sleep(Duration::from_millis(500));
             }
         }
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).ok();
-        if count > 0 && timestamp.is_some() {
-            let avg_pms = PmsMeasurement {
-                c03: (c03_sum / count) as u16,
-                c1: (c1_sum / count) as u16,
-            };
-            let m = Measurement::from_pms_measurement(avg_pms, timestamp.unwrap().as_secs() as u32);
-            (Some(m), stopped)
-        } else {
-            (None, stopped)
-        }
+        let m = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|now| average.measurement(now.as_secs() as u32, frames_within(duration)));
+        (m, stopped)
     }
 
-    fn read_uart<F>(read_byte: F, timeout: Duration) -> Option<Measurement>
+    fn read_uart<F>(read_byte: F, usage: &UsageCounters, timeout: Duration) -> Option<Measurement>
     where
         F: FnMut() -> Option<[u8; 1]>,
     {
-        let pms = Self::read_raw_frame(read_byte, timeout)?;
+        let pms = Self::read_raw_frame(read_byte, usage, timeout)?;
         let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
         Some(Measurement::from_pms_measurement(pms, now.as_secs() as u32))
     }
 
-    fn read_raw_frame<F>(mut read_byte: F, timeout: Duration) -> Option<PmsMeasurement>
+    fn read_raw_frame<F>(
+        mut read_byte: F,
+        usage: &UsageCounters,
+        timeout: Duration,
+    ) -> Option<PmsMeasurement>
     where
         F: FnMut() -> Option<[u8; 1]>,
     {
@@ -461,41 +554,32 @@ impl SensorDriver {
                 }
             }
         }
-        parse_sensor(&buf)
+        let parsed = parse_sensor(&buf);
+        if parsed.is_some() {
+            usage.frame_parsed();
+        }
+        parsed
     }
 
-    /// Reads up to `n` raw frames and returns the per-field averages as a
-    /// PmsMeasurement. Used for the first emit of a fixed-minute session so the
-    /// initial reading is built from the same statistical base as the
-    /// per-minute averaged emits that follow it. Averages the calibration
-    /// inputs (`c03`, `c10`) so the nonlinear pm1/pm2.5 equation is applied
-    /// once on smoothed counts.
+    /// Reads up to `n` raw frames and sums them up. Used for the first emit of a
+    /// fixed-minute session so the initial reading is built from the same
+    /// statistical base as the per-minute averaged emits that follow it.
     fn read_initial_avg_pms<F>(
         mut read_byte: F,
+        usage: &UsageCounters,
         n: u32,
         per_frame_timeout: Duration,
-    ) -> Option<PmsMeasurement>
+    ) -> FrameAverage
     where
         F: FnMut() -> Option<[u8; 1]>,
     {
-        let mut sum_c03: u32 = 0;
-        let mut sum_c10: u32 = 0;
-        let mut count: u32 = 0;
+        let mut average = FrameAverage::default();
         for _ in 0..n {
-            if let Some(frame) = Self::read_raw_frame(&mut read_byte, per_frame_timeout) {
-                sum_c03 += frame.c03 as u32;
-                sum_c10 += frame.c1 as u32;
-                count += 1;
+            if let Some(frame) = Self::read_raw_frame(&mut read_byte, usage, per_frame_timeout) {
+                average.add(&frame);
             }
         }
-        if count == 0 {
-            None
-        } else {
-            Some(PmsMeasurement {
-                c03: (sum_c03 / count) as u16,
-                c1: (sum_c10 / count) as u16,
-            })
-        }
+        average
     }
 
     ///returns sleep duration and measurement collection time
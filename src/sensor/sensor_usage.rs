@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Fan-on hours after which the status reports the PMS as nearing end of life,
+/// unless the app configured a different value.
+pub const DEFAULT_SENSOR_LIFETIME_HOURS: u32 = 20_000;
+/// u32 fan-on seconds + u32 wake cycles + u32 frames
+pub const SENSOR_USAGE_SIZE: usize = 12;
+
+/// Lifetime wear counters of the PMS, kept in NVS across sessions and reboots.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SensorUsage {
+    pub fan_on_secs: u32,
+    pub wake_cycles: u32,
+    pub frames: u32,
+}
+
+impl SensorUsage {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < SENSOR_USAGE_SIZE {
+            return None;
+        }
+        Some(Self {
+            fan_on_secs: u32::from_le_bytes(data[0..4].try_into().ok()?),
+            wake_cycles: u32::from_le_bytes(data[4..8].try_into().ok()?),
+            frames: u32::from_le_bytes(data[8..12].try_into().ok()?),
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.fan_on_secs.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.wake_cycles.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.frames.to_le_bytes());
+    }
+
+    pub fn is_end_of_life(&self, lifetime_hours: u32) -> bool {
+        self.fan_on_secs as u64 >= lifetime_hours as u64 * 3600
+    }
+}
+
+/// Live counters shared between the sensor threads and the main loop.
+/// Fan-on time is the time between a wake and the following sleep command.
+pub struct UsageCounters {
+    fan_on_secs: AtomicU32,
+    wake_cycles: AtomicU32,
+    frames: AtomicU32,
+    awake_since: Mutex<Option<Instant>>,
+}
+
+impl UsageCounters {
+    pub fn new(saved: SensorUsage) -> Self {
+        Self {
+            fan_on_secs: AtomicU32::new(saved.fan_on_secs),
+            wake_cycles: AtomicU32::new(saved.wake_cycles),
+            frames: AtomicU32::new(saved.frames),
+            awake_since: Mutex::new(None),
+        }
+    }
+
+    /// Record a wake command. Repeated wakes without a sleep in between count once.
+    pub fn woke(&self) {
+        if let Ok(mut awake_since) = self.awake_since.lock() {
+            if awake_since.is_none() {
+                *awake_since = Some(Instant::now());
+                self.wake_cycles.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn slept(&self) {
+        if let Ok(mut awake_since) = self.awake_since.lock() {
+            if let Some(since) = awake_since.take() {
+                self.fan_on_secs
+                    .fetch_add(since.elapsed().as_secs() as u32, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn frame_parsed(&self) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current totals, including the fan time of a wake that is still in progress.
+    pub fn snapshot(&self) -> SensorUsage {
+        let running = self
+            .awake_since
+            .lock()
+            .ok()
+            .and_then(|since| since.map(|s| s.elapsed().as_secs() as u32))
+            .unwrap_or(0);
+        SensorUsage {
+            fan_on_secs: self.fan_on_secs.load(Ordering::Relaxed) + running,
+            wake_cycles: self.wake_cycles.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+        }
+    }
+}
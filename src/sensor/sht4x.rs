@@ -0,0 +1,130 @@
+use crate::sensor::i2c_bus::{sensirion_words, I2cBus};
+use crate::sensor::sensor_registry::{Sensor, Stream};
+use std::thread;
+use std::time::Duration;
+
+const ADDRESS: u8 = 0x44;
+const CMD_MEASURE_HIGH_PRECISION: u8 = 0xFD;
+const CMD_READ_SERIAL: u8 = 0x89;
+const MEASURE_TIME: Duration = Duration::from_millis(10);
+
+/// Temperature in °F to match the AirBeam streams on AirCasting.
+const STREAMS: [Stream; 2] = [
+    Stream {
+        name: "Temperature",
+        unit: "F",
+    },
+    Stream {
+        name: "Humidity",
+        unit: "%",
+    },
+];
+
+/// Sensirion SHT4x temperature / relative humidity sensor.
+pub struct Sht4x<B: I2cBus> {
+    bus: B,
+}
+
+impl<B: I2cBus> Sht4x<B> {
+    /// Returns `None` when nothing answers at the SHT4x address.
+    pub fn probe(mut bus: B) -> Option<Self> {
+        let mut buf = [0u8; 6];
+        bus.write(ADDRESS, &[CMD_READ_SERIAL]).ok()?;
+        thread::sleep(Duration::from_millis(1));
+        bus.read(ADDRESS, &mut buf).ok()?;
+        sensirion_words::<2>(&buf).ok()?;
+        Some(Self { bus })
+    }
+}
+
+impl<B: I2cBus> Sensor for Sht4x<B> {
+    fn name(&self) -> &'static str {
+        "SHT4x"
+    }
+
+    fn streams(&self) -> &'static [Stream] {
+        &STREAMS
+    }
+
+    fn sample(&mut self, out: &mut [u16]) -> anyhow::Result<()> {
+        let mut buf = [0u8; 6];
+        self.bus.write(ADDRESS, &[CMD_MEASURE_HIGH_PRECISION])?;
+        thread::sleep(MEASURE_TIME);
+        self.bus.read(ADDRESS, &mut buf)?;
+        let [raw_t, raw_rh] = sensirion_words::<2>(&buf)?;
+
+        let celsius = -45.0 + 175.0 * raw_t as f32 / 65535.0;
+        let fahrenheit = celsius * 9.0 / 5.0 + 32.0;
+        let humidity = (-6.0 + 125.0 * raw_rh as f32 / 65535.0).clamp(0.0, 100.0);
+        out[0] = fahrenheit.round().max(0.0) as u16;
+        out[1] = humidity.round() as u16;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::i2c_bus::{sensirion_crc, FakeBus};
+
+    fn probed() -> Sht4x<FakeBus> {
+        let mut bus = FakeBus::with_devices(&[ADDRESS]);
+        bus.respond_words(ADDRESS, &[0x1234, 0x5678]);
+        Sht4x::probe(bus).expect("SHT4x should answer")
+    }
+
+    fn sample(sensor: &mut Sht4x<FakeBus>, raw_t: u16, raw_rh: u16) -> anyhow::Result<[u16; 2]> {
+        sensor.bus.respond_words(ADDRESS, &[raw_t, raw_rh]);
+        let mut out = [0u16; 2];
+        sensor.sample(&mut out).map(|()| out)
+    }
+
+    #[test]
+    fn probe_reads_the_serial() {
+        let sensor = probed();
+        assert_eq!(sensor.bus.commands(ADDRESS), [&[CMD_READ_SERIAL][..]]);
+    }
+
+    #[test]
+    fn probe_without_a_device_fails() {
+        assert!(Sht4x::probe(FakeBus::default()).is_none());
+    }
+
+    #[test]
+    fn probe_with_a_bad_serial_crc_fails() {
+        let mut bus = FakeBus::with_devices(&[ADDRESS]);
+        bus.reads
+            .push_back((ADDRESS, vec![0x12, 0x34, 0x00, 0x56, 0x78, 0x00]));
+        assert!(Sht4x::probe(bus).is_none());
+    }
+
+    #[test]
+    fn converts_to_fahrenheit_and_percent() {
+        let mut sensor = probed();
+        // 25 °C, 50 %RH
+        assert_eq!(sample(&mut sensor, 26214, 29360).unwrap(), [77, 50]);
+        assert_eq!(
+            sensor.bus.commands(ADDRESS).last(),
+            Some(&&[CMD_MEASURE_HIGH_PRECISION][..])
+        );
+    }
+
+    #[test]
+    fn clamps_out_of_range_readings() {
+        let mut sensor = probed();
+        // -45 °C is below 0 °F; the humidity formula spans -6 to 119 %RH.
+        assert_eq!(sample(&mut sensor, 0, 0).unwrap(), [0, 0]);
+        assert_eq!(sample(&mut sensor, 65535, 65535).unwrap(), [266, 100]);
+    }
+
+    #[test]
+    fn crc_mismatch_fails_the_sample() {
+        let mut sensor = probed();
+        let mut response = vec![0x66, 0x66, 0x00, 0x72, 0xB0, 0x00];
+        response[2] = sensirion_crc(&response[..2]) ^ 1;
+        response[5] = sensirion_crc(&response[3..5]);
+        sensor.bus.reads.push_back((ADDRESS, response));
+        let mut out = [0u16; 2];
+        assert!(sensor.sample(&mut out).is_err());
+    }
+}
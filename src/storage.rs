@@ -1,4 +1,19 @@
+pub mod aggregation;
+pub mod capacity;
+pub mod clock;
+pub mod device_settings;
+pub mod digest;
+pub mod event_log;
+pub mod file_header;
+pub mod flush_policy;
+pub mod journal;
+pub mod line_format;
+pub mod mount_recovery;
 pub mod nvs_manager;
+pub mod repair;
 pub mod session_config;
+pub mod stats;
 pub mod storage_controller;
+pub mod storage_error;
 pub mod storage_iterator;
+pub mod sync_state;
@@ -0,0 +1,386 @@
+use crate::sensor::measurement::{
+    Measurement, FLAG_AGGREGATED, FLAG_CLOCK_UNSET, FLAG_MAX, FLAG_MIN,
+};
+use crate::storage::line_format::MAX_LINE_RECORDS;
+use crate::storage::storage_iterator::{encode_measurements, ForwardMeasurementIter};
+use std::io::{Read, Seek, Write};
+use std::time::Duration;
+
+/// u8 enabled + u8 threshold percent + u8 keep min/max
+pub const AGGREGATION_POLICY_SIZE: usize = 3;
+/// How often the main loop runs a pass. A pass reads every session file and
+/// rewrites some, so it does not follow every record.
+pub const AGGREGATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// A session is only rewritten if that frees at least a littlefs block.
+pub const MIN_SAVED_BYTES: u64 = 4096;
+
+/// Records older than `age` seconds before the newest record of their session are
+/// averaged into buckets of `bucket_secs`.
+pub struct Tier {
+    pub age: u32,
+    pub bucket_secs: u32,
+}
+
+/// 1 min records become 10 min averages after a day, and hourly ones after a week.
+pub const TIERS: [Tier; 2] = [
+    Tier {
+        age: 24 * 60 * 60,
+        bucket_secs: 10 * 60,
+    },
+    Tier {
+        age: 7 * 24 * 60 * 60,
+        bucket_secs: 60 * 60,
+    },
+];
+
+/// Whether and when old data is averaged into `TIERS` to stretch long deployments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AggregationPolicy {
+    pub enabled: bool,
+    /// Fill level of the partition a pass starts at.
+    pub threshold_percent: u8,
+    /// Write the minimum and maximum of each bucket next to its average.
+    pub keep_min_max: bool,
+}
+
+impl Default for AggregationPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_percent: 50,
+            keep_min_max: true,
+        }
+    }
+}
+
+impl AggregationPolicy {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < AGGREGATION_POLICY_SIZE {
+            return None;
+        }
+        Some(Self {
+            enabled: data[0] != 0,
+            threshold_percent: data[1],
+            keep_min_max: data[2] != 0,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.enabled as u8;
+        buf[1] = self.threshold_percent;
+        buf[2] = self.keep_min_max as u8;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.threshold_percent <= 100
+    }
+
+    /// Whether averages written for lack of room, as by the downsample storage
+    /// policy, get their minimum and maximum too.
+    pub fn keeps_min_max(&self) -> bool {
+        self.enabled && self.keep_min_max
+    }
+}
+
+/// Bucket a record goes to in a session whose newest record is at `newest`, `None`
+/// to keep it as is. Cutoffs are rounded down to their bucket size, so no bucket spans
+/// two tiers. Records taken before the clock was set have no age to go by.
+pub fn tier_bucket(record: &Measurement, newest: u32) -> Option<u32> {
+    if record.flags & FLAG_CLOCK_UNSET != 0 {
+        return None;
+    }
+    TIERS
+        .iter()
+        .rev()
+        .find(|tier| {
+            let cutoff = newest.saturating_sub(tier.age);
+            record.timestamp < cutoff - cutoff % tier.bucket_secs
+        })
+        .map(|tier| tier.bucket_secs)
+}
+
+/// Average `lines` into the buckets `bucket_secs` puts each record in, copying records
+/// it puts in none, and write them to `out` as new lines. Returns the number of bytes
+/// written, so a dry run into `std::io::sink()` tells whether it is worth it.
+///
+/// An average is stamped with the start of its bucket and flagged `FLAG_AGGREGATED`,
+/// with the flags of all records in the bucket. With `keep_min_max` it is followed by
+/// `FLAG_MIN` and `FLAG_MAX` records, which fold into those of a coarser bucket
+/// later. Either way every record is a plain record with its own timestamp, so all
+/// readers of the line format still take them.
+pub fn aggregate<R: Read + Seek>(
+    lines: ForwardMeasurementIter<R>,
+    out: &mut impl Write,
+    keep_min_max: bool,
+    bucket_secs: impl Fn(&Measurement) -> Option<u32>,
+) -> std::io::Result<u64> {
+    let stream_count = lines.stream_count();
+    let mut downsampler = Downsampler::new(stream_count, keep_min_max);
+    let mut written = 0;
+    let mut write_line = |records: &[Measurement], out: &mut dyn Write| {
+        let line = encode_measurements(records, stream_count);
+        written += line.len() as u64;
+        out.write_all(&line)
+    };
+    for line in lines {
+        for measurement in line.measurements {
+            if let Some(full) = downsampler.push(&measurement, bucket_secs(&measurement)) {
+                write_line(&full, out)?;
+            }
+        }
+    }
+    for rest in downsampler.finish().chunks(MAX_LINE_RECORDS) {
+        write_line(rest, out)?;
+    }
+    Ok(written)
+}
+
+/// Running average, minimum and maximum over one bucket, collecting finished records
+/// until they fill a line.
+struct Downsampler {
+    bucket_start: Option<u32>,
+    count: u32,
+    sums: Vec<u32>,
+    min: Option<Vec<u16>>,
+    max: Option<Vec<u16>>,
+    flags: u8,
+    keep_min_max: bool,
+    done: Vec<Measurement>,
+}
+
+impl Downsampler {
+    fn new(stream_count: usize, keep_min_max: bool) -> Self {
+        Self {
+            bucket_start: None,
+            count: 0,
+            sums: vec![0; stream_count],
+            min: None,
+            max: None,
+            flags: 0,
+            keep_min_max,
+            done: Vec::with_capacity(MAX_LINE_RECORDS + 3),
+        }
+    }
+
+    /// Add a record to the bucket of `bucket_secs`, or as is for `None`; returns a
+    /// line's worth of records once one is complete.
+    fn push(
+        &mut self,
+        measurement: &Measurement,
+        bucket_secs: Option<u32>,
+    ) -> Option<Vec<Measurement>> {
+        match bucket_secs {
+            Some(secs) => {
+                let timestamp = measurement.timestamp;
+                let bucket_start = timestamp - timestamp % secs;
+                if self.bucket_start != Some(bucket_start) {
+                    self.close_bucket();
+                    self.bucket_start = Some(bucket_start);
+                }
+                self.add(measurement);
+            }
+            None => {
+                self.close_bucket();
+                self.done.push(*measurement);
+            }
+        }
+        (self.done.len() >= MAX_LINE_RECORDS).then(|| {
+            let rest = self.done.split_off(MAX_LINE_RECORDS);
+            std::mem::replace(&mut self.done, rest)
+        })
+    }
+
+    /// Minimum and maximum records of earlier passes only count towards those.
+    fn add(&mut self, measurement: &Measurement) {
+        let values = measurement.values();
+        let is_min = measurement.flags & FLAG_MIN != 0;
+        let is_max = measurement.flags & FLAG_MAX != 0;
+        self.flags |= measurement.flags & !(FLAG_MIN | FLAG_MAX);
+        if !is_min && !is_max {
+            self.count += 1;
+            for (sum, &value) in self.sums.iter_mut().zip(values) {
+                *sum += value as u32;
+            }
+        }
+        if !is_max {
+            let min = self.min.get_or_insert_with(|| values.to_vec());
+            for (min, &value) in min.iter_mut().zip(values) {
+                *min = (*min).min(value);
+            }
+        }
+        if !is_min {
+            let max = self.max.get_or_insert_with(|| values.to_vec());
+            for (max, &value) in max.iter_mut().zip(values) {
+                *max = (*max).max(value);
+            }
+        }
+    }
+
+    fn close_bucket(&mut self) {
+        let Some(start) = self.bucket_start.take() else {
+            return;
+        };
+        let flags = std::mem::take(&mut self.flags) | FLAG_AGGREGATED;
+        let count = std::mem::take(&mut self.count);
+        if count > 0 {
+            let values: Vec<u16> = self
+                .sums
+                .iter()
+                .map(|sum| ((sum + count / 2) / count) as u16)
+                .collect();
+            let mut average = Measurement::new(&values, start);
+            average.flags = flags;
+            self.done.push(average);
+        }
+        self.sums.fill(0);
+        let extremes = [
+            (self.min.take(), 1, FLAG_MIN),
+            (self.max.take(), 2, FLAG_MAX),
+        ];
+        for (values, offset, flag) in extremes {
+            if let Some(values) = values.filter(|_| self.keep_min_max) {
+                let mut record = Measurement::new(&values, start + offset);
+                record.flags = flags | flag;
+                self.done.push(record);
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Measurement> {
+        self.close_bucket();
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::measurement::FLAG_WARMUP;
+    use std::io::Cursor;
+
+    const STREAMS: usize = 2;
+    const DAY: u32 = 24 * 60 * 60;
+
+    fn record(timestamp: u32, values: [u16; STREAMS], flags: u8) -> Measurement {
+        let mut record = Measurement::new(&values, timestamp);
+        record.flags = flags;
+        record
+    }
+
+    fn lines(records: &[Measurement]) -> ForwardMeasurementIter<Cursor<Vec<u8>>> {
+        let file: Vec<u8> = records
+            .chunks(MAX_LINE_RECORDS)
+            .flat_map(|line| encode_measurements(line, STREAMS))
+            .collect();
+        ForwardMeasurementIter::new(Cursor::new(file), STREAMS, 0).unwrap()
+    }
+
+    fn run(
+        records: &[Measurement],
+        keep_min_max: bool,
+        bucket_secs: impl Fn(&Measurement) -> Option<u32>,
+    ) -> Vec<Measurement> {
+        let mut out = Vec::new();
+        let written = aggregate(lines(records), &mut out, keep_min_max, bucket_secs).unwrap();
+        assert_eq!(written, out.len() as u64);
+        ForwardMeasurementIter::new(Cursor::new(out), STREAMS, 0)
+            .unwrap()
+            .flat_map(|line| line.measurements)
+            .collect()
+    }
+
+    #[test]
+    fn buckets_become_their_average_min_and_max() {
+        let records = [
+            record(6000, [10, 40], 0),
+            record(6010, [20, 20], FLAG_WARMUP),
+            record(6020, [31, 30], 0),
+            record(6060, [7, 7], 0),
+        ];
+        let flags = FLAG_AGGREGATED | FLAG_WARMUP;
+        assert_eq!(
+            run(&records, true, |_| Some(60)),
+            [
+                record(6000, [20, 30], flags),
+                record(6001, [10, 20], flags | FLAG_MIN),
+                record(6002, [31, 40], flags | FLAG_MAX),
+                record(6060, [7, 7], FLAG_AGGREGATED),
+                record(6061, [7, 7], FLAG_AGGREGATED | FLAG_MIN),
+                record(6062, [7, 7], FLAG_AGGREGATED | FLAG_MAX),
+            ]
+        );
+        assert_eq!(
+            run(&records, false, |_| Some(60)),
+            [
+                record(6000, [20, 30], flags),
+                record(6060, [7, 7], FLAG_AGGREGATED)
+            ]
+        );
+    }
+
+    #[test]
+    fn min_and_max_fold_into_a_coarser_bucket() {
+        let records = [
+            record(6000, [10, 1], 0),
+            record(6010, [30, 9], 0),
+            record(6060, [50, 5], 0),
+            record(6070, [54, 3], 0),
+        ];
+        let minutes = run(&records, true, |_| Some(60));
+        assert_eq!(minutes.len(), 6);
+        // Only the averages count towards the new average, the extremes keep theirs
+        assert_eq!(
+            run(&minutes, true, |_| Some(600)),
+            [
+                record(6000, [36, 5], FLAG_AGGREGATED),
+                record(6001, [10, 1], FLAG_AGGREGATED | FLAG_MIN),
+                record(6002, [54, 9], FLAG_AGGREGATED | FLAG_MAX),
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregating_into_the_same_buckets_again_changes_nothing() {
+        let records: Vec<Measurement> = (0..200)
+            .map(|i| record(6000 + i * 7, [i as u16, 500 - i as u16], 0))
+            .collect();
+        for keep_min_max in [false, true] {
+            let once = run(&records, keep_min_max, |_| Some(600));
+            assert_eq!(run(&once, keep_min_max, |_| Some(600)), once);
+        }
+    }
+
+    #[test]
+    fn tier_cutoffs_are_rounded_down_to_the_bucket_size() {
+        let newest = 10 * DAY + 1234;
+        let at = |timestamp| tier_bucket(&record(timestamp, [0, 0], 0), newest);
+        // A day before the newest record is 9 days + 1234 s, rounded down to 10 min
+        assert_eq!(at(9 * DAY + 1200), None);
+        assert_eq!(at(9 * DAY + 1199), Some(600));
+        // A week before it is 3 days + 1234 s, rounded down to the hour
+        assert_eq!(at(3 * DAY), Some(600));
+        assert_eq!(at(3 * DAY - 1), Some(3600));
+    }
+
+    #[test]
+    fn records_without_a_set_clock_are_kept_as_they_are() {
+        let newest = 10 * DAY;
+        let unset = record(5, [3, 4], FLAG_CLOCK_UNSET);
+        assert_eq!(tier_bucket(&unset, newest), None);
+        let records = [
+            record(DAY, [10, 10], 0),
+            unset,
+            record(DAY + 3600, [20, 20], 0),
+            record(newest, [1, 1], 0),
+        ];
+        assert_eq!(
+            run(&records, false, |m| tier_bucket(m, newest)),
+            [
+                record(DAY, [10, 10], FLAG_AGGREGATED),
+                unset,
+                record(DAY + 3600, [20, 20], FLAG_AGGREGATED),
+                record(newest, [1, 1], 0),
+            ]
+        );
+    }
+}
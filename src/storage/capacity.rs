@@ -0,0 +1,151 @@
+use crate::storage::aggregation;
+use crate::storage::line_format;
+use crate::storage::storage_iterator::ForwardMeasurementIter;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::{esp, esp_littlefs_info};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Label of the littlefs partition in partitions.csv.
+pub const PARTITION_LABEL: &CStr = c"storage";
+/// Fill level at which the overflow policy kicks in. Kept well below 100 %: littlefs
+/// needs free blocks to stay writable, and a policy needs room to rewrite a file.
+pub const HIGH_WATER_PERCENT: u64 = 85;
+/// Fill level a policy frees space down to, so it does not run again on the next flush.
+pub const LOW_WATER_PERCENT: u64 = 70;
+/// Records are averaged into buckets of this many seconds when downsampling.
+pub const DOWNSAMPLE_SECS: u32 = 60;
+
+/// What happens to new measurements once the partition reaches `HIGH_WATER_PERCENT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoragePolicy {
+    /// Reject new measurements (the behaviour before policies existed).
+    #[default]
+    Stop = 0,
+    /// Delete the oldest data to make room.
+    DropOldest = 1,
+    /// Average the oldest data into `DOWNSAMPLE_SECS` records, dropping data only
+    /// when nothing is left to downsample.
+    Downsample = 2,
+}
+
+impl StoragePolicy {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Stop),
+            1 => Some(Self::DropOldest),
+            2 => Some(Self::Downsample),
+            _ => None,
+        }
+    }
+}
+
+/// Fill level of the storage partition and the policy applied when it runs full.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageUsage {
+    pub policy: StoragePolicy,
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl StorageUsage {
+    pub fn fill_percent(&self) -> u8 {
+        (self.used_bytes * 100)
+            .checked_div(self.total_bytes)
+            .map_or(100, |p| p.min(100) as u8)
+    }
+
+    pub fn is_above(&self, percent: u64) -> bool {
+        self.used_bytes * 100 >= self.total_bytes * percent
+    }
+
+    /// Bytes that can be written before reaching `percent`.
+    pub fn room_below(&self, percent: u64) -> u64 {
+        (self.total_bytes * percent / 100).saturating_sub(self.used_bytes)
+    }
+}
+
+/// `(total, used)` bytes of the storage partition. littlefs walks the whole filesystem
+/// to count used blocks, so callers should not do this on every write.
+#[cfg(target_os = "espidf")]
+pub fn partition_usage() -> anyhow::Result<(u64, u64)> {
+    let mut total = 0usize;
+    let mut used = 0usize;
+    esp!(unsafe { esp_littlefs_info(PARTITION_LABEL.as_ptr(), &mut total, &mut used) })?;
+    Ok((total as u64, used as u64))
+}
+
+/// Offset of the first line starting at or after `position`, or the end of the file.
+pub fn line_boundary(
+    file: &mut File,
+    position: u64,
+    stream_count: usize,
+    xor_lines: bool,
+) -> std::io::Result<u64> {
+    let mut buf = Vec::with_capacity(2 * line_format::max_line_size(stream_count));
+    file.seek(SeekFrom::Start(position))?;
+    Read::by_ref(file)
+        .take(buf.capacity() as u64)
+        .read_to_end(&mut buf)?;
+    Ok(
+        match line_format::next_line(&buf, stream_count, xor_lines) {
+            Some((offset, _, _)) => position + offset as u64,
+            None => file.metadata()?.len(),
+        },
+    )
+}
+
+/// Average `lines` into `DOWNSAMPLE_SECS` records and write them to `out` as new
+/// lines, with their minimum and maximum if `keep_min_max`. Returns the number of
+/// bytes written, so a dry run into `std::io::sink()` tells whether downsampling is
+/// worth it.
+pub fn downsample<R: Read + Seek>(
+    lines: ForwardMeasurementIter<R>,
+    out: &mut impl Write,
+    keep_min_max: bool,
+) -> std::io::Result<u64> {
+    aggregation::aggregate(lines, out, keep_min_max, |_| Some(DOWNSAMPLE_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::measurement::{Measurement, FLAG_AGGREGATED};
+    use crate::storage::storage_iterator::encode_measurements;
+    use std::io::Cursor;
+
+    const STREAMS: usize = 2;
+
+    #[test]
+    fn downsample_averages_each_minute_and_its_dry_run_matches() {
+        let records: Vec<Measurement> = (0..9)
+            .map(|i| Measurement::new(&[i * 2, 100], 6000 + i as u32 * 20))
+            .collect();
+        let file = Cursor::new(encode_measurements(&records, STREAMS));
+        let lines = || ForwardMeasurementIter::new(file.clone(), STREAMS, 0).unwrap();
+        let mut out = Vec::new();
+        let written = downsample(lines(), &mut out, false).unwrap();
+        assert_eq!(written, out.len() as u64);
+        assert_eq!(
+            downsample(lines(), &mut std::io::sink(), false).unwrap(),
+            written
+        );
+
+        let downsampled: Vec<Measurement> =
+            ForwardMeasurementIter::new(Cursor::new(out), STREAMS, 0)
+                .unwrap()
+                .flat_map(|line| line.measurements)
+                .collect();
+        let expected: Vec<Measurement> = [(6000, 2), (6060, 8), (6120, 14)]
+            .into_iter()
+            .map(|(timestamp, average)| {
+                let mut record = Measurement::new(&[average, 100], timestamp);
+                record.flags = FLAG_AGGREGATED;
+                record
+            })
+            .collect();
+        assert_eq!(downsampled, expected);
+        assert!(written < encode_measurements(&records, STREAMS).len() as u64);
+    }
+}
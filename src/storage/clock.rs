@@ -0,0 +1,119 @@
+use crate::storage::line_format::crc16;
+use crate::storage::storage_controller::SESSIONS_DIR;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// No clock that has been set reads earlier than this (2024-01-01). Until the app or
+/// the server sets it, the clock counts up from 0 at boot.
+pub const MIN_VALID_EPOCH: u32 = 1_704_067_200;
+/// Clock corrections smaller than this are drift rather than a jump.
+const MIN_JUMP_SECS: u32 = 60;
+/// u32 clock before + u32 clock after + u16 records rebased + u16 CRC-16, all LE
+const JUMP_SIZE: usize = 12;
+
+/// Whether `timestamp` was taken with the clock set.
+pub fn is_set(timestamp: u32) -> bool {
+    timestamp >= MIN_VALID_EPOCH
+}
+
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// The clock being moved from `from` to `to`, both epoch seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockJump {
+    pub from: u32,
+    pub to: u32,
+}
+
+impl ClockJump {
+    pub fn is_jump(&self) -> bool {
+        self.from.abs_diff(self.to) >= MIN_JUMP_SECS
+    }
+
+    /// Seconds the clock moved by, as an i32 in two's complement.
+    pub fn delta(&self) -> u32 {
+        self.to.wrapping_sub(self.from)
+    }
+
+    /// The clock was counting from boot and now is set.
+    pub fn sets_clock(&self) -> bool {
+        !is_set(self.from) && is_set(self.to)
+    }
+
+    /// `timestamp`, taken before the jump while the clock counted from boot, moved
+    /// onto the clock as set. The unset clock runs on the monotonic uptime timer, so
+    /// `from - timestamp` is how long before the jump it was taken. `None` if the
+    /// timestamp was taken with the clock set or after the jump.
+    pub fn rebase(&self, timestamp: u32) -> Option<u32> {
+        (self.sets_clock() && !is_set(timestamp) && timestamp <= self.from)
+            .then(|| self.to - (self.from - timestamp))
+    }
+}
+
+/// A jump of the clock while a session was recording, with how many of its stored
+/// records were rebased across it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockMark {
+    pub jump: ClockJump,
+    pub rebased: u16,
+}
+
+fn marks_path(session: &Uuid) -> String {
+    format!("{}/{}.clk", SESSIONS_DIR, session.simple())
+}
+
+impl ClockMark {
+    fn encode(&self) -> [u8; JUMP_SIZE] {
+        let mut bytes = [0u8; JUMP_SIZE];
+        bytes[0..4].copy_from_slice(&self.jump.from.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.jump.to.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.rebased.to_le_bytes());
+        let crc = crc16(&bytes[..JUMP_SIZE - 2]);
+        bytes[JUMP_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let crc = u16::from_le_bytes([bytes[JUMP_SIZE - 2], bytes[JUMP_SIZE - 1]]);
+        (crc16(&bytes[..JUMP_SIZE - 2]) == crc).then(|| Self {
+            jump: ClockJump {
+                from: u32_at(0),
+                to: u32_at(4),
+            },
+            rebased: u16::from_le_bytes([bytes[8], bytes[9]]),
+        })
+    }
+
+    /// Append to the marks kept in a sidecar next to the session file.
+    pub fn append(&self, session: &Uuid) -> std::io::Result<()> {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(marks_path(session))?
+            .write_all(&self.encode())
+    }
+
+    /// The marks of `session`, oldest first; damaged ones are left out.
+    pub fn load(session: &Uuid) -> Vec<Self> {
+        let bytes = std::fs::read(marks_path(session)).unwrap_or_default();
+        bytes
+            .chunks_exact(JUMP_SIZE)
+            .filter_map(Self::decode)
+            .collect()
+    }
+
+    /// Forget the marks of a session whose file is gone.
+    pub fn remove(session: &Uuid) -> std::io::Result<()> {
+        match std::fs::remove_file(marks_path(session)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
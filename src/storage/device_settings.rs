@@ -0,0 +1,35 @@
+use crate::alarm::AlarmConfig;
+use crate::led::air_quality::AirQualityLed;
+use crate::sensor::sensor_usage::DEFAULT_SENSOR_LIFETIME_HOURS;
+use crate::storage::aggregation::AggregationPolicy;
+use crate::storage::capacity::StoragePolicy;
+use crate::storage::flush_policy::FlushPolicy;
+use crate::storage::mount_recovery::MountRecovery;
+
+/// Per-device preferences set over BLE. Unlike `SessionConfig` these survive
+/// the end of a session.
+#[derive(Clone, Debug)]
+pub struct DeviceSettings {
+    pub air_quality_led: AirQualityLed,
+    pub alarms: Vec<AlarmConfig>,
+    /// Fan-on hours after which the PMS is flagged as nearing end of life.
+    pub sensor_lifetime_hours: u32,
+    pub storage_policy: StoragePolicy,
+    pub flush_policy: FlushPolicy,
+    pub aggregation_policy: AggregationPolicy,
+    pub mount_recovery: MountRecovery,
+}
+
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        Self {
+            air_quality_led: AirQualityLed::default(),
+            alarms: Vec::new(),
+            sensor_lifetime_hours: DEFAULT_SENSOR_LIFETIME_HOURS,
+            storage_policy: StoragePolicy::default(),
+            flush_policy: FlushPolicy::default(),
+            aggregation_policy: AggregationPolicy::default(),
+            mount_recovery: MountRecovery::default(),
+        }
+    }
+}
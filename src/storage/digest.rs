@@ -0,0 +1,272 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::{
+    mbedtls_sha256_context, mbedtls_sha256_finish, mbedtls_sha256_free, mbedtls_sha256_init,
+    mbedtls_sha256_starts, mbedtls_sha256_update,
+};
+use std::io::{self, Read};
+use uuid::Uuid;
+
+const CHUNK_SIZE: usize = 4096;
+
+/// CRC-32 (IEEE 802.3, as zlib computes it), one table entry per byte value.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DigestAlgorithm {
+    Crc32 = 0,
+    /// Computed by mbedtls, on the SHA accelerator of the chip; in software on the host.
+    Sha256 = 1,
+}
+
+impl DigestAlgorithm {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Crc32),
+            1 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Crc32(u32),
+    Sha256([u8; 32]),
+}
+
+impl Digest {
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        match self {
+            Self::Crc32(_) => DigestAlgorithm::Crc32,
+            Self::Sha256(_) => DigestAlgorithm::Sha256,
+        }
+    }
+
+    /// CRC-32 in little-endian byte order, SHA-256 as is.
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Crc32(crc) => crc.to_le_bytes().to_vec(),
+            Self::Sha256(hash) => hash.to_vec(),
+        }
+    }
+
+    /// Lowercase hex, the CRC-32 as a number, the way `crc32` and `sha256sum` print them.
+    pub fn to_hex(self) -> String {
+        match self {
+            Self::Crc32(crc) => format!("{:08x}", crc),
+            Self::Sha256(hash) => hash.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+/// A byte range of a session to digest, of the session as `/sync` sends it.
+#[derive(Debug, Clone, Copy)]
+pub struct DigestRequest {
+    pub session: Uuid,
+    pub algorithm: DigestAlgorithm,
+    pub offset: u64,
+    /// 0 for everything from `offset` on.
+    pub length: u64,
+}
+
+/// A digest over a byte range of a session, as sent by `/sync`.
+#[derive(Debug, Clone, Copy)]
+pub struct RangeDigest {
+    pub offset: u64,
+    /// Bytes covered, short of the requested length if the data ends first.
+    pub length: u64,
+    pub digest: Digest,
+}
+
+#[cfg(target_os = "espidf")]
+struct Sha256(mbedtls_sha256_context);
+
+#[cfg(target_os = "espidf")]
+impl Sha256 {
+    fn new() -> io::Result<Self> {
+        let mut sha = Self(unsafe { std::mem::zeroed() });
+        unsafe { mbedtls_sha256_init(&mut sha.0) };
+        check(unsafe { mbedtls_sha256_starts(&mut sha.0, 0) })?;
+        Ok(sha)
+    }
+
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        check(unsafe { mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len()) })
+    }
+
+    fn finish(mut self) -> io::Result<[u8; 32]> {
+        let mut hash = [0u8; 32];
+        check(unsafe { mbedtls_sha256_finish(&mut self.0, hash.as_mut_ptr()) })?;
+        Ok(hash)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+struct Sha256(sha2::Sha256);
+
+#[cfg(not(target_os = "espidf"))]
+impl Sha256 {
+    fn new() -> io::Result<Self> {
+        Ok(Self(sha2::Digest::new()))
+    }
+
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        sha2::Digest::update(&mut self.0, data);
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<[u8; 32]> {
+        Ok(sha2::Digest::finalize(self.0).into())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn check(result: i32) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("mbedtls error {}", result)))
+    }
+}
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Digest of everything `reader` yields, with the number of bytes read.
+pub fn digest(mut reader: impl Read, algorithm: DigestAlgorithm) -> io::Result<(Digest, u64)> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut crc = 0;
+    let mut sha = match algorithm {
+        DigestAlgorithm::Sha256 => Some(Sha256::new()?),
+        DigestAlgorithm::Crc32 => None,
+    };
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        match sha.as_mut() {
+            Some(sha) => sha.update(&buf[..n])?,
+            None => crc = crc32_update(crc, &buf[..n]),
+        }
+        total += n as u64;
+    }
+    let digest = match sha {
+        Some(sha) => Digest::Sha256(sha.finish()?),
+        None => Digest::Crc32(crc),
+    };
+    Ok((digest, total))
+}
+
+/// Digest of `length` bytes (0 for all) from `offset` into the `size` bytes `reader`
+/// yields, the range cut short where the data ends.
+pub fn digest_range(
+    mut reader: impl Read,
+    size: u64,
+    algorithm: DigestAlgorithm,
+    offset: u64,
+    length: u64,
+) -> io::Result<RangeDigest> {
+    let offset = offset.min(size);
+    io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+    let length = match length {
+        0 => size - offset,
+        length => length.min(size - offset),
+    };
+    let (digest, length) = digest(reader.take(length), algorithm)?;
+    Ok(RangeDigest {
+        offset,
+        length,
+        digest,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK_INPUT: &[u8] = b"123456789";
+
+    #[test]
+    fn crc32_check_value() {
+        let (digest, len) = digest(CHECK_INPUT, DigestAlgorithm::Crc32).unwrap();
+        assert_eq!(digest, Digest::Crc32(0xCBF4_3926));
+        assert_eq!(len, 9);
+        assert_eq!(digest.to_hex(), "cbf43926");
+        assert_eq!(digest.to_bytes(), [0x26, 0x39, 0xF4, 0xCB]);
+    }
+
+    #[test]
+    fn crc32_is_the_same_across_chunks() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 17).map(|i| (i * 31) as u8).collect();
+        let (whole, _) = digest(&data[..], DigestAlgorithm::Crc32).unwrap();
+        let split = data.split_at(CHUNK_SIZE + 5);
+        let crc = crc32_update(crc32_update(0, split.0), split.1);
+        assert_eq!(whole, Digest::Crc32(crc));
+    }
+
+    #[test]
+    fn sha256_check_value() {
+        let (digest, len) = digest(CHECK_INPUT, DigestAlgorithm::Sha256).unwrap();
+        assert_eq!(len, 9);
+        assert_eq!(
+            digest.to_hex(),
+            "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+        );
+    }
+
+    #[test]
+    fn range_covers_only_its_bytes() {
+        let data = b"xxx123456789yy";
+        let size = data.len() as u64;
+        let range = digest_range(&data[..], size, DigestAlgorithm::Crc32, 3, 9).unwrap();
+        assert_eq!((range.offset, range.length), (3, 9));
+        assert_eq!(range.digest, Digest::Crc32(0xCBF4_3926));
+    }
+
+    #[test]
+    fn range_is_cut_short_at_the_end_of_the_data() {
+        let data = b"xxx123456789";
+        let size = data.len() as u64;
+        let range = digest_range(&data[..], size, DigestAlgorithm::Crc32, 3, 100).unwrap();
+        assert_eq!((range.offset, range.length), (3, 9));
+        assert_eq!(range.digest, Digest::Crc32(0xCBF4_3926));
+        // Everything from the offset on
+        let range = digest_range(&data[..], size, DigestAlgorithm::Crc32, 3, 0).unwrap();
+        assert_eq!(range.digest, Digest::Crc32(0xCBF4_3926));
+        let range = digest_range(&data[..], size, DigestAlgorithm::Crc32, 20, 5).unwrap();
+        assert_eq!((range.offset, range.length), (size, 0));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::storage::line_format::crc16;
+use log::{info, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const EVENTS_FILE_PATH: &str = "/storage/events.bin";
+const EVENTS_OLD_FILE_PATH: &str = "/storage/events.old";
+// Roughly 740 events; the previous generation is kept in EVENTS_OLD_FILE_PATH
+const EVENTS_MAX_FILE_SIZE: u64 = 8192;
+/// u32 timestamp + u8 kind + u32 detail, all LE.
+pub const ENCODED_EVENT_SIZE: usize = 9;
+/// An encoded event followed by its CRC-16, as stored.
+const EVENT_SIZE: usize = ENCODED_EVENT_SIZE + 2;
+
+/// Serializes appends and rotation, which happen from the main loop as well as the
+/// WiFi and BLE threads.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// What an event's `detail` holds is given per kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    /// `esp_reset_reason()` of the boot.
+    Boot = 0x01,
+    /// First 4 bytes of the session UUID.
+    SessionStarted = 0x02,
+    /// First 4 bytes of the session UUID.
+    SessionEnded = 0x03,
+    /// Unused, 0.
+    WifiDisconnected = 0x04,
+    /// HTTP status of the failed upload, 0 if no response was received.
+    UploadFailed = 0x05,
+    /// errno of the failed storage write, 0 if not an OS error.
+    StorageFailed = 0x06,
+    /// Fill level in percent when the overflow policy could not make room.
+    StorageFull = 0x07,
+    /// Seconds the clock was moved by, as an i32.
+    TimeJump = 0x08,
+    /// esp_err_t of the last failed mount, recorded once the partition is mounted
+    /// again (see `mount_recovery`).
+    MountFailed = 0x09,
+}
+
+impl EventKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x01 => Self::Boot,
+            0x02 => Self::SessionStarted,
+            0x03 => Self::SessionEnded,
+            0x04 => Self::WifiDisconnected,
+            0x05 => Self::UploadFailed,
+            0x06 => Self::StorageFailed,
+            0x07 => Self::StorageFull,
+            0x08 => Self::TimeJump,
+            0x09 => Self::MountFailed,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub timestamp: u32,
+    pub kind: EventKind,
+    pub detail: u32,
+}
+
+impl Event {
+    /// Encoded without the CRC, as sent over BLE.
+    pub fn encode(&self) -> [u8; ENCODED_EVENT_SIZE] {
+        let mut bytes = [0u8; ENCODED_EVENT_SIZE];
+        bytes[0..4].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[4] = self.kind as u8;
+        bytes[5..9].copy_from_slice(&self.detail.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (body, crc) = bytes.split_at(ENCODED_EVENT_SIZE);
+        if crc16(body).to_le_bytes() != crc {
+            return None;
+        }
+        Some(Self {
+            timestamp: u32::from_le_bytes(body[0..4].try_into().unwrap()),
+            kind: EventKind::from_u8(body[4])?,
+            detail: u32::from_le_bytes(body[5..9].try_into().unwrap()),
+        })
+    }
+}
+
+/// Append an event stamped with the current time. Failures are only logged: the
+/// event log must never get in the way of what it records.
+pub fn record(kind: EventKind, detail: u32) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+    info!("Event {:?} ({})", kind, detail);
+    let mut bytes = Event {
+        timestamp,
+        kind,
+        detail,
+    }
+    .encode()
+    .to_vec();
+    bytes.extend_from_slice(&crc16(&bytes).to_le_bytes());
+
+    let _guard = LOCK.lock().unwrap();
+    let result = (|| -> std::io::Result<()> {
+        if std::fs::metadata(EVENTS_FILE_PATH).is_ok_and(|m| m.len() >= EVENTS_MAX_FILE_SIZE) {
+            std::fs::rename(EVENTS_FILE_PATH, EVENTS_OLD_FILE_PATH)?;
+        }
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(EVENTS_FILE_PATH)?
+            .write_all(&bytes)
+    })();
+    if let Err(e) = result {
+        warn!("Failed to record event {:?}: {}", kind, e);
+    }
+}
+
+/// Both generations of the log as stored, oldest first.
+pub fn raw() -> Vec<u8> {
+    let _guard = LOCK.lock().unwrap();
+    let mut bytes = std::fs::read(EVENTS_OLD_FILE_PATH).unwrap_or_default();
+    bytes.extend(std::fs::read(EVENTS_FILE_PATH).unwrap_or_default());
+    bytes
+}
+
+/// Every intact event, oldest first. Entries failing their CRC or of a kind this
+/// firmware does not know are skipped.
+pub fn read() -> Vec<Event> {
+    let mut events = Vec::new();
+    // Each generation on its own, so a torn entry at the end of the old file cannot
+    // shift the new one out of alignment.
+    let _guard = LOCK.lock().unwrap();
+    for path in [EVENTS_OLD_FILE_PATH, EVENTS_FILE_PATH] {
+        let bytes = std::fs::read(path).unwrap_or_default();
+        events.extend(bytes.chunks_exact(EVENT_SIZE).filter_map(Event::decode));
+    }
+    events
+}
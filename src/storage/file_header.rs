@@ -0,0 +1,134 @@
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// "ABMS" - cannot be confused with the `0xAB 0xBA` start of a measurement line,
+/// which is how files written before the header existed begin.
+pub const HEADER_MAGIC: [u8; 4] = *b"ABMS";
+/// 1: legacy lines only. 2: compact lines may follow (see `line_format`).
+/// 3: lines are checked with CRC-16 instead of XOR.
+pub const HEADER_VERSION: u8 = 3;
+const CRC_LINES_VERSION: u8 = 3;
+/// 4B magic + u8 version + u16 header size + u8 stream count + 16B session UUID + 6B MAC
+/// + 16B firmware version + u32 creation time + u8 XOR checksum
+pub const HEADER_SIZE: usize = 51;
+const FIRMWARE_SIZE: usize = 16;
+
+/// Identity of a measurement file, written once at its start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub version: u8,
+    /// Bytes before the first measurement line.
+    pub size: u16,
+    /// Streams per record in every line of the file.
+    pub stream_count: u8,
+    pub session: Uuid,
+    pub mac: [u8; 6],
+    pub firmware: String,
+    /// Unix time the file was started, as known to the device at that point.
+    pub created: u32,
+}
+
+impl FileHeader {
+    pub fn new(session: Uuid, stream_count: usize, mac: [u8; 6]) -> Self {
+        Self {
+            version: HEADER_VERSION,
+            size: HEADER_SIZE as u16,
+            stream_count: stream_count as u8,
+            session,
+            mac,
+            firmware: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as u32),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(&HEADER_MAGIC);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        bytes.push(self.stream_count);
+        bytes.extend_from_slice(&self.session.to_bytes_le());
+        bytes.extend_from_slice(&self.mac);
+        let mut firmware = [0u8; FIRMWARE_SIZE];
+        let len = self.firmware.len().min(FIRMWARE_SIZE);
+        firmware[..len].copy_from_slice(&self.firmware.as_bytes()[..len]);
+        bytes.extend_from_slice(&firmware);
+        bytes.extend_from_slice(&self.created.to_le_bytes());
+        bytes.push(bytes.iter().fold(0u8, |acc, &b| acc ^ b));
+        bytes
+    }
+
+    /// `Ok(None)` for a headerless file (including an empty one). A file that starts
+    /// with the magic but carries a broken or unknown header is an error.
+    pub fn decode(data: &[u8]) -> std::io::Result<Option<Self>> {
+        if data.len() < HEADER_MAGIC.len() || data[..HEADER_MAGIC.len()] != HEADER_MAGIC {
+            return Ok(None);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(invalid("truncated file header"));
+        }
+        let version = data[4];
+        if version == 0 || version > HEADER_VERSION {
+            return Err(invalid(&format!("unsupported file version {}", version)));
+        }
+        let size = u16::from_le_bytes([data[5], data[6]]);
+        if size as usize != HEADER_SIZE {
+            return Err(invalid(&format!("unexpected header size {}", size)));
+        }
+        let checksum = data[..HEADER_SIZE - 1].iter().fold(0u8, |acc, &b| acc ^ b);
+        if checksum != data[HEADER_SIZE - 1] {
+            return Err(invalid("file header checksum mismatch"));
+        }
+        let firmware = &data[30..30 + FIRMWARE_SIZE];
+        Ok(Some(Self {
+            version,
+            size,
+            stream_count: data[7],
+            session: Uuid::from_slice_le(&data[8..24]).map_err(|_| invalid("bad session"))?,
+            mac: data[24..30].try_into().unwrap(),
+            firmware: String::from_utf8_lossy(
+                &firmware[..firmware
+                    .iter()
+                    .position(|&b| b == 0)
+                    .unwrap_or(FIRMWARE_SIZE)],
+            )
+            .into_owned(),
+            created: u32::from_le_bytes(data[46..50].try_into().unwrap()),
+        }))
+    }
+
+    /// Read the header at the start of `file`, leaving the position undefined.
+    pub fn read(file: &mut (impl Read + Seek)) -> std::io::Result<Option<Self>> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        file.seek(SeekFrom::Start(0))?;
+        file.take(HEADER_SIZE as u64).read_to_end(&mut buf)?;
+        Self::decode(&buf)
+    }
+
+    /// Whether the file may hold XOR-checked lines: it was started by firmware from
+    /// before CRC-16 lines, or has no header at all.
+    pub fn xor_lines(header: Option<&Self>) -> bool {
+        header.is_none_or(|header| header.version < CRC_LINES_VERSION)
+    }
+
+    /// One-line summary for the log.
+    pub fn describe(&self) -> String {
+        let mac: Vec<String> = self.mac.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            "v{} session {} device {} firmware {} created {} ({} streams)",
+            self.version,
+            self.session,
+            mac.join(":"),
+            self.firmware,
+            self.created,
+            self.stream_count
+        )
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
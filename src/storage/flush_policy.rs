@@ -0,0 +1,578 @@
+use crate::sensor::measurement::Measurement;
+use std::time::{Duration, Instant};
+
+/// u16 min interval (s) + u16 max age (s) + u8 low battery percent
+pub const FLUSH_POLICY_SIZE: usize = 5;
+
+/// When a mobile session writes its buffered records to flash, short of the buffer
+/// filling up. Every flush is a littlefs commit, so flushing less often saves flash
+/// wear and power; every record still buffered is at risk should the journal be lost
+/// along with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Flush no more often than this, unless the battery is low.
+    pub min_interval: Duration,
+    /// Flush before the oldest buffered record would get older than this while
+    /// waiting for the next one. Zero flushes every record.
+    pub max_age: Duration,
+    /// Flush every record while discharging at or below this percentage, where a
+    /// brown-out can come at any time. Zero disables it.
+    pub low_battery_percent: u8,
+}
+
+impl Default for FlushPolicy {
+    /// Records of sessions at intervals of a minute or more are flushed one by one,
+    /// faster ones at least once a minute.
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::ZERO,
+            max_age: Duration::from_secs(60),
+            low_battery_percent: 20,
+        }
+    }
+}
+
+/// Records buffered by `StorageManager`, as the policy sees them.
+#[derive(Debug, Clone, Copy)]
+pub struct Pending {
+    pub records: usize,
+    /// Since the oldest buffered record was buffered.
+    pub oldest_age: Duration,
+    /// Since the last flush, `None` before the first.
+    pub since_flush: Option<Duration>,
+}
+
+impl FlushPolicy {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < FLUSH_POLICY_SIZE {
+            return None;
+        }
+        let secs =
+            |at: usize| Duration::from_secs(u16::from_le_bytes([data[at], data[at + 1]]) as u64);
+        Some(Self {
+            min_interval: secs(0),
+            max_age: secs(2),
+            low_battery_percent: data[4],
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&(self.min_interval.as_secs() as u16).to_le_bytes());
+        buf[2..4].copy_from_slice(&(self.max_age.as_secs() as u16).to_le_bytes());
+        buf[4] = self.low_battery_percent;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.low_battery_percent <= 100
+    }
+
+    /// Whether `pending` should be flushed now rather than wait for the next record,
+    /// `interval` from now. `battery` is `BatteryState::signed_percent`.
+    pub fn is_due(&self, pending: &Pending, interval: Duration, battery: i8) -> bool {
+        if pending.records == 0 {
+            return false;
+        }
+        if battery < 0 && battery.unsigned_abs() <= self.low_battery_percent {
+            return true;
+        }
+        pending.oldest_age + interval >= self.max_age
+            && pending
+                .since_flush
+                .is_none_or(|since| since >= self.min_interval)
+    }
+}
+
+/// Records `StorageManager` holds back from flash, and when it last flushed.
+pub struct RecordBuffer {
+    records: Vec<Measurement>,
+    capacity: usize,
+    /// Streams every record is stored with.
+    stream_count: usize,
+    /// When the oldest record in `records` was buffered.
+    since: Option<Instant>,
+    last_flush: Option<Instant>,
+    /// Leading records already in the journal.
+    journaled: usize,
+}
+
+impl RecordBuffer {
+    pub fn new(capacity: usize, stream_count: usize) -> Self {
+        Self {
+            records: Vec::with_capacity(capacity),
+            capacity,
+            stream_count,
+            since: None,
+            last_flush: None,
+            journaled: 0,
+        }
+    }
+
+    pub fn records(&self) -> &[Measurement] {
+        &self.records
+    }
+
+    pub fn records_mut(&mut self) -> &mut [Measurement] {
+        &mut self.records
+    }
+
+    /// Records not in the journal yet.
+    pub fn unjournaled(&self) -> &[Measurement] {
+        &self.records[self.journaled..]
+    }
+
+    /// All records are in the journal now.
+    pub fn mark_journaled(&mut self) {
+        self.journaled = self.records.len();
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.records.len() >= self.capacity
+    }
+
+    /// Buffer `record`, received at `now`, unless the buffer is full. Records always
+    /// carry every stream on disk.
+    fn push(&mut self, mut record: Measurement, now: Instant) {
+        if !self.is_full() {
+            record.resize(self.stream_count);
+            self.records.push(record);
+            self.since.get_or_insert(now);
+        }
+    }
+
+    fn pending(&self, now: Instant) -> Pending {
+        Pending {
+            records: self.records.len(),
+            oldest_age: self.since.map_or(Duration::ZERO, |since| now - since),
+            since_flush: self.last_flush.map(|at| now - at),
+        }
+    }
+
+    /// Drop the records, flushed or not.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.since = None;
+        self.journaled = 0;
+    }
+
+    /// The records were written to flash at `now`.
+    pub fn flushed(&mut self, now: Instant) {
+        self.clear();
+        self.last_flush = Some(now);
+    }
+}
+
+/// The session file and journal behind a `RecordBuffer`, for `save` and
+/// `flush_if_due`.
+pub trait BufferStore {
+    type Error;
+
+    fn buffer(&mut self) -> &mut RecordBuffer;
+
+    /// Write the buffered records to the session file and mark them
+    /// `RecordBuffer::flushed`.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+
+    /// Append the `RecordBuffer::unjournaled` records to the journal in one write and
+    /// mark them journaled.
+    fn journal(&mut self);
+}
+
+/// Buffer `record`, received at `now`, flushing the buffer once it is full.
+pub fn save<S: BufferStore>(
+    store: &mut S,
+    record: Measurement,
+    now: Instant,
+) -> Result<(), S::Error> {
+    let buffer = store.buffer();
+    buffer.push(record, now);
+    if buffer.is_full() {
+        store.flush()
+    } else {
+        Ok(())
+    }
+}
+
+/// Flush the buffered records if `policy` says so at `now`, see `FlushPolicy::is_due`,
+/// then journal the ones it leaves buffered. Returns whether it flushed.
+pub fn flush_if_due<S: BufferStore>(
+    store: &mut S,
+    policy: &FlushPolicy,
+    interval: Duration,
+    battery: i8,
+    now: Instant,
+) -> Result<bool, S::Error> {
+    let pending = store.buffer().pending(now);
+    let result = if policy.is_due(&pending, interval, battery) {
+        store.flush().map(|()| true)
+    } else {
+        Ok(false)
+    };
+    store.journal();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::journal;
+    use crate::storage::storage_iterator::{encode_measurements, ForwardMeasurementIter};
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use uuid::Uuid;
+
+    const STREAMS: usize = 2;
+    const CHARGING: i8 = 80;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn pending(records: usize, oldest_age: u64, since_flush: Option<u64>) -> Pending {
+        Pending {
+            records,
+            oldest_age: secs(oldest_age),
+            since_flush: since_flush.map(secs),
+        }
+    }
+
+    fn policy(min_interval: u64, max_age: u64, low_battery_percent: u8) -> FlushPolicy {
+        FlushPolicy {
+            min_interval: secs(min_interval),
+            max_age: secs(max_age),
+            low_battery_percent,
+        }
+    }
+
+    #[test]
+    fn empty_buffer_is_never_due() {
+        let policy = policy(0, 0, 100);
+        assert!(!policy.is_due(&pending(0, 3600, None), secs(60), -5));
+        assert!(!policy.is_due(&pending(0, 3600, Some(3600)), secs(60), CHARGING));
+    }
+
+    #[test]
+    fn low_battery_flushes_every_record() {
+        // Held back by both the interval and the age otherwise.
+        let policy = policy(300, 600, 20);
+        let one = pending(1, 0, Some(10));
+        assert!(!policy.is_due(&one, secs(1), CHARGING));
+        assert!(!policy.is_due(&one, secs(1), -21));
+        assert!(policy.is_due(&one, secs(1), -20));
+        assert!(policy.is_due(&one, secs(1), -3));
+        // Charging at a low level is not at risk of a brown-out.
+        assert!(!policy.is_due(&one, secs(1), 3));
+    }
+
+    #[test]
+    fn zero_low_battery_percent_disables_the_override() {
+        let policy = policy(300, 600, 0);
+        assert!(!policy.is_due(&pending(1, 0, Some(10)), secs(1), -1));
+    }
+
+    #[test]
+    fn records_are_flushed_before_they_get_older_than_max_age() {
+        let policy = policy(0, 60, 0);
+        // The next record comes in 10 s; by then the oldest would be 60 s old.
+        assert!(!policy.is_due(&pending(5, 49, Some(49)), secs(10), CHARGING));
+        assert!(policy.is_due(&pending(6, 50, Some(50)), secs(10), CHARGING));
+        assert!(policy.is_due(&pending(6, 90, Some(90)), secs(10), CHARGING));
+        // Zero flushes every record.
+        let policy = FlushPolicy {
+            max_age: Duration::ZERO,
+            ..policy
+        };
+        assert!(policy.is_due(&pending(1, 0, Some(0)), secs(1), CHARGING));
+    }
+
+    #[test]
+    fn min_interval_holds_back_flushes() {
+        let policy = policy(120, 0, 0);
+        // Nothing flushed yet, so nothing to wait for.
+        assert!(policy.is_due(&pending(1, 0, None), secs(1), CHARGING));
+        assert!(!policy.is_due(&pending(1, 0, Some(119)), secs(1), CHARGING));
+        assert!(policy.is_due(&pending(1, 0, Some(120)), secs(1), CHARGING));
+    }
+
+    #[test]
+    fn defaults_flush_records_a_minute_apart_one_by_one() {
+        let policy = FlushPolicy::default();
+        for interval in [60, 61, 300] {
+            assert!(policy.is_due(&pending(1, 0, None), secs(interval), CHARGING));
+            assert!(policy.is_due(&pending(1, 0, Some(0)), secs(interval), CHARGING));
+        }
+        assert!(!policy.is_due(&pending(1, 0, Some(0)), secs(59), CHARGING));
+    }
+
+    #[test]
+    fn defaults_leave_fast_sessions_to_the_full_buffer() {
+        let policy = FlushPolicy::default();
+        let start = Instant::now();
+        let mut buffer = RecordBuffer::new(10, STREAMS);
+        for i in 0..10 {
+            let now = start + secs(i);
+            buffer.push(Measurement::new(&[1, 2], i as u32), now);
+            assert!(!policy.is_due(&buffer.pending(now), secs(1), CHARGING));
+            assert_eq!(buffer.is_full(), i == 9);
+        }
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let policy = policy(90, 3600, 15);
+        let mut buf = [0u8; FLUSH_POLICY_SIZE];
+        policy.encode(&mut buf);
+        assert_eq!(FlushPolicy::decode(&buf), Some(policy));
+        assert_eq!(FlushPolicy::decode(&buf[..4]), None);
+        assert!(!FlushPolicy {
+            low_battery_percent: 101,
+            ..policy
+        }
+        .is_valid());
+    }
+
+    /// A session file and journal in memory, under the real buffer flow.
+    struct Session {
+        buffer: RecordBuffer,
+        /// Clock of the flow, moved along by `tick`.
+        now: Instant,
+        stored: Vec<Measurement>,
+        journal_appends: usize,
+    }
+
+    impl BufferStore for Session {
+        type Error = ();
+
+        fn buffer(&mut self) -> &mut RecordBuffer {
+            &mut self.buffer
+        }
+
+        fn flush(&mut self) -> Result<(), ()> {
+            self.stored.extend_from_slice(self.buffer.records());
+            self.buffer.flushed(self.now);
+            Ok(())
+        }
+
+        fn journal(&mut self) {
+            if !self.buffer.unjournaled().is_empty() {
+                self.journal_appends += 1;
+                self.buffer.mark_journaled();
+            }
+        }
+    }
+
+    impl Session {
+        fn new(start: Instant) -> Self {
+            Self {
+                buffer: RecordBuffer::new(10, STREAMS),
+                now: start,
+                stored: Vec::new(),
+                journal_appends: 0,
+            }
+        }
+
+        /// One record of the main loop of a mobile session at `now`, saved and then
+        /// flushed if due. Returns whether the record was flushed.
+        fn tick(
+            &mut self,
+            policy: &FlushPolicy,
+            record: Measurement,
+            now: Instant,
+            interval: u64,
+            battery: i8,
+        ) -> bool {
+            self.now = now;
+            let stored = self.stored.len();
+            save(self, record, now).unwrap();
+            flush_if_due(self, policy, secs(interval), battery, now).unwrap();
+            self.stored.len() > stored
+        }
+
+        fn stored_timestamps(&self) -> Vec<u32> {
+            self.stored.iter().map(|record| record.timestamp).collect()
+        }
+    }
+
+    #[test]
+    fn due_records_are_flushed() {
+        let policy = FlushPolicy::default();
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        let mut flushes = Vec::new();
+        for i in 0..13u32 {
+            let now = start + secs(10 * i as u64);
+            let record = Measurement::new(&[i as u16, 0], 1_700_000_000 + 10 * i);
+            if session.tick(&policy, record, now, 10, CHARGING) {
+                flushes.push(i);
+            }
+            // Everything buffered is flushed before it is a minute old.
+            let stored = session.stored.len() as u32;
+            assert!(i + 1 - stored <= 6, "{} of {} stored", stored, i + 1);
+        }
+        assert_eq!(flushes, [5, 11]);
+        let expected: Vec<u32> = (0..12).map(|i| 1_700_000_000 + 10 * i).collect();
+        assert_eq!(session.stored_timestamps(), expected);
+    }
+
+    #[test]
+    fn low_battery_records_are_flushed_at_once() {
+        let policy = policy(300, 600, 20);
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        assert!(!session.tick(&policy, Measurement::new(&[1, 2], 1000), start, 1, 50));
+        assert!(session.stored.is_empty());
+        let now = start + secs(1);
+        assert!(session.tick(&policy, Measurement::new(&[3, 4], 1001), now, 1, -19));
+        assert_eq!(session.stored_timestamps(), [1000, 1001]);
+    }
+
+    #[test]
+    fn full_buffer_is_flushed_whatever_the_policy() {
+        let policy = policy(3600, 3600, 0);
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        for i in 0..25u32 {
+            let now = start + secs(i as u64);
+            session.tick(&policy, Measurement::new(&[1, 2], i), now, 1, CHARGING);
+        }
+        assert_eq!(session.stored.len(), 20);
+        assert_eq!(session.buffer.len(), 5);
+    }
+
+    #[test]
+    fn records_are_buffered_with_every_stream() {
+        let policy = FlushPolicy::default();
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        let (short, long) = (
+            Measurement::new(&[7], 1000),
+            Measurement::new(&[1, 2, 3], 1060),
+        );
+        session.tick(&policy, short, start, 60, CHARGING);
+        session.tick(&policy, long, start + secs(60), 60, CHARGING);
+        let values: Vec<&[u16]> = session.stored.iter().map(|m| m.values()).collect();
+        assert_eq!(values, [&[7, 0][..], &[1, 2][..]]);
+    }
+
+    #[test]
+    fn records_flushed_as_they_come_are_not_journaled() {
+        let policy = FlushPolicy::default();
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        for i in 0..5u32 {
+            let now = start + secs(60 * i as u64);
+            let record = Measurement::new(&[1, 2], 60 * i);
+            assert!(session.tick(&policy, record, now, 60, CHARGING));
+        }
+        assert_eq!(session.journal_appends, 0);
+    }
+
+    #[test]
+    fn fast_sessions_journal_each_record_left_buffered_once() {
+        let policy = FlushPolicy::default();
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        for i in 0..3600u32 {
+            let now = start + secs(i as u64);
+            session.tick(&policy, Measurement::new(&[1, 2], i), now, 1, CHARGING);
+            assert_eq!(session.buffer.unjournaled().len(), 0);
+        }
+        // The tenth record of every line fills the buffer and is flushed at once.
+        assert_eq!(session.journal_appends, 3240);
+        assert_eq!(session.stored.len(), 3600);
+    }
+
+    const SESSION: Uuid = Uuid::from_u128(0x3c9e_71a0_4f62_4d15_8b07_e2a4_19c6_5d38);
+
+    /// A session file and journal on the host's file system, written in the formats of
+    /// the device.
+    struct FileSession {
+        buffer: RecordBuffer,
+        now: Instant,
+        path: String,
+        journal_path: String,
+    }
+
+    impl BufferStore for FileSession {
+        type Error = std::io::Error;
+
+        fn buffer(&mut self) -> &mut RecordBuffer {
+            &mut self.buffer
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            let line = encode_measurements(self.buffer.records(), STREAMS);
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.path)?;
+            file.write_all(&line)?;
+            journal::clear_at(&self.journal_path)?;
+            self.buffer.flushed(self.now);
+            Ok(())
+        }
+
+        fn journal(&mut self) {
+            let records = self.buffer.unjournaled();
+            journal::append_at(&self.journal_path, &SESSION, STREAMS, records).unwrap();
+            self.buffer.mark_journaled();
+        }
+    }
+
+    impl FileSession {
+        fn stored(&self) -> Vec<Measurement> {
+            let file = File::open(&self.path).unwrap();
+            ForwardMeasurementIter::new(file, STREAMS, 0)
+                .unwrap()
+                .flat_map(|line| line.measurements)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn records_left_buffered_by_a_reset_are_in_the_journal() {
+        let dir = std::env::temp_dir().join(format!("abm-flush-policy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let start = Instant::now();
+        let mut session = FileSession {
+            buffer: RecordBuffer::new(10, STREAMS),
+            now: start,
+            path: path("session.bin"),
+            journal_path: path("journal.bin"),
+        };
+        let policy = FlushPolicy::default();
+        let records: Vec<Measurement> = (0..25u32)
+            .map(|i| Measurement::new(&[i as u16, 100 - i as u16], 1_700_000_000 + i))
+            .collect();
+        for (i, record) in records.iter().enumerate() {
+            let now = start + secs(i as u64);
+            session.now = now;
+            save(&mut session, *record, now).unwrap();
+            flush_if_due(&mut session, &policy, secs(1), CHARGING, now).unwrap();
+        }
+        // Two full lines flushed, the rest journaled when the device resets
+        let stored = session.stored();
+        assert_eq!(stored, records[..20]);
+        let journaled = journal::read_at(&session.journal_path).unwrap().unwrap();
+        assert_eq!(journaled.session, SESSION);
+        assert_eq!(journaled.records, records[20..]);
+        assert_eq!(journaled.unstored(&stored), records[20..]);
+
+        // A reset after the line was written but before the journal was cleared
+        let line = encode_measurements(&records[20..], STREAMS);
+        let mut file = OpenOptions::new().append(true).open(&session.path).unwrap();
+        file.write_all(&line).unwrap();
+        let journaled = journal::read_at(&session.journal_path).unwrap().unwrap();
+        assert!(journaled.unstored(&session.stored()).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+//! Write-ahead copy of the records `StorageManager` buffers before writing them as a
+//! line, replayed into their session at boot after a reset. RTC memory would spare the
+//! flash writes but does not survive a battery pull.
+//!
+//! Records are journaled only once a tick leaves them buffered, all of them in one
+//! append, and the journal is removed again when they are flushed. Sessions whose
+//! records are flushed as they come write none.
+
+use crate::sensor::measurement::Measurement;
+use crate::storage::line_format::crc16;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use uuid::Uuid;
+
+/// Holds the records of the buffer not yet written as a line.
+const JOURNAL_FILE_PATH: &str = "/storage/journal.bin";
+/// 16B session UUID + u8 stream count + u16 CRC-16, all LE
+const HEADER_SIZE: usize = 19;
+/// Set in the header's stream count byte when entries carry a u8 of flags after their
+/// values; journals left by older firmware do not.
+const FLAGGED_ENTRIES: u8 = 0x80;
+
+/// Records found in the journal at boot.
+pub struct Journal {
+    pub session: Uuid,
+    pub stream_count: usize,
+    /// In the order they were measured.
+    pub records: Vec<Measurement>,
+}
+
+fn entry_size(stream_count: usize, flagged: bool) -> usize {
+    // u32 timestamp + u16 per stream + u8 flags + u16 CRC-16
+    4 + 2 * stream_count + flagged as usize + 2
+}
+
+fn with_crc(mut bytes: Vec<u8>) -> Vec<u8> {
+    let crc = crc16(&bytes);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+fn check_crc(bytes: &[u8]) -> Option<&[u8]> {
+    let (body, crc) = bytes.split_at(bytes.len().checked_sub(2)?);
+    (crc16(body).to_le_bytes() == crc).then_some(body)
+}
+
+impl Journal {
+    /// The records not yet in their session, whose last stored records are
+    /// `stored_tail`, oldest first. A flush writes every journaled record at once, so a
+    /// reset between writing the line and clearing the journal leaves them as the tail
+    /// of the file. Records are compared whole, as timestamps alone repeat when the
+    /// clock is set back.
+    pub fn unstored(mut self, stored_tail: &[Measurement]) -> Vec<Measurement> {
+        let stored = (1..=self.records.len().min(stored_tail.len()))
+            .rev()
+            .find(|&count| self.records[..count] == stored_tail[stored_tail.len() - count..])
+            .unwrap_or(0);
+        self.records.split_off(stored)
+    }
+}
+
+/// Journal bytes for `records`, after the session header unless `header` is false.
+fn encode(session: &Uuid, stream_count: usize, records: &[Measurement], header: bool) -> Vec<u8> {
+    let mut bytes =
+        Vec::with_capacity(HEADER_SIZE + records.len() * entry_size(stream_count, true));
+    if header {
+        let mut header = session.to_bytes_le().to_vec();
+        header.push(stream_count as u8 | FLAGGED_ENTRIES);
+        bytes.extend_from_slice(&with_crc(header));
+    }
+    for record in records {
+        let mut entry = Vec::with_capacity(entry_size(stream_count, true));
+        entry.extend_from_slice(&record.timestamp.to_le_bytes());
+        for value in record.values() {
+            entry.extend_from_slice(&value.to_le_bytes());
+        }
+        entry.push(record.flags);
+        bytes.extend_from_slice(&with_crc(entry));
+    }
+    bytes
+}
+
+/// Append buffered records in one write. The journal is started with the session
+/// header when empty. `records` must already carry `stream_count` values.
+pub fn append(session: &Uuid, stream_count: usize, records: &[Measurement]) -> std::io::Result<()> {
+    append_at(JOURNAL_FILE_PATH, session, stream_count, records)
+}
+
+/// `append` to the journal at `path`.
+pub fn append_at(
+    path: &str,
+    session: &Uuid,
+    stream_count: usize,
+    records: &[Measurement],
+) -> std::io::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    let header = file.metadata()?.len() == 0;
+    // Closing the file commits the append to flash.
+    file.write_all(&encode(session, stream_count, records, header))
+}
+
+/// Forget the journaled records, once flushed or discarded.
+pub fn clear() -> std::io::Result<()> {
+    clear_at(JOURNAL_FILE_PATH)
+}
+
+/// `clear` the journal at `path`.
+pub fn clear_at(path: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The journal left by a reset, if any, see `parse`.
+pub fn read() -> std::io::Result<Option<Journal>> {
+    read_at(JOURNAL_FILE_PATH)
+}
+
+/// `read` the journal at `path`.
+pub fn read_at(path: &str) -> std::io::Result<Option<Journal>> {
+    let mut bytes = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    parse(&bytes)
+}
+
+/// `None` without an intact header. Reading stops at the first damaged entry, which
+/// is the one a power loss interrupted.
+fn parse(bytes: &[u8]) -> std::io::Result<Option<Journal>> {
+    let Some(header) = bytes.get(..HEADER_SIZE).and_then(check_crc) else {
+        return Ok(None);
+    };
+    let session = Uuid::from_slice_le(&header[..16]).map_err(std::io::Error::other)?;
+    let flagged = header[16] & FLAGGED_ENTRIES != 0;
+    let stream_count = (header[16] & !FLAGGED_ENTRIES) as usize;
+    let records = bytes[HEADER_SIZE..]
+        .chunks_exact(entry_size(stream_count, flagged))
+        .map_while(check_crc)
+        .map(|entry| {
+            let timestamp = u32::from_le_bytes(entry[..4].try_into().unwrap());
+            let values: Vec<u16> = entry[4..4 + 2 * stream_count]
+                .chunks_exact(2)
+                .map(|v| u16::from_le_bytes([v[0], v[1]]))
+                .collect();
+            let mut record = Measurement::new(&values, timestamp);
+            if flagged {
+                record.flags = entry[4 + 2 * stream_count];
+            }
+            record
+        })
+        .collect();
+    Ok(Some(Journal {
+        session,
+        stream_count,
+        records,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: Uuid = Uuid::from_u128(0x6a1f_0c2e_55d4_4b8e_9a3c_0f2d_7e41_b9c0);
+
+    fn records() -> Vec<Measurement> {
+        (0..4)
+            .map(|i| {
+                let mut record = Measurement::new(&[10 + i, 20 + i], 1_700_000_000 + i as u32);
+                record.flags = i as u8;
+                record
+            })
+            .collect()
+    }
+
+    /// A journal as `append` leaves it after one append of the first `split` records
+    /// and another of the rest.
+    fn journal(split: usize) -> Vec<u8> {
+        let records = records();
+        let mut bytes = encode(&SESSION, 2, &records[..split], true);
+        bytes.extend_from_slice(&encode(&SESSION, 2, &records[split..], false));
+        bytes
+    }
+
+    #[test]
+    fn reads_back_what_was_appended() {
+        for split in 0..4 {
+            let journal = parse(&journal(split)).unwrap().unwrap();
+            assert_eq!(journal.session, SESSION);
+            assert_eq!(journal.stream_count, 2);
+            assert_eq!(journal.records, records());
+        }
+    }
+
+    #[test]
+    fn no_journal_without_an_intact_header() {
+        assert!(parse(&[]).unwrap().is_none());
+        assert!(parse(&journal(2)[..HEADER_SIZE - 1]).unwrap().is_none());
+        let header_only = encode(&SESSION, 2, &[], true);
+        assert!(parse(&header_only).unwrap().unwrap().records.is_empty());
+    }
+
+    #[test]
+    fn truncated_tail_entry_is_dropped() {
+        let bytes = journal(2);
+        let entry = entry_size(2, true);
+        for cut in 1..entry {
+            let journal = parse(&bytes[..bytes.len() - cut]).unwrap().unwrap();
+            assert_eq!(journal.records, records()[..3]);
+        }
+    }
+
+    #[test]
+    fn reading_stops_at_a_crc_mismatch() {
+        let mut bytes = journal(2);
+        let entry = entry_size(2, true);
+        // Second value of the third entry
+        bytes[HEADER_SIZE + 2 * entry + 6] ^= 0x01;
+        let journal = parse(&bytes).unwrap().unwrap();
+        assert_eq!(journal.records, records()[..2]);
+    }
+
+    #[test]
+    fn damaged_session_is_not_replayed_into_another() {
+        let mut bytes = journal(2);
+        bytes[3] ^= 0x40;
+        assert!(parse(&bytes).unwrap().is_none());
+        let mut bytes = journal(2);
+        bytes[16] = 3 | FLAGGED_ENTRIES;
+        assert!(parse(&bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_journals_from_before_flagged_entries() {
+        let mut bytes = with_crc([SESSION.to_bytes_le().as_slice(), &[2]].concat());
+        for record in records() {
+            let mut entry = record.timestamp.to_le_bytes().to_vec();
+            for value in record.values() {
+                entry.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&with_crc(entry));
+        }
+        let journal = parse(&bytes).unwrap().unwrap();
+        assert_eq!(journal.stream_count, 2);
+        let unflagged: Vec<Measurement> = records()
+            .into_iter()
+            .map(|mut record| {
+                record.flags = 0;
+                record
+            })
+            .collect();
+        assert_eq!(journal.records, unflagged);
+    }
+
+    #[test]
+    fn records_already_stored_are_not_replayed_again() {
+        let journal = || parse(&journal(2)).unwrap().unwrap();
+        let earlier = Measurement::new(&[1, 2], 1_600_000_000);
+        assert_eq!(journal().unstored(&[]), records());
+        assert_eq!(journal().unstored(&[earlier]), records());
+        let stored = [&[earlier][..], &records()].concat();
+        assert!(journal().unstored(&stored).is_empty());
+        assert!(journal().unstored(&records()).is_empty());
+    }
+
+    #[test]
+    fn records_of_a_failed_clear_are_not_replayed_again() {
+        // A journal that could not be cleared after its flush, appended to since.
+        let journal = parse(&journal(2)).unwrap().unwrap();
+        assert_eq!(journal.unstored(&records()[..2]), records()[2..]);
+    }
+
+    #[test]
+    fn records_after_the_clock_was_set_back_are_replayed() {
+        // Stored before the clock was set back by a few seconds, with later timestamps
+        // than every journaled record.
+        let stored: Vec<Measurement> = (0..4)
+            .map(|i| Measurement::new(&[30, 40], 1_700_000_010 + i))
+            .collect();
+        let journal = parse(&journal(2)).unwrap().unwrap();
+        assert_eq!(journal.unstored(&stored), records());
+    }
+}
@@ -0,0 +1,568 @@
+pub const LEGACY_START: [u8; 2] = [0xAB, 0xBA]; // gimmie gimmie gimmie start bytes after midnight
+pub const LEGACY_CRC_START: [u8; 2] = [0xAB, 0xBB];
+pub const COMPACT_START: [u8; 2] = [0xAB, 0xBC];
+pub const COMPACT_CRC_START: [u8; 2] = [0xAB, 0xBD];
+pub const LEGACY_FLAGS_START: [u8; 2] = [0xAB, 0xBE];
+pub const COMPACT_FLAGS_START: [u8; 2] = [0xAB, 0xBF];
+pub const MAX_LINE_RECORDS: usize = 10;
+const LEGACY_HEADER_SIZE: usize = 3; // start bytes + count: u8
+const COMPACT_HEADER_SIZE: usize = 4; // start bytes + count: u8 + payload length: u8
+const MAX_COMPACT_PAYLOAD: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    Legacy,
+    Compact,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    /// u8 XOR of all bytes; only read, lines written by older firmware
+    Xor,
+    /// u16 LE CRC-16/CCITT-FALSE of all bytes
+    Crc16,
+}
+
+impl Check {
+    fn size(self) -> usize {
+        match self {
+            Self::Xor => 1,
+            Self::Crc16 => 2,
+        }
+    }
+
+    fn matches(self, body: &[u8], check: &[u8]) -> bool {
+        match self {
+            Self::Xor => check == [xor(body)],
+            Self::Crc16 => check == crc16(body).to_le_bytes(),
+        }
+    }
+}
+
+/// Layout, check, and whether a flag byte per record follows the records.
+fn framing(start: &[u8], xor_lines: bool) -> Option<(Layout, Check, bool)> {
+    match start {
+        s if s == LEGACY_START && xor_lines => Some((Layout::Legacy, Check::Xor, false)),
+        s if s == LEGACY_CRC_START => Some((Layout::Legacy, Check::Crc16, false)),
+        s if s == COMPACT_START && xor_lines => Some((Layout::Compact, Check::Xor, false)),
+        s if s == COMPACT_CRC_START => Some((Layout::Compact, Check::Crc16, false)),
+        s if s == LEGACY_FLAGS_START => Some((Layout::Legacy, Check::Crc16, true)),
+        s if s == COMPACT_FLAGS_START => Some((Layout::Compact, Check::Crc16, true)),
+        _ => None,
+    }
+}
+
+/// u32 timestamp + u16 per stream
+pub fn record_size(stream_count: usize) -> usize {
+    4 + 2 * stream_count
+}
+
+/// Smallest possible line of any format.
+pub fn min_line_size(stream_count: usize) -> usize {
+    let legacy = LEGACY_HEADER_SIZE + record_size(stream_count) + 1;
+    let compact = COMPACT_HEADER_SIZE + 4 + stream_count + 1;
+    legacy.min(compact)
+}
+
+/// Largest possible line of any format.
+pub fn max_line_size(stream_count: usize) -> usize {
+    let legacy = LEGACY_HEADER_SIZE + MAX_LINE_RECORDS * (record_size(stream_count) + 1) + 2;
+    let compact = COMPACT_HEADER_SIZE + MAX_COMPACT_PAYLOAD + MAX_LINE_RECORDS + 2;
+    legacy.max(compact)
+}
+
+/// Records of one line, `stream_count` values per timestamp.
+#[derive(Debug, Default)]
+pub struct LineRecords {
+    pub timestamps: Vec<u32>,
+    pub values: Vec<u16>,
+    /// One per timestamp, 0 for lines written without flags.
+    pub flags: Vec<u8>,
+}
+
+impl LineRecords {
+    pub fn iter(&self, stream_count: usize) -> impl Iterator<Item = (u32, &[u16], u8)> {
+        self.timestamps
+            .iter()
+            .copied()
+            .zip(self.values.chunks_exact(stream_count.max(1)))
+            .zip(self.flags.iter().copied())
+            .map(|((timestamp, values), flags)| (timestamp, values, flags))
+    }
+}
+
+/// Encode up to `MAX_LINE_RECORDS` records ("line"), each with `stream_count` values.
+/// Two layouts live side by side in a file and are told apart by their start bytes:
+///
+/// - legacy: `0xAB 0xBB` + u8 count + count * (u32 timestamp + u16 per stream) + check
+/// - compact: `0xAB 0xBD` + u8 count + u8 payload length + payload + check. The payload
+///   is the first record as u32 timestamp + varint per stream, then every further record
+///   as a varint timestamp delta + zig-zag varint delta per stream.
+///
+/// If any record has quality flags (`Measurement::flags`), the line starts with
+/// `0xAB 0xBE` (legacy) or `0xAB 0xBF` (compact) instead and one u8 of flags per record
+/// follows the records, outside the compact payload length. Lines without flags keep
+/// the unflagged start bytes so older readers still take them.
+///
+/// The check is a u16 LE CRC-16/CCITT-FALSE. Lines from older firmware carry a u8 XOR
+/// instead and start with `0xAB 0xBA` (legacy) or `0xAB 0xBC` (compact); they are still
+/// read but no longer written.
+///
+/// The compact layout is used unless timestamps go backwards or the payload does not
+/// fit its length byte. Every line carries an exact length and a check, so a backward
+/// scan can find line boundaries without an index.
+///
+/// The tests below measure, over 200 000 random 2-stream lines each, the share of
+/// corrupted lines still accepted with XOR / CRC-16:
+/// - one flipped bit: 0 / 0
+/// - two flipped bits: 7.2 % / 0
+/// - two bytes swapped: 51 % / 0
+/// - one random byte: 0 / 0
+/// - 4-byte random burst: 0.086 % / under 0.005 %
+///
+/// In random data a resync locks onto a false line about 2.3e-9 times per byte while
+/// XOR lines are accepted, against 1.8e-11 with CRC-16 lines only. Files written with
+/// CRC-16 lines from the start therefore reject XOR ones (`xor_lines` false).
+///
+/// `flags` holds one byte per record, or is empty for none.
+pub fn encode_line(records: &[(u32, &[u16])], flags: &[u8], stream_count: usize) -> Vec<u8> {
+    let mut line = encode_compact(records, stream_count)
+        .unwrap_or_else(|| encode_legacy(records, stream_count));
+    if flags.iter().any(|&f| f != 0) {
+        let start = if line[..2] == LEGACY_CRC_START {
+            LEGACY_FLAGS_START
+        } else {
+            COMPACT_FLAGS_START
+        };
+        line[..2].copy_from_slice(&start);
+        line.extend_from_slice(&flags[..records.len()]);
+    }
+    line.extend_from_slice(&crc16(&line).to_le_bytes());
+    line
+}
+
+fn encode_legacy(records: &[(u32, &[u16])], stream_count: usize) -> Vec<u8> {
+    let mut line =
+        Vec::with_capacity(LEGACY_HEADER_SIZE + records.len() * record_size(stream_count) + 2);
+    line.extend_from_slice(&LEGACY_CRC_START);
+    line.push(records.len() as u8);
+    for (timestamp, values) in records {
+        line.extend_from_slice(&timestamp.to_le_bytes());
+        for value in &values[..stream_count] {
+            line.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    line
+}
+
+fn encode_compact(records: &[(u32, &[u16])], stream_count: usize) -> Option<Vec<u8>> {
+    let ((first_ts, first_values), rest) = records.split_first()?;
+    let mut line = Vec::with_capacity(COMPACT_HEADER_SIZE + MAX_COMPACT_PAYLOAD + 2);
+    line.extend_from_slice(&COMPACT_CRC_START);
+    line.push(records.len() as u8);
+    line.push(0); // payload length, patched below
+    line.extend_from_slice(&first_ts.to_le_bytes());
+    for &value in &first_values[..stream_count] {
+        write_varint(&mut line, value as u32);
+    }
+    let mut prev_ts = *first_ts;
+    let mut prev_values = *first_values;
+    for &(timestamp, values) in rest {
+        write_varint(&mut line, timestamp.checked_sub(prev_ts)?);
+        for (&value, &prev) in values[..stream_count].iter().zip(prev_values) {
+            write_varint(&mut line, zigzag(value as i32 - prev as i32));
+        }
+        prev_ts = timestamp;
+        prev_values = values;
+    }
+    let payload_len = line.len() - COMPACT_HEADER_SIZE;
+    if payload_len > MAX_COMPACT_PAYLOAD {
+        return None;
+    }
+    line[3] = payload_len as u8;
+    Some(line)
+}
+
+/// Total length of the line starting with `prefix` (at least its first 4 bytes),
+/// as announced by its header. Used to read a line forward. `xor_lines` accepts the
+/// XOR-checked lines of older firmware.
+pub fn line_len(prefix: &[u8], stream_count: usize, xor_lines: bool) -> Option<usize> {
+    let (layout, check, flagged) = framing(prefix.get(..2)?, xor_lines)?;
+    let count = *prefix.get(2)? as usize;
+    let body = match layout {
+        Layout::Legacy => LEGACY_HEADER_SIZE + count * record_size(stream_count),
+        Layout::Compact => COMPACT_HEADER_SIZE + *prefix.get(3)? as usize,
+    };
+    let flags = if flagged { count } else { 0 };
+    Some(body + flags + check.size())
+}
+
+/// Decode a line occupying exactly `line`. `None` unless the framing, length, and
+/// check all agree, which is what lets a scanner resync on arbitrary data.
+pub fn decode_line(line: &[u8], stream_count: usize, xor_lines: bool) -> Option<LineRecords> {
+    if line.len() < min_line_size(stream_count)
+        || line_len(line, stream_count, xor_lines)? != line.len()
+    {
+        return None;
+    }
+    let (layout, check, flagged) = framing(&line[..2], xor_lines)?;
+    let count = line[2] as usize;
+    if count == 0 || count > MAX_LINE_RECORDS {
+        return None;
+    }
+    let (body, check_bytes) = line.split_at(line.len() - check.size());
+    if !check.matches(body, check_bytes) {
+        return None;
+    }
+    let (body, flags) = body.split_at(body.len() - if flagged { count } else { 0 });
+    let mut records = match layout {
+        Layout::Legacy => decode_legacy(&body[LEGACY_HEADER_SIZE..], count, stream_count),
+        Layout::Compact => decode_compact(&body[COMPACT_HEADER_SIZE..], count, stream_count)?,
+    };
+    records.flags = if flagged {
+        flags.to_vec()
+    } else {
+        vec![0; count]
+    };
+    Some(records)
+}
+
+/// The first valid line in `data` as `(offset, length, records)`, stepping forward
+/// byte by byte over anything that does not decode.
+pub fn next_line(
+    data: &[u8],
+    stream_count: usize,
+    xor_lines: bool,
+) -> Option<(usize, usize, LineRecords)> {
+    (0..data.len()).find_map(|offset| {
+        let len = line_len(&data[offset..], stream_count, xor_lines)?;
+        let records = decode_line(data.get(offset..offset + len)?, stream_count, xor_lines)?;
+        Some((offset, len, records))
+    })
+}
+
+fn decode_legacy(data: &[u8], count: usize, stream_count: usize) -> LineRecords {
+    let mut records = LineRecords {
+        timestamps: Vec::with_capacity(count),
+        values: Vec::with_capacity(count * stream_count),
+        flags: Vec::new(),
+    };
+    for record in data.chunks_exact(record_size(stream_count)) {
+        records
+            .timestamps
+            .push(u32::from_le_bytes(record[..4].try_into().unwrap()));
+        for bytes in record[4..].chunks_exact(2) {
+            records
+                .values
+                .push(u16::from_le_bytes(bytes.try_into().unwrap()));
+        }
+    }
+    records
+}
+
+fn decode_compact(payload: &[u8], count: usize, stream_count: usize) -> Option<LineRecords> {
+    let mut records = LineRecords {
+        timestamps: Vec::with_capacity(count),
+        values: Vec::with_capacity(count * stream_count),
+        flags: Vec::new(),
+    };
+    let mut pos = 4;
+    let mut timestamp = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+    records.timestamps.push(timestamp);
+    for _ in 0..stream_count {
+        let value = read_varint(payload, &mut pos)?;
+        records.values.push(u16::try_from(value).ok()?);
+    }
+    for i in 1..count {
+        timestamp = timestamp.checked_add(read_varint(payload, &mut pos)?)?;
+        records.timestamps.push(timestamp);
+        for s in 0..stream_count {
+            let prev = records.values[(i - 1) * stream_count + s] as i32;
+            let value = prev + unzigzag(read_varint(payload, &mut pos)?);
+            records.values.push(u16::try_from(value).ok()?);
+        }
+    }
+    // Every payload byte must be accounted for, or this is not a real line.
+    (pos == payload.len()).then_some(records)
+}
+
+fn xor(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, &b| acc ^ b)
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, no reflection.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &b| {
+        (0..8).fold(crc ^ (b as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Unsigned LEB128.
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut value: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAMS: usize = 2;
+    const TRIALS: usize = 200_000;
+
+    /// xorshift64*, so the rates are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn byte(&mut self) -> u8 {
+            self.next() as u8
+        }
+    }
+
+    /// A line as a 2-stream session writes it: 1 to 10 records up to a minute apart,
+    /// with a quarter of them flagged. XOR lines are those lines as older firmware
+    /// wrote them, so never flagged.
+    fn random_line(rng: &mut Rng, check: Check) -> Vec<u8> {
+        let count = 1 + rng.below(MAX_LINE_RECORDS);
+        let mut timestamp = 1_600_000_000 + rng.below(300_000_000) as u32;
+        let mut values = [rng.below(1000) as u16, rng.below(1000) as u16];
+        let mut records = Vec::with_capacity(count);
+        for _ in 0..count {
+            timestamp += 1 + rng.below(60) as u32;
+            for value in &mut values {
+                *value = (*value as usize + rng.below(41)).saturating_sub(20) as u16;
+            }
+            records.push((timestamp, values));
+        }
+        let records: Vec<(u32, &[u16])> = records.iter().map(|(t, v)| (*t, &v[..])).collect();
+        let flags: Vec<u8> = match check {
+            Check::Crc16 if rng.below(4) == 0 => (0..count).map(|_| rng.byte() & 0x0F).collect(),
+            _ => Vec::new(),
+        };
+        let mut line = encode_line(&records, &flags, STREAMS);
+        if check == Check::Xor {
+            line.truncate(line.len() - 2);
+            line[1] = if line[1] == LEGACY_CRC_START[1] {
+                LEGACY_START[1]
+            } else {
+                COMPACT_START[1]
+            };
+            line.push(xor(&line));
+        }
+        line
+    }
+
+    /// Share of `TRIALS` random lines with `check` still accepted after `corrupt`,
+    /// which returns false for a trial that left the line unchanged.
+    fn false_match_rate(check: Check, corrupt: impl Fn(&mut Rng, &mut [u8]) -> bool) -> f64 {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut accepted = 0;
+        let mut trials = 0;
+        while trials < TRIALS {
+            let mut line = random_line(&mut rng, check);
+            assert!(decode_line(&line, STREAMS, true).is_some());
+            if !corrupt(&mut rng, &mut line) {
+                continue;
+            }
+            trials += 1;
+            if decode_line(&line, STREAMS, true).is_some() {
+                accepted += 1;
+            }
+        }
+        accepted as f64 / trials as f64
+    }
+
+    fn flip_bit(rng: &mut Rng, line: &mut [u8]) {
+        let bit = rng.below(line.len() * 8);
+        line[bit / 8] ^= 1 << (bit % 8);
+    }
+
+    fn single_bit(rng: &mut Rng, line: &mut [u8]) -> bool {
+        flip_bit(rng, line);
+        true
+    }
+
+    fn two_bits(rng: &mut Rng, line: &mut [u8]) -> bool {
+        let original = line.to_vec();
+        flip_bit(rng, line);
+        flip_bit(rng, line);
+        *line != original[..]
+    }
+
+    fn byte_swap(rng: &mut Rng, line: &mut [u8]) -> bool {
+        let (a, b) = (rng.below(line.len()), rng.below(line.len()));
+        line.swap(a, b);
+        line[a] != line[b]
+    }
+
+    fn random_byte(rng: &mut Rng, line: &mut [u8]) -> bool {
+        let at = rng.below(line.len());
+        let byte = rng.byte();
+        let changed = line[at] != byte;
+        line[at] = byte;
+        changed
+    }
+
+    fn burst(rng: &mut Rng, line: &mut [u8]) -> bool {
+        let original = line.to_vec();
+        let start = rng.below(line.len() - 3);
+        for byte in &mut line[start..start + 4] {
+            *byte = rng.byte();
+        }
+        *line != original[..]
+    }
+
+    #[test]
+    fn single_bit_flips_are_always_caught() {
+        assert_eq!(false_match_rate(Check::Xor, single_bit), 0.0);
+        assert_eq!(false_match_rate(Check::Crc16, single_bit), 0.0);
+    }
+
+    #[test]
+    fn two_bit_flips() {
+        let xor = false_match_rate(Check::Xor, two_bits);
+        assert!((0.06..0.085).contains(&xor), "XOR {}", xor);
+        assert_eq!(false_match_rate(Check::Crc16, two_bits), 0.0);
+    }
+
+    #[test]
+    fn byte_swaps() {
+        let xor = false_match_rate(Check::Xor, byte_swap);
+        assert!((0.46..0.56).contains(&xor), "XOR {}", xor);
+        assert_eq!(false_match_rate(Check::Crc16, byte_swap), 0.0);
+    }
+
+    #[test]
+    fn single_random_bytes_are_always_caught() {
+        assert_eq!(false_match_rate(Check::Xor, random_byte), 0.0);
+        assert_eq!(false_match_rate(Check::Crc16, random_byte), 0.0);
+    }
+
+    #[test]
+    fn four_byte_bursts() {
+        let xor = false_match_rate(Check::Xor, burst);
+        let crc = false_match_rate(Check::Crc16, burst);
+        assert!((0.0006..0.0012).contains(&xor), "XOR {}", xor);
+        assert!(crc < 5e-5, "CRC-16 {}", crc);
+    }
+
+    /// Share of random bytes after `start` that pass framing, length and payload
+    /// decoding, given a matching check. Only the check stands between the rest and a
+    /// false line.
+    fn structural_rate(start: [u8; 2]) -> f64 {
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        let mut data = vec![0u8; max_line_size(STREAMS)];
+        let mut passed = 0;
+        for _ in 0..TRIALS {
+            for chunk in data.chunks_mut(8) {
+                chunk.copy_from_slice(&rng.next().to_le_bytes()[..chunk.len()]);
+            }
+            data[..2].copy_from_slice(&start);
+            // Longer than any line means more than `MAX_LINE_RECORDS` records.
+            let Some(len) = line_len(&data, STREAMS, true).filter(|&len| len <= data.len()) else {
+                continue;
+            };
+            let (_, check, _) = framing(&start, true).unwrap();
+            let (body, check_bytes) = data[..len].split_at_mut(len - check.size());
+            match check {
+                Check::Xor => check_bytes[0] = xor(body),
+                Check::Crc16 => check_bytes.copy_from_slice(&crc16(body).to_le_bytes()),
+            }
+            if decode_line(&data[..len], STREAMS, true).is_some() {
+                passed += 1;
+            }
+        }
+        passed as f64 / TRIALS as f64
+    }
+
+    /// Expected false lines per byte of random data a resync steps over: a start pair
+    /// turns up once in 65536 bytes, and a random check matches once in 256 (XOR) or
+    /// 65536 (CRC-16).
+    fn resync_rate(xor_lines: bool) -> f64 {
+        let starts = [
+            (LEGACY_START, true),
+            (COMPACT_START, true),
+            (LEGACY_CRC_START, false),
+            (COMPACT_CRC_START, false),
+            (LEGACY_FLAGS_START, false),
+            (COMPACT_FLAGS_START, false),
+        ];
+        starts
+            .into_iter()
+            .filter(|&(_, xor)| xor_lines || !xor)
+            .map(|(start, xor)| {
+                let check = if xor { 256.0 } else { 65536.0 };
+                structural_rate(start) / check / 65536.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn resync_over_random_bytes() {
+        let with_xor = resync_rate(true);
+        let crc_only = resync_rate(false);
+        assert!(
+            (1.8e-9..2.9e-9).contains(&with_xor),
+            "with XOR {}",
+            with_xor
+        );
+        assert!(
+            (1.4e-11..2.3e-11).contains(&crc_only),
+            "CRC-16 only {}",
+            crc_only
+        );
+    }
+
+    #[test]
+    fn resync_skips_random_bytes_to_the_next_line() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let mut data: Vec<u8> = (0..1 << 20).map(|_| rng.byte()).collect();
+        assert!(next_line(&data, STREAMS, false).is_none());
+        let line = random_line(&mut rng, Check::Crc16);
+        let at = data.len() - 1000;
+        data[at..at + line.len()].copy_from_slice(&line);
+        let (offset, len, _) = next_line(&data, STREAMS, false).unwrap();
+        assert_eq!((offset, len), (at, line.len()));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::storage::capacity::PARTITION_LABEL;
+use crate::storage::storage_controller::{open_unsynced, LEGACY_FILE_PATH, MOUNT_POINT};
+use esp_idf_svc::fs::littlefs::Littlefs;
+use esp_idf_svc::io::vfs::MountedLittlefs;
+use esp_idf_svc::sys::{
+    esp, esp_vfs_littlefs_conf_t, esp_vfs_littlefs_register, esp_vfs_littlefs_unregister, EspError,
+};
+use log::{error, info, warn};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The storage partition while mounted; unmounted when dropped.
+pub type MountedStorage = MountedLittlefs<Littlefs<()>>;
+
+/// Mount attempts before the partition is taken to be damaged.
+const MOUNT_ATTEMPTS: u8 = 3;
+const MOUNT_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Most bytes salvaged into RAM from a partition that only mounts read-only.
+pub const SALVAGE_MAX_BYTES: usize = 32 * 1024;
+/// u8 state + i32 esp_err_t + u8 failed attempts + u32 salvaged bytes
+pub const MOUNT_INCIDENT_SIZE: usize = 10;
+
+/// Salvaged at boot, kept until the next one.
+static SALVAGED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// What happens once the storage partition cannot be mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountRecovery {
+    /// Run without storage until the app confirms a reformat over BLE.
+    #[default]
+    AwaitConfirmation = 0,
+    /// Reformat right after salvaging, as firmware before this did without salvaging.
+    Reformat = 1,
+}
+
+impl MountRecovery {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::AwaitConfirmation),
+            1 => Some(Self::Reformat),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IncidentState {
+    /// A retry mounted the partition; nothing was lost.
+    Recovered = 0,
+    /// Not mounted, waiting for the app to confirm a reformat.
+    AwaitingReformat = 1,
+    /// Reformatted; what was not salvaged is lost.
+    Reformatted = 2,
+}
+
+impl IncidentState {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Recovered),
+            1 => Some(Self::AwaitingReformat),
+            2 => Some(Self::Reformatted),
+            _ => None,
+        }
+    }
+}
+
+/// A failed mount of the storage partition, kept in NVS for the app to tell the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MountIncident {
+    pub state: IncidentState,
+    /// esp_err_t of the last failed attempt.
+    pub error: i32,
+    pub failed_attempts: u8,
+    /// Bytes salvaged into RAM, see `with_salvaged`.
+    pub salvaged: u32,
+}
+
+impl MountIncident {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < MOUNT_INCIDENT_SIZE {
+            return None;
+        }
+        Some(Self {
+            state: IncidentState::from_u8(data[0])?,
+            error: i32::from_le_bytes(data[1..5].try_into().ok()?),
+            failed_attempts: data[5],
+            salvaged: u32::from_le_bytes(data[6..10].try_into().ok()?),
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.state as u8;
+        buf[1..5].copy_from_slice(&self.error.to_le_bytes());
+        buf[5] = self.failed_attempts;
+        buf[6..10].copy_from_slice(&self.salvaged.to_le_bytes());
+    }
+}
+
+/// Mount the storage partition, retrying a few times. Should that not do, salvage
+/// what can be read of `session` (see `salvage`), then reformat if `policy` says so.
+/// Returns the mounted partition, `None` while a reformat waits for the app, and the
+/// incident if the first attempt failed.
+pub fn mount(
+    policy: MountRecovery,
+    session: Option<Uuid>,
+) -> (Option<MountedStorage>, Option<MountIncident>) {
+    let mut error = 0;
+    for attempt in 1..=MOUNT_ATTEMPTS {
+        match mount_once() {
+            Ok(mounted) => {
+                let incident = (attempt > 1).then_some(MountIncident {
+                    state: IncidentState::Recovered,
+                    error,
+                    failed_attempts: attempt - 1,
+                    salvaged: 0,
+                });
+                return (Some(mounted), incident);
+            }
+            Err(e) => {
+                warn!("Failed to mount storage, attempt {}: {:?}", attempt, e);
+                error = e.code();
+                if attempt < MOUNT_ATTEMPTS {
+                    std::thread::sleep(MOUNT_RETRY_DELAY);
+                }
+            }
+        }
+    }
+    let salvaged = salvage(session).unwrap_or_else(|e| {
+        error!("Failed to salvage storage: {:?}", e);
+        0
+    });
+    let mut incident = MountIncident {
+        state: IncidentState::AwaitingReformat,
+        error,
+        failed_attempts: MOUNT_ATTEMPTS,
+        salvaged: salvaged as u32,
+    };
+    if policy == MountRecovery::Reformat {
+        match reformat() {
+            Ok(mounted) => {
+                incident.state = IncidentState::Reformatted;
+                return (Some(mounted), Some(incident));
+            }
+            Err(e) => error!("Failed to reformat storage: {:?}", e),
+        }
+    }
+    (None, Some(incident))
+}
+
+fn mount_once() -> Result<MountedStorage, EspError> {
+    let lfs = unsafe { Littlefs::<()>::new_partition("storage") }?;
+    MountedLittlefs::mount(lfs, MOUNT_POINT)
+}
+
+/// Format the storage partition and mount it, losing everything on it.
+pub fn reformat() -> Result<MountedStorage, EspError> {
+    warn!("Formatting storage");
+    let mut lfs = unsafe { Littlefs::<()>::new_partition("storage") }?;
+    lfs.format()?;
+    MountedLittlefs::mount(lfs, MOUNT_POINT)
+}
+
+/// Mount the partition read-only and copy up to `SALVAGE_MAX_BYTES` of `session` as
+/// `/sync` sends it, or of `psm.bin` without a session, into RAM. A session cut short
+/// ends in a partial line, which readers skip as corrupt. Returns the bytes salvaged.
+fn salvage(session: Option<Uuid>) -> anyhow::Result<usize> {
+    let base_path = CString::new(MOUNT_POINT)?;
+    let mut conf = esp_vfs_littlefs_conf_t {
+        base_path: base_path.as_ptr(),
+        partition_label: PARTITION_LABEL.as_ptr(),
+        ..Default::default()
+    };
+    conf.set_read_only(1);
+    esp!(unsafe { esp_vfs_littlefs_register(&conf) })?;
+    let read = || -> std::io::Result<Vec<u8>> {
+        let reader: Box<dyn Read> = match session {
+            Some(session) => Box::new(open_unsynced(&session)?.0),
+            None => Box::new(File::open(LEGACY_FILE_PATH)?),
+        };
+        let mut bytes = Vec::new();
+        reader
+            .take(SALVAGE_MAX_BYTES as u64)
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    };
+    let bytes = read();
+    if let Err(e) = esp!(unsafe { esp_vfs_littlefs_unregister(PARTITION_LABEL.as_ptr()) }) {
+        warn!("Failed to unmount read-only storage: {:?}", e);
+    }
+    let bytes = bytes?;
+    info!("Salvaged {} bytes of storage", bytes.len());
+    let len = bytes.len();
+    *SALVAGED.lock().unwrap() = bytes;
+    Ok(len)
+}
+
+/// Run `f` on the bytes salvaged at boot, empty if there are none.
+pub fn with_salvaged<T>(f: impl FnOnce(&[u8]) -> T) -> T {
+    f(&SALVAGED.lock().unwrap())
+}
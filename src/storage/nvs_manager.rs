@@ -1,3 +1,12 @@
+use crate::alarm::{AlarmConfig, ALARM_CONFIG_SIZE, MAX_ALARMS};
+use crate::led::air_quality::AirQualityLed;
+use crate::sensor::measurement::MAX_STREAMS;
+use crate::sensor::sensor_usage::{SensorUsage, DEFAULT_SENSOR_LIFETIME_HOURS, SENSOR_USAGE_SIZE};
+use crate::storage::aggregation::{AggregationPolicy, AGGREGATION_POLICY_SIZE};
+use crate::storage::capacity::StoragePolicy;
+use crate::storage::device_settings::DeviceSettings;
+use crate::storage::flush_policy::{FlushPolicy, FLUSH_POLICY_SIZE};
+use crate::storage::mount_recovery::{MountIncident, MountRecovery, MOUNT_INCIDENT_SIZE};
 use crate::storage::session_config::{SessionConfig, SessionType};
 use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition, EspNvs};
 use esp_idf_svc::sys::EspError;
@@ -12,9 +21,20 @@ const KEY_IS_MOBILE: &str = "is_mobile";
 const KEY_MEASUREMENT_INTERVAL: &str = "interval";
 const KEY_PM1_INDEX: &str = "pm1_index";
 const KEY_PM2_5_INDEX: &str = "pm2_5_index";
+const KEY_STREAM_INDICES: &str = "stream_idx";
 const KEY_TOKEN: &str = "token";
 const KEY_DOMAIN: &str = "domain";
 const DEFAULT_DOMAIN: &str = "aircasting.org";
+const KEY_AQ_LED: &str = "aq_led";
+const KEY_AQ_THRESHOLDS: &str = "aq_thresholds";
+const KEY_ALARMS: &str = "alarms";
+const KEY_SENSOR_USAGE: &str = "sensor_usage";
+const KEY_SENSOR_LIFETIME: &str = "sensor_life";
+const KEY_STORAGE_POLICY: &str = "storage_policy";
+const KEY_FLUSH_POLICY: &str = "flush_policy";
+const KEY_AGGREGATION_POLICY: &str = "aggregation";
+const KEY_MOUNT_RECOVERY: &str = "mount_recovery";
+const KEY_MOUNT_INCIDENT: &str = "mount_incident";
 
 /// Manages persistent session data stored in the ESP32's NVS flash.
 pub struct NvsManager {
@@ -36,6 +56,7 @@ impl NvsManager {
         let _ = self.nvs.remove(KEY_MEASUREMENT_INTERVAL);
         let _ = self.nvs.remove(KEY_PM1_INDEX);
         let _ = self.nvs.remove(KEY_PM2_5_INDEX);
+        let _ = self.nvs.remove(KEY_STREAM_INDICES);
     }
 
     pub fn get_domain(&self) -> Result<String, EspError> {
@@ -107,20 +128,22 @@ impl NvsManager {
             .set_u32(KEY_MEASUREMENT_INTERVAL, interval.as_secs() as u32)
     }
 
-    pub fn get_pm1_index(&self) -> Result<Option<u8>, EspError> {
-        self.nvs.get_u8(KEY_PM1_INDEX)
-    }
-
-    pub fn set_pm1_index(&mut self, pm_index: u8) -> Result<(), EspError> {
-        self.nvs.set_u8(KEY_PM1_INDEX, pm_index)
-    }
-
-    pub fn get_pm2_5_index(&self) -> Result<Option<u8>, EspError> {
-        self.nvs.get_u8(KEY_PM2_5_INDEX)
+    /// Falls back to the PM1/PM2.5 keys written by firmware before the sensor registry.
+    pub fn get_stream_indices(&self) -> Result<Option<Vec<u8>>, EspError> {
+        // One more for the quality flags
+        let mut buffer = [0u8; MAX_STREAMS + 1];
+        if let Some(indices) = self.nvs.get_blob(KEY_STREAM_INDICES, &mut buffer)? {
+            return Ok(Some(indices.to_vec()));
+        }
+        Ok(self
+            .nvs
+            .get_u8(KEY_PM1_INDEX)?
+            .zip(self.nvs.get_u8(KEY_PM2_5_INDEX)?)
+            .map(|(pm1, pm2_5)| vec![pm1, pm2_5]))
     }
 
-    pub fn set_pm2_5_index(&mut self, pm_index: u8) -> Result<(), EspError> {
-        self.nvs.set_u8(KEY_PM2_5_INDEX, pm_index)
+    pub fn set_stream_indices(&mut self, indices: &[u8]) -> Result<(), EspError> {
+        self.nvs.set_blob(KEY_STREAM_INDICES, indices)
     }
 
     pub fn get_token(&self) -> Result<Option<u128>, EspError> {
@@ -138,6 +161,168 @@ impl NvsManager {
         self.nvs.set_blob(KEY_TOKEN, &token.to_le_bytes())
     }
 
+    /// Device settings are kept across sessions (not touched by `clear_session_config`).
+    pub fn get_device_settings(&self) -> Result<DeviceSettings, EspError> {
+        Ok(DeviceSettings {
+            air_quality_led: self.get_air_quality_led()?,
+            alarms: self.get_alarms()?,
+            sensor_lifetime_hours: self.get_sensor_lifetime_hours()?,
+            storage_policy: self.get_storage_policy()?,
+            flush_policy: self.get_flush_policy()?,
+            aggregation_policy: self.get_aggregation_policy()?,
+            mount_recovery: self.get_mount_recovery()?,
+        })
+    }
+
+    pub fn get_air_quality_led(&self) -> Result<AirQualityLed, EspError> {
+        let enabled = self.nvs.get_u8(KEY_AQ_LED)?.is_some_and(|v| v != 0);
+        let mut thresholds = AirQualityLed::default().thresholds;
+        let mut buffer = [0u8; 8];
+        if let Some(bytes) = self.nvs.get_blob(KEY_AQ_THRESHOLDS, &mut buffer)? {
+            for (threshold, b) in thresholds.iter_mut().zip(bytes.chunks_exact(2)) {
+                *threshold = u16::from_le_bytes([b[0], b[1]]);
+            }
+        }
+        Ok(AirQualityLed {
+            enabled,
+            thresholds,
+        })
+    }
+
+    pub fn set_air_quality_led(&mut self, config: &AirQualityLed) -> Result<(), EspError> {
+        let mut buffer = [0u8; 8];
+        for (b, threshold) in buffer.chunks_exact_mut(2).zip(config.thresholds) {
+            b.copy_from_slice(&threshold.to_le_bytes());
+        }
+        self.nvs.set_blob(KEY_AQ_THRESHOLDS, &buffer)?;
+        self.nvs.set_u8(KEY_AQ_LED, config.enabled as u8)
+    }
+
+    pub fn get_alarms(&self) -> Result<Vec<AlarmConfig>, EspError> {
+        let mut buffer = [0u8; MAX_ALARMS * ALARM_CONFIG_SIZE];
+        Ok(self
+            .nvs
+            .get_blob(KEY_ALARMS, &mut buffer)?
+            .map(|bytes| {
+                bytes
+                    .chunks_exact(ALARM_CONFIG_SIZE)
+                    .filter_map(AlarmConfig::decode)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    pub fn set_alarms(&mut self, alarms: &[AlarmConfig]) -> Result<(), EspError> {
+        let mut buffer = [0u8; MAX_ALARMS * ALARM_CONFIG_SIZE];
+        let alarms = &alarms[..alarms.len().min(MAX_ALARMS)];
+        for (chunk, alarm) in buffer.chunks_exact_mut(ALARM_CONFIG_SIZE).zip(alarms) {
+            alarm.encode(chunk);
+        }
+        self.nvs
+            .set_blob(KEY_ALARMS, &buffer[..alarms.len() * ALARM_CONFIG_SIZE])
+    }
+
+    pub fn get_sensor_lifetime_hours(&self) -> Result<u32, EspError> {
+        Ok(self
+            .nvs
+            .get_u32(KEY_SENSOR_LIFETIME)?
+            .unwrap_or(DEFAULT_SENSOR_LIFETIME_HOURS))
+    }
+
+    pub fn set_sensor_lifetime_hours(&mut self, hours: u32) -> Result<(), EspError> {
+        self.nvs.set_u32(KEY_SENSOR_LIFETIME, hours)
+    }
+
+    pub fn get_storage_policy(&self) -> Result<StoragePolicy, EspError> {
+        Ok(self
+            .nvs
+            .get_u8(KEY_STORAGE_POLICY)?
+            .and_then(StoragePolicy::from_u8)
+            .unwrap_or_default())
+    }
+
+    pub fn set_storage_policy(&mut self, policy: StoragePolicy) -> Result<(), EspError> {
+        self.nvs.set_u8(KEY_STORAGE_POLICY, policy as u8)
+    }
+
+    pub fn get_flush_policy(&self) -> Result<FlushPolicy, EspError> {
+        let mut buffer = [0u8; FLUSH_POLICY_SIZE];
+        Ok(self
+            .nvs
+            .get_blob(KEY_FLUSH_POLICY, &mut buffer)?
+            .and_then(FlushPolicy::decode)
+            .unwrap_or_default())
+    }
+
+    pub fn set_flush_policy(&mut self, policy: &FlushPolicy) -> Result<(), EspError> {
+        let mut buffer = [0u8; FLUSH_POLICY_SIZE];
+        policy.encode(&mut buffer);
+        self.nvs.set_blob(KEY_FLUSH_POLICY, &buffer)
+    }
+
+    pub fn get_aggregation_policy(&self) -> Result<AggregationPolicy, EspError> {
+        let mut buffer = [0u8; AGGREGATION_POLICY_SIZE];
+        Ok(self
+            .nvs
+            .get_blob(KEY_AGGREGATION_POLICY, &mut buffer)?
+            .and_then(AggregationPolicy::decode)
+            .unwrap_or_default())
+    }
+
+    pub fn set_aggregation_policy(&mut self, policy: &AggregationPolicy) -> Result<(), EspError> {
+        let mut buffer = [0u8; AGGREGATION_POLICY_SIZE];
+        policy.encode(&mut buffer);
+        self.nvs.set_blob(KEY_AGGREGATION_POLICY, &buffer)
+    }
+
+    pub fn get_mount_recovery(&self) -> Result<MountRecovery, EspError> {
+        Ok(self
+            .nvs
+            .get_u8(KEY_MOUNT_RECOVERY)?
+            .and_then(MountRecovery::from_u8)
+            .unwrap_or_default())
+    }
+
+    pub fn set_mount_recovery(&mut self, policy: MountRecovery) -> Result<(), EspError> {
+        self.nvs.set_u8(KEY_MOUNT_RECOVERY, policy as u8)
+    }
+
+    /// The last failed mount of the storage partition. Not a device setting: it stays
+    /// until the app clears it.
+    pub fn get_mount_incident(&self) -> Result<Option<MountIncident>, EspError> {
+        let mut buffer = [0u8; MOUNT_INCIDENT_SIZE];
+        Ok(self
+            .nvs
+            .get_blob(KEY_MOUNT_INCIDENT, &mut buffer)?
+            .and_then(MountIncident::decode))
+    }
+
+    pub fn set_mount_incident(&mut self, incident: &MountIncident) -> Result<(), EspError> {
+        let mut buffer = [0u8; MOUNT_INCIDENT_SIZE];
+        incident.encode(&mut buffer);
+        self.nvs.set_blob(KEY_MOUNT_INCIDENT, &buffer)
+    }
+
+    pub fn clear_mount_incident(&mut self) -> Result<(), EspError> {
+        self.nvs.remove(KEY_MOUNT_INCIDENT).map(|_| ())
+    }
+
+    /// Wear counters of the PMS. Not a device setting: never reset from the app.
+    pub fn get_sensor_usage(&self) -> Result<SensorUsage, EspError> {
+        let mut buffer = [0u8; SENSOR_USAGE_SIZE];
+        Ok(self
+            .nvs
+            .get_blob(KEY_SENSOR_USAGE, &mut buffer)?
+            .and_then(SensorUsage::decode)
+            .unwrap_or_default())
+    }
+
+    pub fn set_sensor_usage(&mut self, usage: &SensorUsage) -> Result<(), EspError> {
+        let mut buffer = [0u8; SENSOR_USAGE_SIZE];
+        usage.encode(&mut buffer);
+        self.nvs.set_blob(KEY_SENSOR_USAGE, &buffer)
+    }
+
     pub fn get_session_config(&self) -> Result<Option<SessionConfig>, EspError> {
         let uuid = match self.get_uuid()? {
             Some(u) => u,
@@ -158,18 +343,18 @@ impl NvsManager {
         let Some(session_type) = (if is_mobile {
             Some(SessionType::MOBILE)
         } else {
-            self.get_pm1_index()?
+            self.get_stream_indices()?
                 .zip(self.get_token()?)
-                .zip(self.get_pm2_5_index()?)
                 .zip(self.get_wifi_ssid()?)
                 .zip(self.get_wifi_password()?)
-                .map(|((((p1, token), p2), ssid), pass)| SessionType::FIXED {
-                    pm1_index: p1,
-                    pm2_5_index: p2,
-                    token,
-                    wifi_ssid: ssid,
-                    wifi_password: pass,
-                })
+                .map(
+                    |(((stream_indices, token), ssid), pass)| SessionType::FIXED {
+                        stream_indices,
+                        token,
+                        wifi_ssid: ssid,
+                        wifi_password: pass,
+                    },
+                )
         }) else {
             return Ok(None);
         };
@@ -184,15 +369,13 @@ impl NvsManager {
                 self.set_is_mobile(true)?;
             }
             SessionType::FIXED {
-                pm1_index,
-                pm2_5_index,
+                stream_indices,
                 token,
                 wifi_ssid,
                 wifi_password,
             } => {
                 self.set_is_mobile(false)?;
-                self.set_pm1_index(*pm1_index)?;
-                self.set_pm2_5_index(*pm2_5_index)?;
+                self.set_stream_indices(stream_indices)?;
                 self.set_token(*token)?;
                 self.set_wifi_ssid(wifi_ssid)?;
                 self.set_wifi_password(wifi_password)?;
@@ -0,0 +1,147 @@
+use crate::storage::line_format::{self, MAX_LINE_RECORDS};
+use crate::storage::storage_iterator::ForwardMeasurementIter;
+use std::collections::VecDeque;
+use std::io::{Read, Seek, Write};
+
+/// Corrupt ranges listed in a report; `corrupt_bytes` still counts all of them.
+pub const MAX_REPORTED_RANGES: usize = 16;
+/// Records a timestamp is compared against to find duplicates: enough to catch a line
+/// written twice. Duplicates are only reported; two real records share a timestamp when
+/// the clock is set back by less than a jump, or when both were taken before it was set.
+const DUPLICATE_WINDOW: usize = 2 * MAX_LINE_RECORDS;
+
+/// What a scan of a session file found.
+#[derive(Debug, Clone, Default)]
+pub struct ScanReport {
+    /// Lines that passed their check.
+    pub lines: u32,
+    pub records: u32,
+    /// `(offset, length)` of data between lines that is not a valid line, the first
+    /// `MAX_REPORTED_RANGES` of them.
+    pub corrupt_ranges: Vec<(u64, u64)>,
+    pub corrupt_bytes: u64,
+    /// Records older than the record before them.
+    pub out_of_order: u32,
+    /// Records with the timestamp of a record shortly before them.
+    pub duplicates: u32,
+    /// The file was rewritten without its corrupt data.
+    pub repaired: bool,
+}
+
+impl ScanReport {
+    /// Nothing a repair would remove. Out-of-order and duplicate records are kept, as
+    /// the clock may well have been set back.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_bytes == 0
+    }
+
+    fn add_corrupt(&mut self, start: u64, end: u64) {
+        if end <= start {
+            return;
+        }
+        self.corrupt_bytes += end - start;
+        if self.corrupt_ranges.len() < MAX_REPORTED_RANGES {
+            self.corrupt_ranges.push((start, end - start));
+        }
+    }
+}
+
+/// Check every line from `data_start` on, writing every valid record to `out` as new
+/// lines, without the corrupt bytes between them. Scan into `std::io::sink()` for the
+/// report alone.
+pub fn scan<R: Read + Seek>(
+    lines: ForwardMeasurementIter<R>,
+    data_start: u64,
+    out: &mut impl Write,
+) -> std::io::Result<ScanReport> {
+    let stream_count = lines.stream_count();
+    let file_len = lines.file_len();
+    let mut report = ScanReport::default();
+    let mut expected = data_start;
+    let mut recent: VecDeque<u32> = VecDeque::with_capacity(DUPLICATE_WINDOW);
+    for line in lines {
+        report.add_corrupt(expected, line.start_offset);
+        expected = line.end_offset;
+        report.lines += 1;
+        let mut records: Vec<(u32, &[u16])> = Vec::with_capacity(line.measurements.len());
+        let mut flags = Vec::with_capacity(line.measurements.len());
+        for measurement in &line.measurements {
+            report.records += 1;
+            let timestamp = measurement.timestamp;
+            if recent.contains(&timestamp) {
+                report.duplicates += 1;
+            }
+            if recent.back().is_some_and(|&last| timestamp < last) {
+                report.out_of_order += 1;
+            }
+            if recent.len() == DUPLICATE_WINDOW {
+                recent.pop_front();
+            }
+            recent.push_back(timestamp);
+            records.push((timestamp, measurement.values()));
+            flags.push(measurement.flags);
+        }
+        if !records.is_empty() {
+            out.write_all(&line_format::encode_line(&records, &flags, stream_count))?;
+        }
+    }
+    report.add_corrupt(expected, file_len);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::file_header::FileHeader;
+    use std::io::Cursor;
+    use uuid::Uuid;
+
+    const STREAMS: usize = 2;
+
+    fn session_file(lines: &[&[u8]]) -> (Vec<u8>, u64) {
+        let header = FileHeader::new(Uuid::nil(), STREAMS, [0; 6]).encode();
+        let data_start = header.len() as u64;
+        (
+            header.into_iter().chain(lines.concat()).collect(),
+            data_start,
+        )
+    }
+
+    fn scan_file(file: Vec<u8>, data_start: u64) -> (ScanReport, Vec<u8>) {
+        let lines = ForwardMeasurementIter::new(Cursor::new(file), STREAMS, 0).unwrap();
+        let mut out = Vec::new();
+        let report = scan(lines, data_start, &mut out).unwrap();
+        (report, out)
+    }
+
+    #[test]
+    fn records_sharing_a_timestamp_are_reported_and_kept() {
+        // The clock set back by a few seconds between the two lines
+        let first = line_format::encode_line(&[(100, &[1, 2]), (101, &[3, 4])], &[0, 0], STREAMS);
+        let second = line_format::encode_line(&[(101, &[5, 6])], &[0], STREAMS);
+        let (file, data_start) = session_file(&[&first, &second]);
+        let (report, out) = scan_file(file, data_start);
+        assert_eq!(report.records, 3);
+        assert_eq!(report.duplicates, 1);
+        assert!(report.is_clean());
+        assert_eq!(out, [first, second].concat());
+    }
+
+    #[test]
+    fn only_corrupt_bytes_are_removed() {
+        let first = line_format::encode_line(&[(100, &[1, 2])], &[0], STREAMS);
+        let second = line_format::encode_line(&[(100, &[1, 2])], &[0], STREAMS);
+        let garbage = [0x55; 7];
+        let (file, data_start) = session_file(&[&first, &garbage, &second]);
+        let (report, out) = scan_file(file, data_start);
+        assert_eq!(report.lines, 2);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.corrupt_bytes, garbage.len() as u64);
+        assert_eq!(
+            report.corrupt_ranges,
+            [(data_start + first.len() as u64, garbage.len() as u64)]
+        );
+        assert!(!report.is_clean());
+        assert_eq!(out, [first, second].concat());
+    }
+}
@@ -12,8 +12,9 @@ pub struct SessionConfig {
 pub enum SessionType {
     MOBILE,
     FIXED {
-        pm1_index: u8,
-        pm2_5_index: u8,
+        /// Server-side sensor index for each registry stream, in record order, optionally
+        /// followed by the one record quality flags are uploaded to.
+        stream_indices: Vec<u8>,
         token: u128,
         wifi_ssid: String,
         wifi_password: String,
@@ -0,0 +1,119 @@
+use crate::sensor::measurement::Measurement;
+use crate::storage::capacity::{StorageUsage, HIGH_WATER_PERCENT};
+use crate::storage::clock;
+use crate::storage::line_format;
+use crate::storage::storage_iterator::ForwardMeasurementIter;
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+/// What a session holds on flash, counted once by `scan` and then kept up to date as
+/// lines are appended.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionStats {
+    pub records: u32,
+    pub first: Option<u32>,
+    pub last: Option<u32>,
+    /// Records timestamped before the clock was set, which could not be rebased.
+    pub unset_clock: u32,
+    /// Bytes between lines that are not a valid line.
+    pub corrupt_bytes: u64,
+    /// Bytes of lines and corruption from the read pointer on.
+    pub data_bytes: u64,
+}
+
+impl SessionStats {
+    /// Count the lines from `data_start` on.
+    pub fn scan<R: Read + Seek>(lines: ForwardMeasurementIter<R>, data_start: u64) -> Self {
+        let file_len = lines.file_len();
+        let mut stats = Self {
+            data_bytes: file_len.saturating_sub(data_start),
+            ..Self::default()
+        };
+        let mut expected = data_start;
+        for line in lines {
+            stats.corrupt_bytes += line.start_offset.saturating_sub(expected);
+            expected = line.end_offset;
+            stats.add(&line.measurements);
+        }
+        stats.corrupt_bytes += file_len.saturating_sub(expected);
+        stats
+    }
+
+    /// Count records appended to the session, or still buffered for it.
+    pub fn add(&mut self, records: &[Measurement]) {
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            return;
+        };
+        self.records += records.len() as u32;
+        self.unset_clock += records
+            .iter()
+            .filter(|record| !clock::is_set(record.timestamp))
+            .count() as u32;
+        self.first.get_or_insert(first.timestamp);
+        self.last = Some(last.timestamp);
+    }
+
+    /// Take `removed`, the lines a sync removed from the head of the session, off these
+    /// stats; `first` is the timestamp of the record now at the head. Returns false if
+    /// that cannot be done exactly and the session has to be scanned again, as when it
+    /// holds corrupt bytes the removed lines may or may not have covered.
+    pub fn remove_head(&mut self, removed: &SessionStats, first: Option<u32>) -> bool {
+        if self.corrupt_bytes > 0
+            || removed.records > self.records
+            || removed.data_bytes > self.data_bytes
+            || (removed.records < self.records && first.is_none())
+        {
+            return false;
+        }
+        self.records -= removed.records;
+        self.unset_clock = self.unset_clock.saturating_sub(removed.unset_clock);
+        self.data_bytes -= removed.data_bytes;
+        self.first = first;
+        if self.records == 0 {
+            self.last = None;
+        }
+        true
+    }
+}
+
+/// A session's stats together with the state of the partition it is stored on.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageStats {
+    pub session: SessionStats,
+    /// `None` if littlefs could not be queried.
+    pub usage: Option<StorageUsage>,
+    /// Hours of recording at the session interval before the partition reaches
+    /// `HIGH_WATER_PERCENT` and the overflow policy applies.
+    pub remaining_hours: Option<u32>,
+    /// Clock jumps marked while the session was recording.
+    pub clock_jumps: usize,
+}
+
+impl StorageStats {
+    pub fn new(
+        session: SessionStats,
+        usage: Option<StorageUsage>,
+        interval: Duration,
+        stream_count: usize,
+        clock_jumps: usize,
+    ) -> Self {
+        // The session's own bytes per record include line framing and compaction;
+        // an uncompacted record is the estimate until it has any.
+        let record_bytes = session
+            .data_bytes
+            .saturating_sub(session.corrupt_bytes)
+            .checked_div(session.records as u64)
+            .filter(|&bytes| bytes > 0)
+            .unwrap_or(line_format::record_size(stream_count) as u64);
+        let remaining_hours = usage.filter(|_| !interval.is_zero()).map(|usage| {
+            let records = usage.room_below(HIGH_WATER_PERCENT) / record_bytes;
+            (records * interval.as_secs() / 3600).min(u32::MAX as u64) as u32
+        });
+        Self {
+            session,
+            usage,
+            remaining_hours,
+            clock_jumps,
+        }
+    }
+}
@@ -1,96 +1,482 @@
-use crate::sensor::measurement::Measurement;
-use crate::storage::storage_iterator::MeasurementIter;
+use crate::alarm::AlarmRecord;
+use crate::sensor::measurement::{Measurement, FLAG_CLOCK_UNSET};
+use crate::sensor::sensor_thread::PMS_STREAMS;
+use crate::storage::aggregation::{self, AggregationPolicy};
+use crate::storage::capacity::{
+    self, StoragePolicy, StorageUsage, HIGH_WATER_PERCENT, LOW_WATER_PERCENT,
+};
+use crate::storage::clock::{ClockJump, ClockMark};
+use crate::storage::digest::{self, DigestAlgorithm, DigestRequest, RangeDigest};
+use crate::storage::event_log::{self, EventKind};
+use crate::storage::file_header::FileHeader;
+use crate::storage::flush_policy::{self, BufferStore, FlushPolicy, RecordBuffer};
+use crate::storage::journal::{self, Journal};
+use crate::storage::line_format;
+use crate::storage::repair::{self, ScanReport};
+use crate::storage::stats::{SessionStats, StorageStats};
+use crate::storage::storage_error::{ErrorCounts, StorageError, StorageErrorKind};
+use crate::storage::storage_iterator::{
+    encode_measurements, ForwardMeasurementIter, MeasurementIter,
+};
+use crate::storage::sync_state::{SyncBatch, SyncState, RANGE_BATCH};
 use log::{error, info, warn};
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::sync::Mutex;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 pub const MOUNT_POINT: &str = "/storage";
-pub const FILE_PATH: &str = "/storage/psm.bin";
-pub const START_BYTES: [u8; 2] = [0xAB, 0xBA]; // gimmie gimmie gimmie start bytes after midnight
+/// One measurement file per session, named after its UUID.
+pub const SESSIONS_DIR: &str = "/storage/sessions";
+/// Single measurement file written by firmware before per-session files.
+pub const LEGACY_FILE_PATH: &str = "/storage/psm.bin";
+const LEGACY_PREV_FILE_PATH: &str = "/storage/psm.prev";
+const ALARMS_FILE_PATH: &str = "/storage/alarms.bin";
+const ALARMS_OLD_FILE_PATH: &str = "/storage/alarms.old";
+// Roughly 300 alarm records; the previous generation is kept in ALARMS_OLD_FILE_PATH
+const ALARMS_MAX_FILE_SIZE: u64 = 4096;
 
-// Buffer up to N records before flushing to flash.
-const BUFFER_CAPACITY: usize = 10;
-//Record size is 2 start bytes + 1 byte number of measurements in a record + 8 bytes (timestamp + raw data) for each record + 1 byte checksum
-const MAX_RECORD_SIZE: usize = BUFFER_CAPACITY * 8 + 4;
+/// Fill level up to which a policy may rewrite a file to free space.
+const REWRITE_MAX_PERCENT: u64 = 95;
+
+// Buffer up to one line of records before flushing to flash.
+const BUFFER_CAPACITY: usize = line_format::MAX_LINE_RECORDS;
+/// Streams per line of a headerless file: firmware before the header only stored the
+/// PMS streams.
+const HEADERLESS_STREAMS: usize = PMS_STREAMS.len();
+
+pub fn session_file_path(session: &Uuid) -> String {
+    format!("{}/{}.bin", SESSIONS_DIR, session.simple())
+}
+
+/// Where a session file is rewritten before replacing the original.
+fn rewrite_file_path(session: &Uuid) -> String {
+    format!("{}/{}.tmp", SESSIONS_DIR, session.simple())
+}
+
+fn read_pointer(session: &Uuid) -> u64 {
+    SyncState::load(session).read_pointer
+}
+
+fn read_header(path: &str) -> Option<FileHeader> {
+    File::open(path)
+        .and_then(|mut file| FileHeader::read(&mut file))
+        .ok()
+        .flatten()
+}
+
+/// errno of a failed file operation, 0 if it did not come from the OS.
+fn os_error(error: &std::io::Error) -> u32 {
+    error.raw_os_error().unwrap_or(0) as u32
+}
+
+/// `record` moved across `jump` if it was taken before the clock was set.
+fn rebase_record(mut record: Measurement, jump: ClockJump) -> Measurement {
+    if let Some(timestamp) = jump.rebase(record.timestamp) {
+        record.timestamp = timestamp;
+        record.flags &= !FLAG_CLOCK_UNSET;
+    }
+    record
+}
+
+/// The header size, or 0 for a headerless file.
+fn header_size(path: &str) -> u64 {
+    read_header(path).map_or(0, |header| header.size as u64)
+}
+
+/// Streams per line of the file at `path`, from its header.
+fn file_stream_count(path: &str) -> usize {
+    read_header(path).map_or(HEADERLESS_STREAMS, |header| header.stream_count as usize)
+}
+
+/// Offset of the first measurement line not yet removed from the head of a session.
+fn data_start(session: &Uuid) -> u64 {
+    header_size(&session_file_path(session)).max(read_pointer(session))
+}
+
+/// A session file as it is uploaded: the header followed by the lines from the read
+/// pointer on. Returns the reader and its length.
+pub fn open_unsynced(session: &Uuid) -> std::io::Result<(impl Read, u64)> {
+    let path = session_file_path(session);
+    let header = File::open(&path)?;
+    let len = header.metadata()?.len();
+    let header_size = header_size(&path).min(len);
+    let start = data_start(session).min(len);
+    let mut lines = File::open(&path)?;
+    lines.seek(SeekFrom::Start(start))?;
+    Ok((
+        header.take(header_size).chain(lines),
+        header_size + len - start,
+    ))
+}
+
+/// Digest of `length` bytes (0 for all) from `offset` into the session as
+/// `open_unsynced` reads it, so that a client can check what it downloaded.
+pub fn digest_unsynced(
+    session: &Uuid,
+    algorithm: DigestAlgorithm,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<RangeDigest> {
+    let (reader, size) = open_unsynced(session)?;
+    digest::digest_range(reader, size, algorithm, offset, length)
+}
+
+fn create_sessions_dir() {
+    if let Err(e) = std::fs::create_dir(SESSIONS_DIR) {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            error!("Failed to create sessions directory: {}", e);
+        }
+    }
+}
+
+/// A session that has a measurement file on flash.
+#[derive(Debug, Clone)]
+pub struct StoredSession {
+    pub session: Uuid,
+    /// File size in bytes, header included.
+    pub size: u64,
+    /// Timestamps of the oldest and newest stored record, if any are readable.
+    pub first: Option<u32>,
+    pub last: Option<u32>,
+}
 
 struct StorageInner {
-    buffer: Vec<Measurement>,
+    buffer: RecordBuffer,
+    /// Session new measurements are written to.
+    current: Option<Uuid>,
+    policy: StoragePolicy,
+    flush_policy: FlushPolicy,
+    aggregation: AggregationPolicy,
+    /// `(total, used)` bytes of the partition as last queried, advanced by every
+    /// flush. `None` until first queried.
+    usage: Option<(u64, u64)>,
+    /// The policy could not make room for the last flush.
+    full: bool,
+    errors: ErrorCounts,
 }
 
 pub struct StorageManager {
-    inner: Mutex<StorageInner>,
+    /// Held for every change to the session files, see `files_lock`.
+    inner: Arc<Mutex<StorageInner>>,
+    /// Stats of the sessions asked about, see `stats`. Locked after `inner`.
+    stats: Mutex<HashMap<Uuid, SessionStats>>,
+    /// Streams per record of the sensor registry, which may change between boots with
+    /// `i2c-sensors`. Each session file keeps the count it was created with.
+    stream_count: usize,
+    mac: [u8; 6],
 }
 
 impl StorageManager {
-    /// Create a new StorageManager.
+    /// Create a new StorageManager. A measurement file left by older firmware is moved
+    /// into the sessions directory as `legacy_session`, the session saved in NVS that
+    /// wrote it.
+    ///
+    /// After an `unclean_reset` (a crash, watchdog or brown-out), or when measurements
+    /// were left in the journal, every session is scanned and repaired.
     ///
     /// IMPORTANT: You must mount LittleFS before calling this.
-    pub fn new() -> Self {
-        // Ensure the file exists
-        if let Err(e) = OpenOptions::new().append(true).create(true).open(FILE_PATH) {
-            log::error!("Failed to create/open storage file: {}", e);
+    pub fn new(
+        stream_count: usize,
+        mac: [u8; 6],
+        legacy_session: Option<Uuid>,
+        unclean_reset: bool,
+    ) -> Self {
+        create_sessions_dir();
+        // Left by a rewrite cut short by a reset; the original file is still in place.
+        for entry in std::fs::read_dir(SESSIONS_DIR)
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+        for path in [LEGACY_PREV_FILE_PATH, LEGACY_FILE_PATH] {
+            if let Err(e) = Self::migrate_legacy_file(path, legacy_session) {
+                error!("Failed to migrate {}: {}", path, e);
+            }
         }
-        Self {
-            inner: Mutex::new(StorageInner {
-                buffer: Vec::with_capacity(BUFFER_CAPACITY),
-            }),
+        let manager = Self {
+            inner: Arc::new(Mutex::new(StorageInner {
+                buffer: RecordBuffer::new(BUFFER_CAPACITY, stream_count),
+                current: None,
+                policy: StoragePolicy::default(),
+                flush_policy: FlushPolicy::default(),
+                aggregation: AggregationPolicy::default(),
+                usage: None,
+                full: false,
+                errors: ErrorCounts::default(),
+            })),
+            stats: Mutex::new(HashMap::new()),
+            stream_count,
+            mac,
+        };
+        manager.reconcile_sync_states();
+        let journaled = manager.replay_journal().unwrap_or_else(|e| {
+            error!("Failed to replay journaled measurements: {:?}", e);
+            true
+        });
+        if unclean_reset || journaled {
+            manager.repair_sessions();
         }
+        manager
     }
 
-    /// Buffer a measurement. When the buffer is full, it automatically flushes to flash.
-    pub fn save_measurement(&mut self, record: Measurement) -> anyhow::Result<()> {
+    /// Append the records buffered before a reset to their session. Returns whether
+    /// there were any.
+    fn replay_journal(&self) -> anyhow::Result<bool> {
+        let result = match journal::read()? {
+            Some(journal) if !journal.records.is_empty() => self.replay(journal).map(|()| true),
+            _ => Ok(false),
+        };
+        // Cleared even if replaying failed, or new records would be journaled after
+        // the old ones.
+        journal::clear()?;
+        result
+    }
+
+    /// Scan every session and rewrite the damaged ones.
+    fn repair_sessions(&self) {
+        info!("Checking stored sessions after an unclean reset");
+        for stored in self.stored_sessions() {
+            match self.scan_session(&stored.session, true) {
+                Ok(report) if report.is_clean() => {}
+                Ok(report) => warn!("Session {}: {:?}", stored.session, report),
+                Err(e) => error!("Failed to check session {}: {:?}", stored.session, e),
+            }
+        }
+    }
+
+    fn replay(&self, journal: Journal) -> anyhow::Result<()> {
+        let session = journal.session;
+        let path = session_file_path(&session);
+        let Ok(file) = File::open(&path) else {
+            warn!(
+                "Dropping {} journaled records of deleted session {}",
+                journal.records.len(),
+                session
+            );
+            return Ok(());
+        };
+        let stream_count = file_stream_count(&path);
+        if journal.stream_count != stream_count {
+            warn!(
+                "Dropping {} journaled records with {} streams, session {} has {}",
+                journal.records.len(),
+                journal.stream_count,
+                session,
+                stream_count
+            );
+            return Ok(());
+        }
+        // The last lines, enough of them to hold every journaled record
+        let mut tail_lines = Vec::new();
+        let mut tail_len = 0;
+        for line in MeasurementIter::new(file, HEADERLESS_STREAMS, data_start(&session))? {
+            tail_len += line.len();
+            tail_lines.push(line);
+            if tail_len >= journal.records.len() {
+                break;
+            }
+        }
+        let stored_tail: Vec<Measurement> = tail_lines.into_iter().rev().flatten().collect();
+        let records = journal.unstored(&stored_tail);
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.forget_stats(&session);
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        for line in records.chunks(line_format::MAX_LINE_RECORDS) {
+            file.write_all(&encode_measurements(line, stream_count))?;
+        }
+        info!(
+            "Replayed {} journaled records into session {}",
+            records.len(),
+            session
+        );
+        Ok(())
+    }
+
+    fn migrate_legacy_file(path: &str, legacy_session: Option<Uuid>) -> anyhow::Result<()> {
+        let Ok(mut file) = File::open(path) else {
+            return Ok(());
+        };
+        let header = FileHeader::read(&mut file)?;
+        let data_start = header.as_ref().map_or(0, |h| h.size as u64);
+        let session = header.map(|h| h.session).or(legacy_session);
+        drop(file);
+        match session {
+            Some(session) if std::fs::metadata(path)?.len() > data_start => {
+                let target = session_file_path(&session);
+                if std::fs::metadata(&target).is_ok() {
+                    warn!("{} already exists, keeping {}", target, path);
+                    return Ok(());
+                }
+                info!("Moving {} to {}", path, target);
+                std::fs::rename(path, target)?;
+            }
+            Some(_) => std::fs::remove_file(path)?,
+            None => warn!("Keeping {}: no session to attribute it to", path),
+        }
+        Ok(())
+    }
+
+    /// Start over on a partition formatted after the manager was created, which finds
+    /// no session files, without the records buffered for the lost ones.
+    pub fn reformatted(&self) {
         let mut inner = self.inner.lock().unwrap();
-        if inner.buffer.len() < BUFFER_CAPACITY {
-            inner.buffer.push(record);
+        inner.buffer.clear();
+        inner.current = None;
+        inner.usage = None;
+        inner.full = false;
+        self.stats.lock().unwrap().clear();
+        create_sessions_dir();
+    }
+
+    /// Whether new measurements can be appended to `session`: it has no file yet, or
+    /// one with as many streams per line as the sensor registry.
+    pub fn can_continue(&self, session: &Uuid) -> bool {
+        let _guard = self.inner.lock().unwrap();
+        let path = session_file_path(session);
+        std::fs::metadata(&path).is_err() || file_stream_count(&path) == self.stream_count
+    }
+
+    /// Direct new measurements to `session`, creating its file (with header) unless
+    /// the session already has one, in which case it is continued. Fails if that file
+    /// has a different number of streams, see `can_continue`.
+    pub fn start_session(&self, session: Uuid) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.buffer.is_empty() {
+            self.flush_buffer(&mut inner)?;
         }
-        if inner.buffer.len() >= BUFFER_CAPACITY {
-            Self::flush_buffer(&mut inner)
-        } else {
-            Ok(())
+        inner.current = None;
+        let path = session_file_path(&session);
+        if std::fs::metadata(&path).is_ok() {
+            let stream_count = file_stream_count(&path);
+            if stream_count != self.stream_count {
+                anyhow::bail!(
+                    "Session {} has {} streams, the sensors {}",
+                    session,
+                    stream_count,
+                    self.stream_count
+                );
+            }
+            inner.current = Some(session);
+            info!("Continuing storage file {}", path);
+            return Ok(());
+        }
+        inner.current = Some(session);
+        let header = FileHeader::new(session, self.stream_count, self.mac);
+        File::create(&path)?.write_all(&header.encode())?;
+        info!("Storage file: {}", header.describe());
+        Ok(())
+    }
+
+    /// Flush and stop writing to the current session. Its file stays until synced or deleted.
+    pub fn end_session(&self) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let result = self.flush_buffer(&mut inner);
+        Self::clear_buffer(&mut inner);
+        inner.current = None;
+        Ok(result?)
+    }
+
+    /// Buffer a measurement. When the buffer is full, it automatically flushes to flash.
+    /// Follow with `flush` or `flush_if_due`, which journal what they leave buffered so
+    /// a reset does not lose it.
+    pub fn save_measurement(&mut self, record: Measurement) -> Result<(), StorageError> {
+        let mut inner = self.inner.lock().unwrap();
+        flush_policy::save(&mut self.buffered(&mut inner), record, Instant::now())
+    }
+
+    /// Drop the buffered records along with their journal copy, which must not outlive
+    /// them: the next record would be journaled after the wrong session's.
+    fn clear_buffer(inner: &mut StorageInner) {
+        inner.buffer.clear();
+        if let Err(e) = journal::clear() {
+            warn!("Failed to clear measurement journal: {}", e);
         }
     }
 
     /// Force flush any buffered records to flash.
     /// Call this before sleeping, shutting down, or when you need data persisted immediately.
-    pub fn flush(&self) -> anyhow::Result<()> {
+    pub fn flush(&self) -> Result<(), StorageError> {
         let mut inner = self.inner.lock().unwrap();
-        if !inner.buffer.is_empty() {
-            Self::flush_buffer(&mut inner)
+        let result = if !inner.buffer.is_empty() {
+            self.flush_buffer(&mut inner)
         } else {
             Ok(())
+        };
+        self.journal_buffer(&mut inner);
+        result
+    }
+
+    /// Flush the buffered records if the flush policy says so, see `FlushPolicy::is_due`.
+    /// Returns whether it did.
+    pub fn flush_if_due(&self, interval: Duration, battery: i8) -> Result<bool, StorageError> {
+        let mut inner = self.inner.lock().unwrap();
+        let policy = inner.flush_policy;
+        flush_policy::flush_if_due(
+            &mut self.buffered(&mut inner),
+            &policy,
+            interval,
+            battery,
+            Instant::now(),
+        )
+    }
+
+    fn buffered<'a>(&'a self, inner: &'a mut StorageInner) -> Buffered<'a> {
+        Buffered {
+            manager: self,
+            inner,
+        }
+    }
+
+    /// Journal the records left buffered, by the flush policy or a failed flush, that
+    /// are not journaled yet. One append for all of them, see `journal`.
+    fn journal_buffer(&self, inner: &mut StorageInner) {
+        let Some(session) = inner.current else {
+            return;
+        };
+        match journal::append(&session, self.stream_count, inner.buffer.unjournaled()) {
+            Ok(()) => inner.buffer.mark_journaled(),
+            Err(e) => warn!("Failed to journal measurements: {}", e),
         }
     }
 
     /// Internal: write all buffered records to the file in one operation.
-    fn flush_buffer(inner: &mut StorageInner) -> anyhow::Result<()> {
-        let file = OpenOptions::new().append(true).open(FILE_PATH);
+    /// Errors are counted, see `errors`.
+    fn flush_buffer(&self, inner: &mut StorageInner) -> Result<(), StorageError> {
+        let result = self.write_buffer(inner);
+        if let Err(e) = &result {
+            inner.errors.add(e.kind());
+        }
+        result
+    }
+
+    fn write_buffer(&self, inner: &mut StorageInner) -> Result<(), StorageError> {
         if inner.buffer.is_empty() {
             return Ok(());
         }
+        let Some(session) = inner.current else {
+            return Err(StorageError::OpenFailed(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No session to store measurements in",
+            )));
+        };
+        self.ensure_capacity(inner)?;
+        let path = session_file_path(&session);
+        let file = OpenOptions::new().append(true).open(&path);
 
         match file {
             Ok(mut file) => {
-                // Pre-allocate a byte buffer for all records
-                let mut bytes = Vec::with_capacity(inner.buffer.len() * MAX_RECORD_SIZE);
-                bytes.extend_from_slice(&START_BYTES);
-                bytes.push(inner.buffer.len() as u8);
-
-                for record in &inner.buffer {
-                    let ts_bytes = record.timestamp.to_le_bytes();
-                    let pm1_bytes = record.pm1_0_avg.to_le_bytes();
-                    let pm2_bytes = record.pm2_5_avg.to_le_bytes();
-                    bytes.extend_from_slice(&ts_bytes);
-                    bytes.extend_from_slice(&pm1_bytes);
-                    bytes.extend_from_slice(&pm2_bytes);
-                }
-
-                // XOR checksum
-                let mut checksum: u8 = 0;
-                for b in bytes.iter() {
-                    checksum ^= b;
-                }
-                bytes.push(checksum);
+                let bytes = encode_measurements(inner.buffer.records(), file_stream_count(&path));
 
                 if let Err(e) = file.write_all(&bytes) {
                     log::error!(
@@ -98,93 +484,919 @@ impl StorageManager {
                         inner.buffer.len(),
                         e
                     );
-                    return Err(e.into());
+                    event_log::record(EventKind::StorageFailed, os_error(&e));
+                    return Err(StorageError::WriteFailed(e));
                 }
 
                 info!("Flushed {} records to flash", inner.buffer.len());
-                inner.buffer.clear();
+                if let Some(stats) = self.stats.lock().unwrap().get_mut(&session) {
+                    stats.add(inner.buffer.records());
+                    stats.data_bytes += bytes.len() as u64;
+                }
+                Self::clear_buffer(inner);
+                inner.buffer.flushed(Instant::now());
+                if let Some((_, used)) = &mut inner.usage {
+                    *used += bytes.len() as u64;
+                }
                 Ok(())
             }
             Err(e) => {
                 log::error!("Failed to open storage file for writing: {}", e);
-                Err(e.into())
+                event_log::record(EventKind::StorageFailed, os_error(&e));
+                Err(StorageError::OpenFailed(e))
+            }
+        }
+    }
+
+    /// Select what happens once the partition runs full.
+    pub fn set_policy(&self, policy: StoragePolicy) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.policy = policy;
+        inner.full = false;
+    }
+
+    /// Select when `flush_if_due` flushes.
+    pub fn set_flush_policy(&self, policy: FlushPolicy) {
+        self.inner.lock().unwrap().flush_policy = policy;
+    }
+
+    pub fn set_aggregation_policy(&self, policy: AggregationPolicy) {
+        self.inner.lock().unwrap().aggregation = policy;
+    }
+
+    /// Fill level of the partition, freshly queried.
+    pub fn usage(&self) -> Option<StorageUsage> {
+        let mut inner = self.inner.lock().unwrap();
+        Self::query_usage(&mut inner, true)
+    }
+
+    /// Storage errors since boot.
+    pub fn errors(&self) -> ErrorCounts {
+        self.inner.lock().unwrap().errors
+    }
+
+    /// Count an error met outside of the manager, such as a failed mount.
+    pub fn count_error(&self, kind: StorageErrorKind) {
+        self.inner.lock().unwrap().errors.add(kind);
+    }
+
+    /// The overflow policy could not make room for the last flush.
+    pub fn is_full(&self) -> bool {
+        self.inner.lock().unwrap().full
+    }
+
+    fn query_usage(inner: &mut StorageInner, refresh: bool) -> Option<StorageUsage> {
+        if refresh || inner.usage.is_none() {
+            match capacity::partition_usage() {
+                Ok(usage) => inner.usage = Some(usage),
+                Err(e) => {
+                    warn!("Failed to query storage usage: {:?}", e);
+                    return None;
+                }
+            }
+        }
+        let (total_bytes, used_bytes) = inner.usage?;
+        Some(StorageUsage {
+            policy: inner.policy,
+            used_bytes,
+            total_bytes,
+        })
+    }
+
+    /// Apply the overflow policy once the partition passes `HIGH_WATER_PERCENT`,
+    /// freeing space down to `LOW_WATER_PERCENT`. Errors if there is still no room.
+    fn ensure_capacity(&self, inner: &mut StorageInner) -> Result<(), StorageError> {
+        let above = |usage: Option<StorageUsage>| {
+            usage.is_some_and(|usage| usage.is_above(HIGH_WATER_PERCENT))
+        };
+        // The running estimate drifts from littlefs' block-granular count, so it only
+        // decides when to ask littlefs again.
+        if !above(Self::query_usage(inner, false)) || !above(Self::query_usage(inner, true)) {
+            inner.full = false;
+            return Ok(());
+        }
+        while let Some(usage) =
+            Self::query_usage(inner, true).filter(|usage| usage.is_above(LOW_WATER_PERCENT))
+        {
+            match self.make_room(inner, usage) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    error!("Failed to free storage: {:?}", e);
+                    break;
+                }
+            }
+        }
+        let was_full = inner.full;
+        let usage = Self::query_usage(inner, true);
+        inner.full = above(usage);
+        if inner.full {
+            if !was_full {
+                let fill = usage.map_or(0, |usage| usage.fill_percent());
+                event_log::record(EventKind::StorageFull, fill as u32);
+            }
+            return Err(StorageError::Full);
+        }
+        Ok(())
+    }
+
+    /// Free some space, first by reclaiming lines already removed from the head of a
+    /// session, then according to the policy. `false` once nothing is left to free.
+    fn make_room(&self, inner: &StorageInner, usage: StorageUsage) -> anyhow::Result<bool> {
+        // An empty current session has nothing to give.
+        let sessions: Vec<StoredSession> = self
+            .stored_sessions()
+            .into_iter()
+            .filter(|s| s.first.is_some() || inner.current != Some(s.session))
+            .collect();
+        for stored in &sessions {
+            if self.reclaim_synced(&stored.session, usage)? {
+                return Ok(true);
             }
         }
+        if usage.policy == StoragePolicy::Stop {
+            return Ok(false);
+        }
+        if usage.policy == StoragePolicy::Downsample {
+            for stored in &sessions {
+                let keep_min_max = inner.aggregation.keeps_min_max();
+                if self.downsample_session(&stored.session, usage, keep_min_max)? {
+                    return Ok(true);
+                }
+            }
+        }
+        let Some(oldest) = sessions.first() else {
+            return Ok(false);
+        };
+        if inner.current != Some(oldest.session) {
+            warn!("Storage full, deleting session {}", oldest.session);
+            std::fs::remove_file(session_file_path(&oldest.session))?;
+            self.forget_stats(&oldest.session);
+            SyncState::remove(&oldest.session)?;
+            ClockMark::remove(&oldest.session)?;
+            return Ok(true);
+        }
+        self.drop_head(&oldest.session, usage)
+    }
+
+    /// Rewrite a session without the lines before its read pointer, if it fits.
+    fn reclaim_synced(&self, session: &Uuid, usage: StorageUsage) -> anyhow::Result<bool> {
+        let path = session_file_path(session);
+        let header_size = header_size(&path);
+        let start = read_pointer(session);
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        if start <= header_size
+            || header_size + len.saturating_sub(start) > usage.room_below(REWRITE_MAX_PERCENT)
+        {
+            return Ok(false);
+        }
+        info!(
+            "Reclaiming {} synced bytes of session {}",
+            start - header_size,
+            session
+        );
+        self.rewrite_session(session, header_size, Some(start..len), |out| {
+            file.seek(SeekFrom::Start(start))?;
+            std::io::copy(&mut file, out)
+        })?;
+        Ok(true)
+    }
+
+    /// Replace a session's lines with their `DOWNSAMPLE_SECS` averages, if that saves
+    /// at least a quarter of them and the rewritten file fits. Not while a batch is
+    /// pending, whose lines would change.
+    fn downsample_session(
+        &self,
+        session: &Uuid,
+        usage: StorageUsage,
+        keep_min_max: bool,
+    ) -> anyhow::Result<bool> {
+        if SyncState::load(session).pending.is_some() {
+            return Ok(false);
+        }
+        let path = session_file_path(session);
+        let header_size = header_size(&path);
+        let start = data_start(session);
+        let lines = || ForwardMeasurementIter::new(File::open(&path)?, HEADERLESS_STREAMS, start);
+        let data_len = std::fs::metadata(&path)?.len().saturating_sub(start);
+        let size = capacity::downsample(lines()?, &mut std::io::sink(), keep_min_max)?;
+        if size > data_len * 3 / 4 || header_size + size > usage.room_below(REWRITE_MAX_PERCENT) {
+            return Ok(false);
+        }
+        warn!(
+            "Storage full, downsampling session {}: {} -> {} bytes",
+            session, data_len, size
+        );
+        self.rewrite_session(session, header_size, None, |out| {
+            capacity::downsample(lines()?, out, keep_min_max)
+        })?;
+        Ok(true)
+    }
+
+    /// Average the old data of every session into `aggregation::TIERS` while the
+    /// partition is past the threshold of the aggregation policy. Returns whether any
+    /// session was rewritten.
+    pub fn aggregate(&self) -> anyhow::Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let policy = inner.aggregation;
+        if !policy.enabled {
+            return Ok(false);
+        }
+        let mut aggregated = false;
+        for stored in self.stored_sessions() {
+            let Some(usage) = Self::query_usage(&mut inner, true)
+                .filter(|usage| usage.is_above(policy.threshold_percent as u64))
+            else {
+                break;
+            };
+            let Some(newest) = stored.last else {
+                continue;
+            };
+            aggregated |=
+                self.aggregate_session(&stored.session, newest, usage, policy.keep_min_max)?;
+        }
+        Ok(aggregated)
+    }
+
+    /// Replace a session's lines with their tiered averages, if that frees at least
+    /// `aggregation::MIN_SAVED_BYTES` and the rewritten file fits. Not while a batch is
+    /// pending, whose lines would change.
+    fn aggregate_session(
+        &self,
+        session: &Uuid,
+        newest: u32,
+        usage: StorageUsage,
+        keep_min_max: bool,
+    ) -> anyhow::Result<bool> {
+        if SyncState::load(session).pending.is_some() {
+            return Ok(false);
+        }
+        let path = session_file_path(session);
+        let header_size = header_size(&path);
+        let start = data_start(session);
+        let lines = || ForwardMeasurementIter::new(File::open(&path)?, HEADERLESS_STREAMS, start);
+        let tier = |record: &Measurement| aggregation::tier_bucket(record, newest);
+        let data_len = std::fs::metadata(&path)?.len().saturating_sub(start);
+        let size = aggregation::aggregate(lines()?, &mut std::io::sink(), keep_min_max, tier)?;
+        if size + aggregation::MIN_SAVED_BYTES > data_len
+            || header_size + size > usage.room_below(REWRITE_MAX_PERCENT)
+        {
+            return Ok(false);
+        }
+        info!(
+            "Aggregating old data of session {}: {} -> {} bytes",
+            session, data_len, size
+        );
+        self.rewrite_session(session, header_size, None, |out| {
+            aggregation::aggregate(lines()?, out, keep_min_max, tier)
+        })?;
+        Ok(true)
+    }
+
+    /// Drop the oldest lines of the session being recorded, keeping as many of the
+    /// newest as a rewrite has room for, and at most half of them.
+    fn drop_head(&self, session: &Uuid, usage: StorageUsage) -> anyhow::Result<bool> {
+        let path = session_file_path(session);
+        let header_size = header_size(&path);
+        let start = data_start(session);
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        if len <= start {
+            return Ok(false);
+        }
+        let room = usage
+            .room_below(REWRITE_MAX_PERCENT)
+            .saturating_sub(header_size);
+        let keep = ((len - start) / 2).min(room);
+        let xor_lines = FileHeader::xor_lines(read_header(&path).as_ref());
+        let tail_start =
+            capacity::line_boundary(&mut file, len - keep, file_stream_count(&path), xor_lines)?;
+        warn!(
+            "Storage full, dropping the oldest {} bytes of session {}",
+            tail_start - start,
+            session
+        );
+        self.rewrite_session(session, header_size, Some(tail_start..len), |out| {
+            file.seek(SeekFrom::Start(tail_start))?;
+            std::io::copy(&mut file, out)
+        })?;
+        Ok(true)
+    }
+
+    /// Replace a session file with its first `header_size` bytes followed by what
+    /// `write_lines` produces, and reset its read pointer. littlefs cannot drop the
+    /// start of a file in place, so this goes through a temporary file renamed over
+    /// the original. `kept` is the range of lines `write_lines` starts with, if it
+    /// copies them unchanged; a pending batch within them stays pending (see
+    /// `SyncState::rewritten`), any other is dropped.
+    fn rewrite_session(
+        &self,
+        session: &Uuid,
+        header_size: u64,
+        kept: Option<Range<u64>>,
+        write_lines: impl FnOnce(&mut File) -> std::io::Result<u64>,
+    ) -> anyhow::Result<()> {
+        let path = session_file_path(session);
+        let tmp_path = rewrite_file_path(session);
+        let mut header = vec![0u8; header_size as usize];
+        File::open(&path)?.read_exact(&mut header)?;
+        let result = File::create(&tmp_path).and_then(|mut out| {
+            out.write_all(&header)?;
+            write_lines(&mut out)?;
+            out.sync_all()
+        });
+        // The pointer goes first: a reset in between then leaves the original file
+        // to be synced again in full, rather than a pointer into the new one. A
+        // pending batch moved along is caught by `reconcile_sync_states`.
+        if let Err(e) = result
+            .and_then(|()| SyncState::rewritten(session, kept, header_size))
+            .and_then(|()| std::fs::rename(&tmp_path, &path))
+        {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        self.forget_stats(session);
+        Ok(())
+    }
+
+    /// Move the current session's records taken before the clock was first set onto
+    /// the set clock, and mark the jump in the session. Only records since the last
+    /// boot can be rebased: the buffered ones, and the run of unset-clock records at
+    /// the end of the file whose timestamps keep counting up, as a boot starts the
+    /// unset clock over at 0. Earlier ones keep their timestamps, which
+    /// `clock::is_set` tells apart.
+    pub fn rebase(&self, jump: ClockJump) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(session) = inner.current else {
+            return Ok(());
+        };
+        if !jump.is_jump() {
+            return Ok(());
+        }
+        let mut rebased = 0;
+        for record in inner.buffer.records_mut() {
+            if jump.rebase(record.timestamp).is_some() {
+                *record = rebase_record(*record, jump);
+                rebased += 1;
+            }
+        }
+        if rebased > 0 {
+            // The journal must match the buffer, or a reset would replay the old times.
+            let journaled = journal::clear().and_then(|()| {
+                journal::append(&session, self.stream_count, inner.buffer.records())
+            });
+            match journaled {
+                Ok(()) => inner.buffer.mark_journaled(),
+                Err(e) => warn!("Failed to journal rebased measurements: {}", e),
+            }
+        }
+        if jump.sets_clock() {
+            match self.rebase_file(&session, jump) {
+                Ok(count) => rebased += count,
+                Err(e) => error!("Failed to rebase session {}: {:?}", session, e),
+            }
+        }
+        info!(
+            "Clock set from {} to {}, rebased {} records",
+            jump.from, jump.to, rebased
+        );
+        let mark = ClockMark {
+            jump,
+            rebased: rebased.min(u16::MAX as u32) as u16,
+        };
+        Ok(mark.append(&session)?)
+    }
+
+    /// Rewrite the run of unset-clock records at the end of a session file with
+    /// rebased timestamps, see `rebase`. Returns how many were rebased.
+    fn rebase_file(&self, session: &Uuid, jump: ClockJump) -> anyhow::Result<u32> {
+        let path = session_file_path(session);
+        let header_size = header_size(&path);
+        let start = data_start(session);
+        // Start offset and last timestamp of the run so far
+        let mut run: Option<(u64, u32)> = None;
+        for line in ForwardMeasurementIter::new(File::open(&path)?, HEADERLESS_STREAMS, start)? {
+            let extend = |last: Option<u32>| {
+                line.measurements
+                    .iter()
+                    .try_fold(last, |last, m| {
+                        let counting_up = last.is_none_or(|last| m.timestamp >= last);
+                        (jump.rebase(m.timestamp).is_some() && counting_up)
+                            .then_some(Some(m.timestamp))
+                    })
+                    .flatten()
+            };
+            run = run
+                .and_then(|(from, last)| extend(Some(last)).map(|last| (from, last)))
+                .or_else(|| extend(None).map(|last| (line.start_offset, last)));
+        }
+        let Some((run_start, _)) = run else {
+            return Ok(0);
+        };
+        // Records already sent keep their times, or they would be sent again as new ones
+        if let Some(batch) = SyncState::load(session).pending {
+            if batch.end_offset > run_start {
+                warn!(
+                    "Not rebasing session {}, its records were sent in batch {}",
+                    session, batch.id
+                );
+                return Ok(0);
+            }
+        }
+
+        let mut rebased = 0;
+        let mut file = File::open(&path)?;
+        self.rewrite_session(session, header_size, Some(start..run_start), |out| {
+            file.seek(SeekFrom::Start(start))?;
+            std::io::copy(&mut (&mut file).take(run_start - start), out)?;
+            let lines = ForwardMeasurementIter::new(file, HEADERLESS_STREAMS, run_start)?;
+            let stream_count = lines.stream_count();
+            for line in lines {
+                let records: Vec<Measurement> = line
+                    .measurements
+                    .into_iter()
+                    .map(|m| rebase_record(m, jump))
+                    .collect();
+                out.write_all(&encode_measurements(&records, stream_count))?;
+                rebased += records.len() as u32;
+            }
+            Ok(0)
+        })?;
+        Ok(rebased)
+    }
+
+    /// Append a finished alarm to the alarm log:
+    /// u32 start + u32 end + u8 alarm + u8 stream + u16 peak + u16 CRC-16, all LE.
+    pub fn save_alarm(&self, record: &AlarmRecord) -> anyhow::Result<()> {
+        let _guard = self.inner.lock().unwrap();
+        if std::fs::metadata(ALARMS_FILE_PATH).is_ok_and(|m| m.len() >= ALARMS_MAX_FILE_SIZE) {
+            std::fs::rename(ALARMS_FILE_PATH, ALARMS_OLD_FILE_PATH)?;
+        }
+        let mut bytes = Vec::with_capacity(14);
+        bytes.extend_from_slice(&record.start.to_le_bytes());
+        bytes.extend_from_slice(&record.end.to_le_bytes());
+        bytes.push(record.alarm);
+        bytes.push(record.stream);
+        bytes.extend_from_slice(&record.peak.to_le_bytes());
+        let crc = line_format::crc16(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(ALARMS_FILE_PATH)?;
+        file.write_all(&bytes)?;
+        info!(
+            "Saved alarm {} on stream {}: {}..{}",
+            record.alarm, record.stream, record.start, record.end
+        );
+        Ok(())
     }
-    pub fn has_measurements(&self) -> bool {
+
+    pub fn has_measurements(&self, session: &Uuid) -> bool {
         let guard = self.inner.lock().unwrap();
-        if !guard.buffer.is_empty() {
+        if guard.current.as_ref() == Some(session) && !guard.buffer.is_empty() {
             return true;
         }
 
-        match std::fs::metadata(FILE_PATH) {
-            Ok(metadata) => metadata.len() > 0,
-            Err(e) => {
-                log::warn!("Failed to read storage metadata: {}", e);
-                false
-            }
+        match std::fs::metadata(session_file_path(session)) {
+            Ok(metadata) => metadata.len() > data_start(session),
+            Err(_) => false,
         }
     }
 
-    pub fn get_file_size(&self) -> Option<u64> {
+    pub fn stream_count(&self) -> usize {
+        self.stream_count
+    }
+
+    /// Size of the session as a sync sends it, without the lines already synced, see
+    /// `open_unsynced`. `None` if the session has no file.
+    pub fn get_file_size(&self, session: &Uuid) -> Option<u64> {
         let _guard = self.inner.lock().ok()?;
-        Some(std::fs::metadata(FILE_PATH).ok()?.len())
+        open_unsynced(session).ok().map(|(_, len)| len)
     }
 
-    pub fn iter_measurements(&self) -> Option<MeasurementIter> {
+    /// Every session with a file on flash, with its size and time range.
+    pub fn sessions(&self) -> Vec<StoredSession> {
         if let Err(e) = self.flush() {
             warn!("Failed to flush storage: {}", e);
         }
         let _guard = self.inner.lock().unwrap();
-        info!("Reading measurements from storage");
-        let file = File::open(FILE_PATH).ok()?;
-        MeasurementIter::new(file).ok()
+        self.stored_sessions()
+    }
+
+    /// `sessions` without flushing or locking, oldest first. Sessions without a readable
+    /// record, new or damaged ones, come last so that `make_room` does not take them for
+    /// the oldest.
+    fn stored_sessions(&self) -> Vec<StoredSession> {
+        let Ok(entries) = std::fs::read_dir(SESSIONS_DIR) else {
+            return Vec::new();
+        };
+        let mut sessions: Vec<StoredSession> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let session = Uuid::parse_str(name.to_str()?.strip_suffix(".bin")?).ok()?;
+                let file = File::open(entry.path()).ok()?;
+                let size = file.metadata().ok()?.len();
+                let start = data_start(&session);
+                let (first, last) = match MeasurementIter::new(file, HEADERLESS_STREAMS, start) {
+                    Ok(mut iter) => {
+                        let first = iter.first_line().and_then(|l| l.iter().min().copied());
+                        let last = iter.next().and_then(|l| l.into_iter().max());
+                        (first.map(|m| m.timestamp), last.map(|m| m.timestamp))
+                    }
+                    Err(e) => {
+                        warn!("Unreadable session file {:?}: {}", name, e);
+                        (None, None)
+                    }
+                };
+                Some(StoredSession {
+                    session,
+                    size,
+                    first,
+                    last,
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|s| (s.first.is_none(), s.first));
+        sessions
     }
 
-    /// Clear all stored measurements and discard the buffer.
-    pub fn clear_measurements(&self) -> anyhow::Result<()> {
+    /// The lines of a session not yet removed, oldest first.
+    pub fn iter_forward(&self, session: &Uuid) -> Option<ForwardMeasurementIter> {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush storage: {}", e);
+        }
+        let _guard = self.inner.lock().unwrap();
+        info!("Reading measurements of session {} from storage", session);
+        let file = File::open(session_file_path(session)).ok()?;
+        ForwardMeasurementIter::new(file, HEADERLESS_STREAMS, data_start(session)).ok()
+    }
+
+    /// Delete a session's measurements. The current session keeps an empty file
+    /// (header only) so recording carries on; any other session's file is removed.
+    /// The file goes before its sync state: a reset in between leaves a state that
+    /// `reconcile_sync_states` drops, rather than the whole session to sync again.
+    pub fn delete_session(&self, session: &Uuid) -> anyhow::Result<()> {
         let mut inner = self.inner.lock().unwrap();
-        inner.buffer.clear();
-        if let Err(e) = File::create(FILE_PATH) {
+        let path = session_file_path(session);
+        self.forget_stats(session);
+        if inner.current.as_ref() != Some(session) {
+            info!("Deleting {}", path);
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    error!("Failed to delete storage file: {}", e);
+                    Err(e.into())
+                }
+                _ => {
+                    SyncState::remove(session)?;
+                    Ok(ClockMark::remove(session)?)
+                }
+            };
+        }
+        Self::clear_buffer(&mut inner);
+        let header = FileHeader::new(*session, self.stream_count, self.mac);
+        if let Err(e) = File::create(&path)
+            .and_then(|mut file| file.write_all(&header.encode()))
+            .and_then(|()| SyncState::reset(session))
+            .and_then(|()| ClockMark::remove(session))
+        {
             error!("Failed to create storage file: {}", e);
             Err(e.into())
         } else {
             Ok(())
         }
     }
-    pub fn remove_last(&self, bytes_to_remove: usize) -> anyhow::Result<()> {
+
+    /// Check the lines of a session not yet removed for corrupt data, duplicate and
+    /// out-of-order records. With `repair`, a session with corrupt data is rewritten
+    /// without it; every valid record is kept. The lines of a pending batch are left as
+    /// they were sent, so only corrupt data behind them is removed.
+    pub fn scan_session(&self, session: &Uuid, repair: bool) -> anyhow::Result<ScanReport> {
         let mut inner = self.inner.lock().unwrap();
+        if inner.current.as_ref() == Some(session) && !inner.buffer.is_empty() {
+            self.flush_buffer(&mut inner)?;
+        }
+        let path = session_file_path(session);
+        let header_size = header_size(&path);
+        let start = data_start(session);
+        let lines =
+            |from| ForwardMeasurementIter::new(File::open(&path)?, HEADERLESS_STREAMS, from);
+        let mut report = repair::scan(lines(start)?, start, &mut std::io::sink())?;
+        if report.corrupt_bytes > 0 {
+            let e = StorageError::CorruptData(report.corrupt_bytes);
+            warn!("Session {}: {}", session, e);
+            inner.errors.add(e.kind());
+        }
+        if !repair || report.is_clean() {
+            return Ok(report);
+        }
+        let repair_from = SyncState::load(session)
+            .pending
+            .map_or(start, |batch| batch.end_offset.max(start));
+        if repair_from > start
+            && repair::scan(lines(repair_from)?, repair_from, &mut std::io::sink())?.is_clean()
+        {
+            return Ok(report);
+        }
+        info!("Repairing session {}", session);
+        let mut file = File::open(&path)?;
+        self.rewrite_session(session, header_size, Some(start..repair_from), |out| {
+            file.seek(SeekFrom::Start(start))?;
+            std::io::copy(&mut (&mut file).take(repair_from - start), out)?;
+            repair::scan(lines(repair_from)?, repair_from, out).map(|_| 0)
+        })?;
+        report.repaired = true;
+        Ok(report)
+    }
 
-        if !inner.buffer.is_empty() {
-            Self::flush_buffer(&mut inner)?;
+    /// Digest over a range of the session as `/sync` sends it, buffered records
+    /// flushed first.
+    pub fn digest(&self, request: &DigestRequest) -> anyhow::Result<RangeDigest> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.current.as_ref() == Some(&request.session) && !inner.buffer.is_empty() {
+            self.flush_buffer(&mut inner)?;
         }
+        Ok(digest_unsynced(
+            &request.session,
+            request.algorithm,
+            request.offset,
+            request.length,
+        )?)
+    }
 
-        let metadata = std::fs::metadata(FILE_PATH)?;
-        let current_size = metadata.len() as usize;
+    /// Record count, time range and corrupt bytes of a session, with the fill level of
+    /// the partition and how long recording at `interval` can go on. A session is
+    /// scanned when first asked about; its stats then follow every flush and synced
+    /// batch, and only a rewrite has it scanned again. `None` if the session has no
+    /// file.
+    pub fn stats(&self, session: &Uuid, interval: Duration) -> Option<StorageStats> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut cached = self.stats.lock().unwrap();
+        let mut stats = match cached.get(session) {
+            Some(stats) => *stats,
+            None => {
+                let file = File::open(session_file_path(session)).ok()?;
+                let start = data_start(session);
+                let lines = ForwardMeasurementIter::new(file, HEADERLESS_STREAMS, start).ok()?;
+                let stats = SessionStats::scan(lines, start);
+                cached.insert(*session, stats);
+                stats
+            }
+        };
+        drop(cached);
+        if inner.current.as_ref() == Some(session) {
+            stats.add(inner.buffer.records());
+        }
+        let usage = Self::query_usage(&mut inner, false);
+        let clock_jumps = ClockMark::load(session).len();
+        Some(StorageStats::new(
+            stats,
+            usage,
+            interval,
+            self.stream_count,
+            clock_jumps,
+        ))
+    }
 
-        if bytes_to_remove == 0 {
-            return Ok(());
+    /// Have the stats of a session scanned again when next asked for.
+    fn forget_stats(&self, session: &Uuid) {
+        self.stats.lock().unwrap().remove(session);
+    }
+
+    /// Take the lines of a committed batch off the cached stats of a session, whose
+    /// lines now start at `start`. See `SessionStats::remove_head`.
+    fn remove_stats(&self, session: &Uuid, removed: &SessionStats, start: u64) {
+        let mut cached = self.stats.lock().unwrap();
+        let Some(stats) = cached.get_mut(session) else {
+            return;
+        };
+        let first = File::open(session_file_path(session))
+            .and_then(|file| ForwardMeasurementIter::new(file, HEADERLESS_STREAMS, start))
+            .ok()
+            .and_then(|mut lines| lines.next())
+            .and_then(|line| line.measurements.first().map(|m| m.timestamp));
+        if !stats.remove_head(removed, first) {
+            cached.remove(session);
         }
+    }
+
+    /// Lock to read session files with outside of the manager, such as a `/sync`
+    /// download and its digest, while it leaves them alone.
+    pub fn files_lock(&self) -> FilesLock {
+        FilesLock(self.inner.clone())
+    }
+
+    /// Sync-commit protocol for the lines of `session`, see `SessionSync`.
+    pub fn session_sync(&self, session: &Uuid) -> SessionSync<'_> {
+        SessionSync {
+            storage: self,
+            session: *session,
+            removed: Cell::new(None),
+        }
+    }
+
+    /// Drop the lines of the pending batch of a session by moving its read pointer;
+    /// the file itself is not rewritten. Removing every line deletes the session.
+    /// `removed` are the stats of those lines, if known, to keep the cached ones.
+    fn commit_batch(&self, session: &Uuid, removed: Option<SessionStats>) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut state = SyncState::load(session);
+        let Some(batch) = state.pending.take() else {
+            return Err(anyhow::Error::msg("No sync batch pending"));
+        };
 
-        if bytes_to_remove >= current_size {
+        if inner.current.as_ref() == Some(session) && !inner.buffer.is_empty() {
+            self.flush_buffer(&mut inner)?;
+        }
+
+        let len = std::fs::metadata(session_file_path(session))?.len();
+        if batch.end_offset >= len {
             drop(inner);
-            self.clear_measurements()?;
-            return Ok(());
+            return self.delete_session(session);
+        }
+        let moved = state.read_pointer <= batch.end_offset;
+        state.read_pointer = state.read_pointer.max(batch.end_offset);
+        state.store(session)?;
+        match removed.filter(|_| moved) {
+            Some(removed) => self.remove_stats(session, &removed, batch.end_offset),
+            None => self.forget_stats(session),
         }
+        Ok(())
+    }
 
-        let new_size = current_size - bytes_to_remove;
-        let file = File::options().write(true).open(FILE_PATH)?;
-        file.set_len(new_size as u64)?;
+    /// Check the sync state of every session at boot. States of deleted sessions are
+    /// removed, and a pointer or pending batch that does not end on a line of its file,
+    /// as a reset during a rewrite or delete can leave, is dropped.
+    fn reconcile_sync_states(&self) {
+        for entry in std::fs::read_dir(SESSIONS_DIR)
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let name = entry.file_name();
+            let Some(session) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".pos"))
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            if let Err(e) = self.reconcile_sync_state(&session) {
+                warn!("Failed to check sync state of session {}: {:?}", session, e);
+            }
+        }
+    }
+
+    fn reconcile_sync_state(&self, session: &Uuid) -> anyhow::Result<()> {
+        let path = session_file_path(session);
+        let Ok(mut file) = File::open(&path) else {
+            info!("Removing sync state of deleted session {}", session);
+            return Ok(SyncState::remove(session)?);
+        };
+        let header_size = header_size(&path);
+        let xor_lines = FileHeader::xor_lines(read_header(&path).as_ref());
+        let stream_count = file_stream_count(&path);
+        let mut on_line = |offset: u64| {
+            capacity::line_boundary(&mut file, offset, stream_count, xor_lines)
+                .is_ok_and(|boundary| boundary == offset)
+        };
+        let loaded = SyncState::load(session);
+        let mut state = loaded;
+        if state.read_pointer > header_size && !on_line(state.read_pointer) {
+            warn!("Resetting read pointer of session {}", session);
+            state.read_pointer = 0;
+            state.pending = None;
+        }
+        if let Some(batch) = state.pending {
+            if batch.end_offset > state.read_pointer.max(header_size) && on_line(batch.end_offset) {
+                info!(
+                    "Batch {} of session {} was not acknowledged before the reset, \
+                     it is sent again under the same id",
+                    batch.id, session
+                );
+            } else {
+                warn!("Dropping batch {} of session {}", batch.id, session);
+                state.pending = None;
+            }
+        }
+        if state != loaded {
+            state.store(session)?;
+        }
         Ok(())
     }
 }
 
+/// The buffer of `StorageManager` with its session file and the journal.
+struct Buffered<'a> {
+    manager: &'a StorageManager,
+    inner: &'a mut StorageInner,
+}
+
+impl BufferStore for Buffered<'_> {
+    type Error = StorageError;
+
+    fn buffer(&mut self) -> &mut RecordBuffer {
+        &mut self.inner.buffer
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        self.manager.flush_buffer(self.inner)
+    }
+
+    fn journal(&mut self) {
+        self.manager.journal_buffer(self.inner)
+    }
+}
+
+/// Keeps the session files of a `StorageManager` from changing, for readers outside of
+/// it that have to see the same bytes twice.
+#[derive(Clone)]
+pub struct FilesLock(Arc<Mutex<StorageInner>>);
+
+/// No flush, rewrite or delete happens while this is held.
+pub struct FilesGuard<'a> {
+    _inner: MutexGuard<'a, StorageInner>,
+}
+
+impl FilesLock {
+    pub fn hold(&self) -> FilesGuard<'_> {
+        FilesGuard {
+            _inner: self.0.lock().unwrap(),
+        }
+    }
+}
+
+/// Removes synced lines from a session without losing or duplicating any across a
+/// reset. Each batch is persisted as pending before it is sent, and its lines are
+/// removed only once it is acknowledged. A batch still pending at the next sync is
+/// sent again with the same lines under the same id; rewrites of the session keep it
+/// pending or wait until it is committed.
+///
+/// Receivers have to dedup on the batch id (`X-Batch-Id` on uploads, the id in the
+/// header of BLE sync packets): a batch with the id of the last one they stored is a
+/// resend to acknowledge without storing it again. A receiver that does not will store
+/// the records of a batch interrupted after delivery twice. BLE packets of a range
+/// sync carry `RANGE_BATCH` instead of a batch id and must be stored without dedup.
+pub struct SessionSync<'a> {
+    storage: &'a StorageManager,
+    session: Uuid,
+    /// Stats of the lines of the batch begun last, taken off the cached stats of the
+    /// session once it is committed.
+    removed: Cell<Option<SessionStats>>,
+}
+
+impl SessionSync<'_> {
+    /// Where the next batch has to end, if one was left pending.
+    pub fn pending_end(&self) -> Option<u64> {
+        let _guard = self.storage.inner.lock().unwrap();
+        SyncState::load(&self.session)
+            .pending
+            .map(|batch| batch.end_offset)
+    }
+
+    /// Persist the batch of lines up to `end_offset` (see `ForwardLine`), holding
+    /// `records`, as pending. Returns the id to send it under.
+    pub fn begin(&self, end_offset: u64, records: &[Measurement]) -> anyhow::Result<u16> {
+        let _guard = self.storage.inner.lock().unwrap();
+        let mut removed = SessionStats {
+            data_bytes: end_offset.saturating_sub(data_start(&self.session)),
+            ..SessionStats::default()
+        };
+        removed.add(records);
+        self.removed.set(Some(removed));
+        let mut state = SyncState::load(&self.session);
+        let id = match state.pending {
+            Some(batch) if batch.end_offset == end_offset => batch.id,
+            _ => {
+                let id = match state.next_batch {
+                    RANGE_BATCH => 0,
+                    id => id,
+                };
+                state.next_batch = id + 1;
+                id
+            }
+        };
+        state.pending = Some(SyncBatch { id, end_offset });
+        state.store(&self.session)?;
+        Ok(id)
+    }
+
+    /// The pending batch was acknowledged: remove its lines.
+    pub fn commit(&self) -> anyhow::Result<()> {
+        self.storage
+            .commit_batch(&self.session, self.removed.take())
+    }
+}
+
 impl Drop for StorageManager {
     fn drop(&mut self) {
         // Flush any remaining buffered records before the manager is dropped
         let mut inner = self.inner.lock().unwrap();
         if !inner.buffer.is_empty() {
-            let _ = Self::flush_buffer(&mut inner);
+            let _ = self.flush_buffer(&mut inner);
         }
     }
 }
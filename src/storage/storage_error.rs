@@ -0,0 +1,87 @@
+use std::fmt;
+use std::io;
+
+/// Kinds of `StorageError`, numbered from 1 as reported over BLE. The number is also
+/// how many times the LED blinks for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StorageErrorKind {
+    Full = 1,
+    OpenFailed = 2,
+    WriteFailed = 3,
+    CorruptData = 4,
+    MountFailed = 5,
+}
+
+impl StorageErrorKind {
+    pub const ALL: [Self; 5] = [
+        Self::Full,
+        Self::OpenFailed,
+        Self::WriteFailed,
+        Self::CorruptData,
+        Self::MountFailed,
+    ];
+}
+
+/// Why measurements could not be stored.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The overflow policy could not make room.
+    Full,
+    /// The session file could not be opened, or no session is being recorded.
+    OpenFailed(io::Error),
+    WriteFailed(io::Error),
+    /// Bytes of a session file that are not valid lines.
+    CorruptData(u64),
+    /// littlefs could not be mounted, see `mount_recovery`.
+    MountFailed,
+}
+
+impl StorageError {
+    pub fn kind(&self) -> StorageErrorKind {
+        match self {
+            Self::Full => StorageErrorKind::Full,
+            Self::OpenFailed(_) => StorageErrorKind::OpenFailed,
+            Self::WriteFailed(_) => StorageErrorKind::WriteFailed,
+            Self::CorruptData(_) => StorageErrorKind::CorruptData,
+            Self::MountFailed => StorageErrorKind::MountFailed,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "Storage full"),
+            Self::OpenFailed(e) => write!(f, "Failed to open storage file: {}", e),
+            Self::WriteFailed(e) => write!(f, "Failed to write storage file: {}", e),
+            Self::CorruptData(bytes) => write!(f, "{} bytes of corrupt storage data", bytes),
+            Self::MountFailed => write!(f, "Storage could not be mounted"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::OpenFailed(e) | Self::WriteFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Storage errors since boot, per kind.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorCounts {
+    /// Indexed by kind number - 1.
+    pub counts: [u32; StorageErrorKind::ALL.len()],
+    pub last: Option<StorageErrorKind>,
+}
+
+impl ErrorCounts {
+    pub fn add(&mut self, kind: StorageErrorKind) {
+        let count = &mut self.counts[kind as usize - 1];
+        *count = count.saturating_add(1);
+        self.last = Some(kind);
+    }
+}
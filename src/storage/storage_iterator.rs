@@ -1,24 +1,62 @@
-use crate::sensor::measurement::Measurement;
-use crate::storage::storage_controller::START_BYTES;
+use crate::sensor::measurement::{Measurement, MAX_STREAMS};
+use crate::storage::file_header::FileHeader;
+use crate::storage::line_format::{self, LineRecords};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::sys::vTaskDelay;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 
-const MAX_LINE_MEASUREMENTS: usize = 10;
-const MEASUREMENT_SIZE: usize = 8; // u32 + u16 + u16
-const LINE_HEADER_SIZE: usize = 3; // 0xAB + 0xBA + count: u8
-const MIN_LINE_SIZE: usize = LINE_HEADER_SIZE + MEASUREMENT_SIZE + 1;
-const MAX_LINE_SIZE: usize = LINE_HEADER_SIZE + MAX_LINE_MEASUREMENTS * MEASUREMENT_SIZE + 1;
 const BUF_CAPACITY: usize = 4096;
-#[derive(Debug, Clone)]
-pub struct MeasurementLine {
-    pub measurements: Vec<Measurement>,
-    /// Bytes from end of file to the start of this line.
-    /// Truncate file to `file_len - offset_from_end` to discard
-    /// this line and everything after it (including skipped corruption).
-    pub offset_from_end: u64,
+
+/// Let other tasks run while walking a long file. The format code also builds for
+/// host tools, where there is nothing to yield to.
+fn yield_task() {
+    #[cfg(target_os = "espidf")]
+    unsafe {
+        vTaskDelay(1);
+    }
+}
+
+/// Header of `file` and the stream count its lines are parsed with: the one recorded
+/// in the header, or `stream_count` for a headerless file.
+fn read_header(
+    file: &mut (impl Read + Seek),
+    stream_count: usize,
+) -> std::io::Result<(Option<FileHeader>, usize)> {
+    let header = FileHeader::read(file)?;
+    let stream_count = header.as_ref().map_or(stream_count, |header| {
+        (header.stream_count as usize).min(MAX_STREAMS)
+    });
+    Ok((header, stream_count))
+}
+
+/// Both the legacy and the compact line format are accepted; see `line_format`.
+fn parse_line(slice: &[u8], stream_count: usize, xor_lines: bool) -> Option<Vec<Measurement>> {
+    Some(measurements(
+        line_format::decode_line(slice, stream_count, xor_lines)?,
+        stream_count,
+    ))
+}
+
+/// Encode `records` as one line, flags included; see `line_format::encode_line`.
+pub fn encode_measurements(records: &[Measurement], stream_count: usize) -> Vec<u8> {
+    let pairs: Vec<(u32, &[u16])> = records.iter().map(|m| (m.timestamp, m.values())).collect();
+    let flags: Vec<u8> = records.iter().map(|m| m.flags).collect();
+    line_format::encode_line(&pairs, &flags, stream_count)
+}
+
+fn measurements(records: LineRecords, stream_count: usize) -> Vec<Measurement> {
+    records
+        .iter(stream_count)
+        .map(|(timestamp, values, flags)| {
+            let mut measurement = Measurement::new(values, timestamp);
+            measurement.flags = flags;
+            measurement
+        })
+        .collect()
 }
 
+/// Walks the lines of a measurement file from the newest back to the oldest.
 pub struct MeasurementIter {
     file: File,
     buf: Vec<u8>,
@@ -27,12 +65,26 @@ pub struct MeasurementIter {
     /// Points at the end of the next line to parse (moves left)
     cursor: u64,
     done: bool,
+    /// Offset of the first line: the size of the file header (0 for headerless files),
+    /// or the read pointer if lines were removed from the head
+    data_start: u64,
+    stream_count: usize,
+    /// Accept the XOR-checked lines of older firmware
+    xor_lines: bool,
+    min_line_size: usize,
+    max_line_size: usize,
 }
 
 impl MeasurementIter {
-    pub fn new(mut file: File) -> std::io::Result<Self> {
+    /// Files with a header are parsed with the stream count recorded there;
+    /// `stream_count` only applies to headerless files. Lines before `start` (a read
+    /// pointer, 0 for none) are left out.
+    pub fn new(mut file: File, stream_count: usize, start: u64) -> std::io::Result<Self> {
         let file_len = file.metadata()?.len();
-        let read_size = (file_len as usize).min(BUF_CAPACITY);
+        let (header, stream_count) = read_header(&mut file, stream_count)?;
+        let xor_lines = FileHeader::xor_lines(header.as_ref());
+        let data_start = header.map_or(0, |h| h.size as u64).max(start).min(file_len);
+        let read_size = ((file_len - data_start) as usize).min(BUF_CAPACITY);
         let start = file_len - read_size as u64;
 
         file.seek(SeekFrom::Start(start))?;
@@ -46,12 +98,17 @@ impl MeasurementIter {
             file_len,
             cursor: file_len,
             done: false,
+            data_start,
+            stream_count,
+            xor_lines,
+            min_line_size: line_format::min_line_size(stream_count),
+            max_line_size: line_format::max_line_size(stream_count),
         })
     }
 
     /// Extend buffer leftward to cover earlier file data.
     fn extend_left(&mut self) -> bool {
-        if self.buf_file_start == 0 {
+        if self.buf_file_start <= self.data_start {
             return false;
         }
 
@@ -59,7 +116,7 @@ impl MeasurementIter {
         let keep = (self.cursor - self.buf_file_start) as usize;
         self.buf.truncate(keep);
 
-        let read_size = (self.buf_file_start as usize).min(BUF_CAPACITY);
+        let read_size = ((self.buf_file_start - self.data_start) as usize).min(BUF_CAPACITY);
         let new_start = self.buf_file_start - read_size as u64;
 
         if self.file.seek(SeekFrom::Start(new_start)).is_err() {
@@ -77,79 +134,42 @@ impl MeasurementIter {
         true
     }
 
+    /// The oldest line of the file, read forward from the start. `None` if it is
+    /// damaged or the file holds no lines. Does not move the backward cursor.
+    pub fn first_line(&mut self) -> Option<Vec<Measurement>> {
+        self.file.seek(SeekFrom::Start(self.data_start)).ok()?;
+        let mut line = Vec::with_capacity(self.max_line_size);
+        (&mut self.file)
+            .take(self.max_line_size as u64)
+            .read_to_end(&mut line)
+            .ok()?;
+        let len = line_format::line_len(&line, self.stream_count, self.xor_lines)?;
+        parse_line(line.get(..len)?, self.stream_count, self.xor_lines)
+    }
+
     /// Try parsing a line that starts at `buf_pos` and ends exactly at cursor.
     fn try_parse_at(&self, buf_pos: usize, end: usize) -> Option<Vec<Measurement>> {
-        let slice = &self.buf[buf_pos..end];
-        let len = slice.len();
-
-        if len < MIN_LINE_SIZE {
-            log::warn!("Skipping line with too few bytes: {}", len);
-            return None;
-        }
-        if slice[0] != START_BYTES[0] || slice[1] != START_BYTES[1] {
-            //log::warn!("Skipping line with invalid start bytes: {:02x}{:02x}", slice[0], slice[1]);
-            return None;
-        }
-
-        let count = slice[2] as usize;
-        if count == 0 || count > MAX_LINE_MEASUREMENTS {
-            log::warn!("Skipping line with invalid measurement count: {}", count);
-            return None;
-        }
-
-        let expected = LINE_HEADER_SIZE + count * MEASUREMENT_SIZE + 1;
-        if len != expected {
-            log::warn!("Skipping line with invalid length: {}", len);
-            return None;
-        }
-
-        let stored_checksum = slice[len - 1];
-        let mut checksum: u8 = 0;
-        for &b in &slice[..len - 1] {
-            checksum ^= b;
-        }
-        if checksum != stored_checksum {
-            log::warn!(
-                "Skipping line with invalid checksum: {:02x}",
-                stored_checksum
-            );
-            return None;
-        }
-
-        let data = &slice[LINE_HEADER_SIZE..];
-        let mut measurements = Vec::with_capacity(count);
-        for i in 0..count {
-            let o = i * MEASUREMENT_SIZE;
-            measurements.push(Measurement {
-                timestamp: u32::from_le_bytes(data[o..o + 4].try_into().unwrap()),
-                pm1_0_avg: u16::from_le_bytes(data[o + 4..o + 6].try_into().unwrap()),
-                pm2_5_avg: u16::from_le_bytes(data[o + 6..o + 8].try_into().unwrap()),
-            });
-        }
-
-        Some(measurements)
+        parse_line(&self.buf[buf_pos..end], self.stream_count, self.xor_lines)
     }
 }
 
 impl Iterator for MeasurementIter {
-    type Item = MeasurementLine;
+    type Item = Vec<Measurement>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.done || self.cursor == 0 {
+        if self.done || self.cursor <= self.data_start {
             self.done = true;
             return None;
         }
 
         loop {
-            unsafe {
-                vTaskDelay(1);
-            }
+            yield_task();
             let cursor_in_buf = (self.cursor - self.buf_file_start) as usize;
 
-            // Ensure full MAX_LINE_SIZE window is in buffer before scanning,
+            // Ensure a full max-line-size window is in buffer before scanning,
             // otherwise a block straddling the left edge gets missed and the
             // resync byte-walk skips past it.
-            if cursor_in_buf < MAX_LINE_SIZE && self.buf_file_start > 0 {
+            if cursor_in_buf < self.max_line_size && self.buf_file_start > self.data_start {
                 if !self.extend_left() {
                     self.done = true;
                     return None;
@@ -157,23 +177,20 @@ impl Iterator for MeasurementIter {
                 continue;
             }
 
-            let scan_lo = cursor_in_buf.saturating_sub(MAX_LINE_SIZE);
-            let scan_hi = cursor_in_buf.saturating_sub(MIN_LINE_SIZE);
+            let scan_lo = cursor_in_buf.saturating_sub(self.max_line_size);
+            let scan_hi = cursor_in_buf.saturating_sub(self.min_line_size);
 
             if scan_lo < cursor_in_buf {
                 for pos in (scan_lo..=scan_hi).rev() {
                     if let Some(measurements) = self.try_parse_at(pos, cursor_in_buf) {
                         self.cursor = self.buf_file_start + pos as u64;
-                        return Some(MeasurementLine {
-                            measurements,
-                            offset_from_end: self.file_len - self.cursor,
-                        });
+                        return Some(measurements);
                     }
                 }
 
                 // Resync: step back 1 byte
                 self.cursor -= 1;
-                if self.cursor == 0 {
+                if self.cursor <= self.data_start {
                     self.done = true;
                     return None;
                 }
@@ -188,3 +205,213 @@ impl Iterator for MeasurementIter {
         }
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct ForwardLine {
+    pub measurements: Vec<Measurement>,
+    /// Offset of the first byte of this line.
+    pub start_offset: u64,
+    /// Offset just past this line. A sync batch ending here (see `SessionSync`)
+    /// removes this line and everything before it (including skipped corruption).
+    pub end_offset: u64,
+}
+
+/// Walks the lines of a measurement file from the oldest to the newest, with the same
+/// byte-by-byte resync over damaged data as `MeasurementIter`.
+pub struct ForwardMeasurementIter<R = File> {
+    file: R,
+    buf: Vec<u8>,
+    /// File offset of `buf[0]`
+    buf_file_start: u64,
+    /// Next byte of `buf` to parse
+    pos: usize,
+    eof: bool,
+    file_len: u64,
+    /// Offset of the first line, as for `MeasurementIter`
+    data_start: u64,
+    header: Option<FileHeader>,
+    stream_count: usize,
+    xor_lines: bool,
+    max_line_size: usize,
+}
+
+impl<R: Read + Seek> ForwardMeasurementIter<R> {
+    /// See `MeasurementIter::new`.
+    pub fn new(mut file: R, stream_count: usize, start: u64) -> std::io::Result<Self> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let (header, stream_count) = read_header(&mut file, stream_count)?;
+        let data_start = header
+            .as_ref()
+            .map_or(0, |h| h.size as u64)
+            .max(start)
+            .min(file_len);
+        file.seek(SeekFrom::Start(data_start))?;
+        Ok(Self {
+            file,
+            buf: Vec::with_capacity(BUF_CAPACITY),
+            buf_file_start: data_start,
+            pos: 0,
+            eof: false,
+            file_len,
+            data_start,
+            xor_lines: FileHeader::xor_lines(header.as_ref()),
+            header,
+            stream_count,
+            max_line_size: line_format::max_line_size(stream_count),
+        })
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.stream_count
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    pub fn header(&self) -> Option<&FileHeader> {
+        self.header.as_ref()
+    }
+
+    /// The lines holding records in `[from, to)`, with the records outside it left out.
+    /// The first line is found by bisecting the file on line timestamps, so a range at
+    /// the end of a long session reads a few kilobytes instead of the whole file. This
+    /// assumes timestamps grow through the file: records from before a clock was set
+    /// back can be missed.
+    pub fn time_range(mut self, from: u32, to: u32) -> TimeRangeIter<R> {
+        let start = self.bisect(from);
+        if !self.reposition(start) {
+            self.eof = true;
+        }
+        TimeRangeIter {
+            lines: self,
+            start_offset: start,
+            from,
+            to,
+        }
+    }
+
+    /// Offset from which a forward walk finds every line with a record at or after
+    /// `from`: the end of the last line seen to hold only older records.
+    fn bisect(&mut self, from: u32) -> u64 {
+        let (mut lo, mut hi) = (self.data_start, self.file_len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if !self.reposition(mid) {
+                return self.data_start;
+            }
+            match self.next() {
+                Some(line)
+                    if line.start_offset < hi
+                        && line.measurements.iter().all(|m| m.timestamp < from) =>
+                {
+                    lo = line.end_offset
+                }
+                _ => hi = mid,
+            }
+        }
+        lo
+    }
+
+    /// Continue from `offset`, resyncing on the next line if it falls inside one.
+    fn reposition(&mut self, offset: u64) -> bool {
+        self.buf.clear();
+        self.buf_file_start = offset;
+        self.pos = 0;
+        self.eof = false;
+        self.file.seek(SeekFrom::Start(offset)).is_ok()
+    }
+
+    /// Drop the parsed part of the buffer and append the next chunk of the file.
+    fn refill(&mut self) -> bool {
+        self.buf.drain(..self.pos);
+        self.buf_file_start += self.pos as u64;
+        self.pos = 0;
+
+        let old_len = self.buf.len();
+        self.buf.resize(old_len + BUF_CAPACITY, 0);
+        match self.file.read(&mut self.buf[old_len..]) {
+            Ok(n) => {
+                self.buf.truncate(old_len + n);
+                n > 0
+            }
+            Err(e) => {
+                log::warn!("Failed to read storage file: {}", e);
+                self.buf.truncate(old_len);
+                false
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for ForwardMeasurementIter<R> {
+    type Item = ForwardLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((offset, len, records)) =
+                line_format::next_line(&self.buf[self.pos..], self.stream_count, self.xor_lines)
+            {
+                let start_offset = self.buf_file_start + (self.pos + offset) as u64;
+                self.pos += offset + len;
+                return Some(ForwardLine {
+                    measurements: measurements(records, self.stream_count),
+                    start_offset,
+                    end_offset: self.buf_file_start + self.pos as u64,
+                });
+            }
+            if self.eof {
+                return None;
+            }
+            // Nothing complete left; keep what may be the start of a line cut by the
+            // end of the buffer.
+            self.pos = self
+                .pos
+                .max(self.buf.len().saturating_sub(self.max_line_size));
+            yield_task();
+            self.eof = !self.refill();
+        }
+    }
+}
+
+/// Lines of a session within a time range, see `ForwardMeasurementIter::time_range`.
+pub struct TimeRangeIter<R = File> {
+    lines: ForwardMeasurementIter<R>,
+    start_offset: u64,
+    from: u32,
+    to: u32,
+}
+
+impl<R: Read + Seek> TimeRangeIter<R> {
+    pub fn stream_count(&self) -> usize {
+        self.lines.stream_count()
+    }
+
+    /// Bytes from the first line of the range to the end of the file, an upper bound
+    /// on what the range holds.
+    pub fn span(&self) -> u64 {
+        self.lines.file_len().saturating_sub(self.start_offset)
+    }
+}
+
+impl<R: Read + Seek> Iterator for TimeRangeIter<R> {
+    type Item = ForwardLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut line = self.lines.next()?;
+            if line
+                .measurements
+                .first()
+                .is_some_and(|m| m.timestamp >= self.to)
+            {
+                return None;
+            }
+            line.measurements
+                .retain(|m| (self.from..self.to).contains(&m.timestamp));
+            if !line.measurements.is_empty() {
+                return Some(line);
+            }
+        }
+    }
+}
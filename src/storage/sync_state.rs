@@ -0,0 +1,141 @@
+use crate::storage::line_format::crc16;
+use crate::storage::storage_controller::SESSIONS_DIR;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::ops::Range;
+use uuid::Uuid;
+
+/// u64 read pointer + u16 next batch id + u16 pending batch id + u64 pending batch end
+/// (0 for none) + u16 CRC-16, all LE
+const STATE_SIZE: usize = 22;
+/// Written before batches existed: u64 read pointer + u8 XOR checksum
+const READ_POINTER_SIZE: usize = 9;
+
+/// Batch id of the BLE sync packets of a range sync, which are sent outside any
+/// `SyncBatch` and remove nothing: receivers store them without dedup. Never given to a
+/// batch of a session.
+pub const RANGE_BATCH: u16 = u16::MAX;
+
+/// Lines handed to the app or server under `id`, removed from the session up to
+/// `end_offset` once acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncBatch {
+    pub id: u16,
+    pub end_offset: u64,
+}
+
+/// How far a session has been synced, kept in a sidecar next to its file. The sidecar
+/// is replaced whole and littlefs commits a file only when it is closed, so a reset
+/// leaves either the old or the new state, never a mix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncState {
+    /// Offset of the first line not yet removed, 0 if none were. Moving it is how
+    /// synced lines are dropped from the head without a rewrite.
+    pub read_pointer: u64,
+    /// Id for the next batch. Ids are not reused within a session, so a receiver
+    /// seeing the id of the last batch it stored again knows it is a resend. They wrap
+    /// around before `RANGE_BATCH`.
+    pub next_batch: u16,
+    /// Persisted before the batch is sent, cleared once it is acknowledged.
+    pub pending: Option<SyncBatch>,
+}
+
+fn state_path(session: &Uuid) -> String {
+    format!("{}/{}.pos", SESSIONS_DIR, session.simple())
+}
+
+impl SyncState {
+    /// The state of `session`; the default if it has none or it is damaged.
+    pub fn load(session: &Uuid) -> Self {
+        let mut bytes = Vec::with_capacity(STATE_SIZE);
+        if File::open(state_path(session))
+            .and_then(|file| file.take(STATE_SIZE as u64).read_to_end(&mut bytes))
+            .is_err()
+        {
+            return Self::default();
+        }
+        Self::decode(&bytes).unwrap_or_default()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        match bytes.len() {
+            READ_POINTER_SIZE if bytes[..8].iter().fold(0, |acc, &b| acc ^ b) == bytes[8] => {
+                Some(Self {
+                    read_pointer: u64_at(0),
+                    ..Self::default()
+                })
+            }
+            STATE_SIZE if crc16(&bytes[..STATE_SIZE - 2]) == u16_at(STATE_SIZE - 2) => {
+                let end_offset = u64_at(12);
+                Some(Self {
+                    read_pointer: u64_at(0),
+                    next_batch: u16_at(8),
+                    pending: (end_offset != 0).then(|| SyncBatch {
+                        id: u16_at(10),
+                        end_offset,
+                    }),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> [u8; STATE_SIZE] {
+        let mut bytes = [0u8; STATE_SIZE];
+        bytes[..8].copy_from_slice(&self.read_pointer.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.next_batch.to_le_bytes());
+        if let Some(pending) = self.pending {
+            bytes[10..12].copy_from_slice(&pending.id.to_le_bytes());
+            bytes[12..20].copy_from_slice(&pending.end_offset.to_le_bytes());
+        }
+        let crc = crc16(&bytes[..STATE_SIZE - 2]);
+        bytes[STATE_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    pub fn store(&self, session: &Uuid) -> std::io::Result<()> {
+        File::create(state_path(session))?.write_all(&self.encode())
+    }
+
+    /// Forget the state of a session whose file is gone.
+    pub fn remove(session: &Uuid) -> std::io::Result<()> {
+        match std::fs::remove_file(state_path(session)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start over from the head of an emptied file, keeping the batch ids going.
+    pub fn reset(session: &Uuid) -> std::io::Result<()> {
+        Self::rewritten(session, None, 0)
+    }
+
+    /// Start over from the head of a rewritten file, keeping the batch ids going. If
+    /// the lines in `kept` were copied unchanged to just behind a `header_size` header
+    /// and the pending batch starts and ends within them, it stays pending at its new
+    /// offset.
+    pub fn rewritten(
+        session: &Uuid,
+        kept: Option<Range<u64>>,
+        header_size: u64,
+    ) -> std::io::Result<()> {
+        let state = Self::load(session);
+        let pending = state.pending.zip(kept).and_then(|(pending, kept)| {
+            (kept.start == state.read_pointer.max(header_size)
+                && pending.end_offset > kept.start
+                && pending.end_offset <= kept.end)
+                .then(|| SyncBatch {
+                    id: pending.id,
+                    end_offset: pending.end_offset - kept.start + header_size,
+                })
+        });
+        Self {
+            read_pointer: 0,
+            next_batch: state.next_batch,
+            pending,
+        }
+        .store(session)
+    }
+}
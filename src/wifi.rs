@@ -1 +1,2 @@
+pub mod upload_payload;
 pub mod wifi_manager;
@@ -0,0 +1,69 @@
+use crate::sensor::measurement::{Measurement, FLAG_MAX, FLAG_MIN};
+
+/// The format `/api/v3/fixed_sessions/.../measurements` parses. It stays XOR-checked
+/// until the server accepts another one; CRC-16 only protects the storage lines.
+const MAGIC: &[u8; 2] = &[0xAB, 0xBA];
+
+/// Body of a fixed-session measurements POST: one (timestamp, sensor index, value)
+/// entry per stream of every record. Streams the app did not assign a server index to
+/// are left out. An index past the record's streams names the sensor its quality flags
+/// go to, as one more entry for records that have any. The minimum and maximum records
+/// of aggregated data are left out, the server has no place for them. `None` if the
+/// entries do not fit the u16 count.
+pub fn encode(measurements: &[Measurement], stream_indices: &[u8]) -> Option<Vec<u8>> {
+    let flags_index = |m: &Measurement| {
+        stream_indices
+            .get(m.values().len())
+            .filter(|_| m.flags != 0)
+            .copied()
+    };
+    let uploaded = |m: &&Measurement| m.flags & (FLAG_MIN | FLAG_MAX) == 0;
+    let entries: usize = measurements
+        .iter()
+        .filter(uploaded)
+        .map(|m| m.values().len().min(stream_indices.len()) + flags_index(m).is_some() as usize)
+        .sum();
+
+    if entries > u16::MAX as usize {
+        return None;
+    }
+    let count = entries as u16;
+
+    // 0xAB + 0xBA + u16 + N * (u32 + u8 + float) + u8
+    let capacity = 2 + 2 + entries * 9 + 1;
+    let mut buffer = Vec::with_capacity(capacity);
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&count.to_be_bytes());
+    for m in measurements.iter().filter(uploaded) {
+        for (value, index) in m.values().iter().zip(stream_indices) {
+            buffer.extend_from_slice(&m.timestamp.to_be_bytes());
+            buffer.push(*index);
+            buffer.extend_from_slice(&f32::from(*value).to_be_bytes());
+        }
+        if let Some(index) = flags_index(m) {
+            buffer.extend_from_slice(&m.timestamp.to_be_bytes());
+            buffer.push(index);
+            buffer.extend_from_slice(&f32::from(m.flags).to_be_bytes());
+        }
+    }
+    let checksum = buffer.iter().fold(0u8, |acc, &b| acc ^ b);
+    buffer.push(checksum);
+    Some(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_is_the_xor_checked_format_the_server_parses() {
+        let payload = encode(&[Measurement::new(&[3, 7], 0x0102_0304)], &[1, 2]).unwrap();
+        let mut expected = vec![0xAB, 0xBA, 0x00, 0x02];
+        for (index, value) in [(1u8, 3.0f32), (2, 7.0)] {
+            expected.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, index]);
+            expected.extend_from_slice(&value.to_be_bytes());
+        }
+        expected.push(expected.iter().fold(0, |acc, &b| acc ^ b));
+        assert_eq!(payload, expected);
+    }
+}
@@ -1,6 +1,10 @@
 use crate::sensor::measurement::Measurement;
+use crate::storage::digest::DigestAlgorithm;
+use crate::storage::event_log::{self, EventKind};
+use crate::storage::mount_recovery;
 use crate::storage::session_config::{SessionConfig, SessionType};
-use crate::storage::storage_controller::FILE_PATH;
+use crate::storage::storage_controller::{digest_unsynced, open_unsynced, FilesLock};
+use crate::wifi::upload_payload;
 use crate::{LoopEvent, SendingError};
 use embedded_svc::http::Method;
 use embedded_svc::io::Write;
@@ -14,23 +18,24 @@ use esp_idf_svc::http::client::EspHttpConnection;
 use esp_idf_svc::sys::{
     esp_err_t, esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_random,
     heap_caps_get_largest_free_block, http_method_HTTP_GET, httpd_config_t, httpd_handle_t,
-    httpd_register_uri_handler, httpd_req_t, httpd_req_to_sockfd, httpd_resp_send_chunk,
-    httpd_resp_set_hdr, httpd_resp_set_type, httpd_start, httpd_stop, httpd_uri_t, linger,
-    lwip_setsockopt, socklen_t, ESP_FAIL, ESP_OK, MALLOC_CAP_8BIT, MALLOC_CAP_INTERNAL, SOL_SOCKET,
-    SO_LINGER,
+    httpd_query_key_value, httpd_register_uri_handler, httpd_req_get_url_query_len,
+    httpd_req_get_url_query_str, httpd_req_t, httpd_req_to_sockfd, httpd_resp_send,
+    httpd_resp_send_404, httpd_resp_send_chunk, httpd_resp_set_hdr, httpd_resp_set_type,
+    httpd_start, httpd_stop, httpd_uri_t, linger, lwip_setsockopt, socklen_t, ESP_FAIL, ESP_OK,
+    MALLOC_CAP_8BIT, MALLOC_CAP_INTERNAL, SOL_SOCKET, SO_LINGER,
 };
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use log::{error, info};
-use std::ffi::{c_int, c_void, CString};
-use std::fs::File;
+use std::ffi::{c_int, c_void, CStr, CString};
 use std::io::{BufReader, Read};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::time::Duration;
 use uuid::Uuid;
 
 const CHUNK_SIZE: usize = 4096;
-const MAGIC: &[u8; 2] = &[0xAB, 0xBA];
+type HttpdHandler = unsafe extern "C" fn(*mut httpd_req_t) -> esp_err_t;
 // Default 5s send_wait_timeout trips when the phone reads the body slowly under
 // BLE/Wi-Fi coex. 30s eats real-world stalls.
 const HTTPD_TIMEOUT_SECS: u16 = 30;
@@ -42,7 +47,10 @@ pub enum SyncStatus {
 }
 
 struct SyncHandlerCtx {
-    file_path: CString,
+    /// Served when the request has no `?session=<uuid>` selector.
+    default_session: Option<Uuid>,
+    /// Held from the digest through the last chunk, so the body is what was digested.
+    files: FilesLock,
     tx: Sender<SyncStatus>,
 }
 
@@ -62,6 +70,8 @@ impl Drop for SyncServer {
 pub struct WifiManager {
     wifi: Mutex<BlockingWifi<EspWifi<'static>>>,
     sync_server: Mutex<Option<SyncServer>>,
+    /// The last upload failed; only the first failure of a run goes to the event log.
+    upload_failing: AtomicBool,
 }
 
 impl WifiManager {
@@ -69,6 +79,7 @@ impl WifiManager {
         Self {
             wifi: Mutex::new(wifi),
             sync_server: Mutex::new(None),
+            upload_failing: AtomicBool::new(false),
         }
     }
 
@@ -78,7 +89,17 @@ impl WifiManager {
     /// Uses the raw esp-idf httpd FFI so the recv/send wait timeouts can be
     /// bumped from the crate-hardcoded 5s — `esp-idf-svc::http::server::Configuration`
     /// does not expose those fields.
-    pub fn manual_sync(&self) -> anyhow::Result<Receiver<SyncStatus>> {
+    ///
+    /// `GET /sync?session=<uuid>` serves the file of any stored session; plain `/sync`
+    /// serves `default_session`. `GET /events` serves the event log, `GET /salvage` what
+    /// was salvaged from a storage partition that failed to mount. A `/sync` download
+    /// holds `files` until it is sent, holding off flushes and other changes to the
+    /// session files.
+    pub fn manual_sync(
+        &self,
+        default_session: Option<Uuid>,
+        files: FilesLock,
+    ) -> anyhow::Result<Receiver<SyncStatus>> {
         if let Some(mut wifi) = self.wifi.try_lock() {
             let ssid = "AirBeamMini Sync";
             let n = unsafe { esp_random() } % 100_000_000;
@@ -105,7 +126,8 @@ impl WifiManager {
 
             let (tx, rx) = std::sync::mpsc::channel();
             let ctx = Box::into_raw(Box::new(SyncHandlerCtx {
-                file_path: CString::new(FILE_PATH)?,
+                default_session,
+                files,
                 tx: tx.clone(),
             }));
 
@@ -146,26 +168,32 @@ impl WifiManager {
                 )));
             }
 
-            let uri = c"/sync";
-            let uri_handler = httpd_uri_t {
-                uri: uri.as_ptr(),
-                method: http_method_HTTP_GET,
-                handler: Some(sync_get_handler),
-                user_ctx: ctx as *mut c_void,
-            };
-            info!("manual_sync: register_uri_handler");
-            let reg_res = unsafe { httpd_register_uri_handler(handle, &uri_handler) };
-            info!("manual_sync: register_uri_handler ret={}", reg_res);
-            if reg_res != ESP_OK {
-                error!("manual_sync: register_uri_handler FAILED: {}", reg_res);
-                unsafe {
-                    let _ = httpd_stop(handle);
-                    drop(Box::from_raw(ctx));
+            let handlers: [(&CStr, HttpdHandler); 3] = [
+                (c"/sync", sync_get_handler),
+                (c"/events", events_get_handler),
+                (c"/salvage", salvage_get_handler),
+            ];
+            for (uri, handler) in handlers {
+                let uri_handler = httpd_uri_t {
+                    uri: uri.as_ptr(),
+                    method: http_method_HTTP_GET,
+                    handler: Some(handler),
+                    user_ctx: ctx as *mut c_void,
+                };
+                info!("manual_sync: register_uri_handler {:?}", uri);
+                let reg_res = unsafe { httpd_register_uri_handler(handle, &uri_handler) };
+                info!("manual_sync: register_uri_handler ret={}", reg_res);
+                if reg_res != ESP_OK {
+                    error!("manual_sync: register_uri_handler FAILED: {}", reg_res);
+                    unsafe {
+                        let _ = httpd_stop(handle);
+                        drop(Box::from_raw(ctx));
+                    }
+                    return Err(anyhow::Error::msg(format!(
+                        "httpd_register_uri_handler failed: {}",
+                        reg_res
+                    )));
                 }
-                return Err(anyhow::Error::msg(format!(
-                    "httpd_register_uri_handler failed: {}",
-                    reg_res
-                )));
             }
             log_heap("after httpd_start success");
 
@@ -263,9 +291,13 @@ impl WifiManager {
         Ok(())
     }
 
+    /// `batch` is the id of a batch synced from storage, sent as `X-Batch-Id`. The server
+    /// must not store a batch again that has the id of the last one it stored, see
+    /// `SessionSync`.
     pub fn send_measurements(
         &self,
         measurements: &[Measurement],
+        batch: Option<u16>,
         domain: &str,
         config: SessionConfig,
         event_tx: Sender<LoopEvent>,
@@ -277,8 +309,7 @@ impl WifiManager {
             return Err(SendingError::ConnectionError);
         }
         let SessionType::FIXED {
-            pm1_index,
-            pm2_5_index,
+            stream_indices,
             token,
             wifi_ssid: _,
             wifi_password: _,
@@ -286,7 +317,8 @@ impl WifiManager {
         else {
             panic!("Config error, expected fixed session")
         };
-        let payload = self.encode_measurements(measurements, pm1_index, pm2_5_index)?;
+        let payload =
+            upload_payload::encode(measurements, &stream_indices).ok_or(SendingError::Overflow)?;
         use esp_idf_svc::http::client::Configuration as HttpConfiguration;
 
         let http_config = &HttpConfiguration {
@@ -298,11 +330,16 @@ impl WifiManager {
         );
         let content_len_header = format!("{}", payload.len());
 
-        let headers = [
+        let authorization = format!("Bearer {:032x}", token);
+        let batch_header = batch.map(|batch| batch.to_string());
+        let mut headers = vec![
             ("Content-Type", "application/octet-stream"),
             ("Content-Length", &content_len_header),
-            ("Authorization", &format!("Bearer {:032x}", token)),
+            ("Authorization", &authorization),
         ];
+        if let Some(batch) = &batch_header {
+            headers.push(("X-Batch-Id", batch));
+        }
 
         let url = format!(
             "https://{}/api/v3/fixed_sessions/{}/measurements",
@@ -310,12 +347,12 @@ impl WifiManager {
         );
         let mut request = client
             .request(Method::Post, &url, &headers)
-            .map_err(|_| SendingError::Retry)?;
+            .map_err(|_| self.upload_failed(0))?;
         request
             .write_all(&payload)
             .map_err(|_| SendingError::Overflow)?;
-        request.flush().map_err(|_| SendingError::Retry)?;
-        let response = request.submit().map_err(|_| SendingError::Retry)?;
+        request.flush().map_err(|_| self.upload_failed(0))?;
+        let response = request.submit().map_err(|_| self.upload_failed(0))?;
         let status = response.status();
 
         if let Some(epoch) = response.header("X-Server-Time") {
@@ -331,12 +368,24 @@ impl WifiManager {
         );
 
         if !(200..300).contains(&(status as i32)) {
+            self.upload_failed(status);
             return Err(SendingError::ConfigError);
         }
+        self.upload_failing.store(false, Ordering::Relaxed);
 
         Ok(())
     }
 
+    /// Record the first of a run of failed uploads, `status` 0 if no response came
+    /// back, so an outage shows up in the event log once rather than per measurement.
+    /// Returns the error for a failed request.
+    fn upload_failed(&self, status: u16) -> SendingError {
+        if !self.upload_failing.swap(true, Ordering::Relaxed) {
+            event_log::record(EventKind::UploadFailed, status as u32);
+        }
+        SendingError::Retry
+    }
+
     pub fn disconnect(&self) {
         if let Some(mut wifi) = self.wifi.try_lock() {
             let _ = wifi.disconnect();
@@ -351,37 +400,6 @@ impl WifiManager {
             false
         }
     }
-
-    fn encode_measurements(
-        &self,
-        measurements: &[Measurement],
-        pm1_index: u8,
-        pm2_5_index: u8,
-    ) -> Result<Vec<u8>, SendingError> {
-        let count = measurements.len();
-
-        if count > u16::MAX as usize {
-            return Err(SendingError::Overflow);
-        }
-        let count = (count * 2) as u16;
-
-        // 0xAB + 0xBA + u16 + N * 2 * (u32 + u8 + float) + u8
-        let capacity = 2 + 2 + count * 18 + 1;
-        let mut buffer = Vec::with_capacity(capacity as usize);
-        buffer.extend_from_slice(MAGIC);
-        buffer.extend_from_slice(&count.to_be_bytes());
-        for m in measurements {
-            buffer.extend_from_slice(&m.timestamp.to_be_bytes());
-            buffer.push(pm1_index);
-            buffer.extend_from_slice(&f32::from(m.pm1_0_avg).to_be_bytes());
-            buffer.extend_from_slice(&m.timestamp.to_be_bytes());
-            buffer.push(pm2_5_index);
-            buffer.extend_from_slice(&f32::from(m.pm2_5_avg).to_be_bytes());
-        }
-        let checksum = buffer.iter().fold(0u8, |acc, &b| acc ^ b);
-        buffer.push(checksum);
-        Ok(buffer)
-    }
 }
 
 fn log_heap(tag: &str) {
@@ -398,6 +416,57 @@ fn log_heap(tag: &str) {
     );
 }
 
+/// `session` parameter of the request query string, if present and a valid UUID.
+fn query_session(req: *mut httpd_req_t) -> Option<Uuid> {
+    let query_len = unsafe { httpd_req_get_url_query_len(req) };
+    if query_len == 0 {
+        return None;
+    }
+    let mut query = vec![0u8; query_len + 1];
+    let mut value = [0u8; 40];
+    unsafe {
+        if httpd_req_get_url_query_str(req, query.as_mut_ptr() as *mut _, query.len()) != ESP_OK
+            || httpd_query_key_value(
+                query.as_ptr() as *const _,
+                c"session".as_ptr(),
+                value.as_mut_ptr() as *mut _,
+                value.len(),
+            ) != ESP_OK
+        {
+            return None;
+        }
+    }
+    let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+    Uuid::parse_str(std::str::from_utf8(&value[..len]).ok()?).ok()
+}
+
+/// The event log as stored; unlike `/sync` this does not end the manual sync.
+extern "C" fn events_get_handler(req: *mut httpd_req_t) -> esp_err_t {
+    let bytes = event_log::raw();
+    info!("events_get: sending {} bytes", bytes.len());
+    unsafe {
+        if httpd_resp_set_type(req, c"application/octet-stream".as_ptr()) != ESP_OK {
+            error!("events_get: set_type failed");
+            return ESP_FAIL;
+        }
+        httpd_resp_send(req, bytes.as_ptr() as *const _, bytes.len() as isize)
+    }
+}
+
+/// Data salvaged at boot, see `mount_recovery`; empty if there is none.
+extern "C" fn salvage_get_handler(req: *mut httpd_req_t) -> esp_err_t {
+    mount_recovery::with_salvaged(|bytes| {
+        info!("salvage_get: sending {} bytes", bytes.len());
+        unsafe {
+            if httpd_resp_set_type(req, c"application/octet-stream".as_ptr()) != ESP_OK {
+                error!("salvage_get: set_type failed");
+                return ESP_FAIL;
+            }
+            httpd_resp_send(req, bytes.as_ptr() as *const _, bytes.len() as isize)
+        }
+    })
+}
+
 extern "C" fn sync_get_handler(req: *mut httpd_req_t) -> esp_err_t {
     info!("sync_get: entered, opening file");
     let ctx_ptr = unsafe { (*req).user_ctx as *const SyncHandlerCtx };
@@ -407,25 +476,22 @@ extern "C" fn sync_get_handler(req: *mut httpd_req_t) -> esp_err_t {
     }
     let ctx = unsafe { &*ctx_ptr };
 
-    let path = match ctx.file_path.to_str() {
-        Ok(p) => p,
-        Err(_) => {
-            error!("sync_get: file_path not utf8");
-            return ESP_FAIL;
-        }
+    let Some(session) = query_session(req).or(ctx.default_session) else {
+        error!("sync_get: no session selected");
+        unsafe { httpd_resp_send_404(req) };
+        return ESP_OK;
     };
-    let file = match File::open(path) {
-        Ok(f) => f,
+    // The X-Content-SHA256 header has to match the body read after it.
+    let files = ctx.files.hold();
+    let (file, file_size) = match open_unsynced(&session) {
+        Ok(opened) => opened,
         Err(e) => {
-            error!("sync_get: file open failed: {:?}", e);
-            return ESP_FAIL;
-        }
-    };
-    let file_size = match file.metadata() {
-        Ok(m) => m.len(),
-        Err(e) => {
-            error!("sync_get: metadata failed: {:?}", e);
-            return ESP_FAIL;
+            error!(
+                "sync_get: file open failed for session {}: {:?}",
+                session, e
+            );
+            unsafe { httpd_resp_send_404(req) };
+            return ESP_OK;
         }
     };
     info!("sync_get: file_size = {}", file_size);
@@ -433,6 +499,15 @@ extern "C" fn sync_get_handler(req: *mut httpd_req_t) -> esp_err_t {
         Ok(s) => s,
         Err(_) => return ESP_FAIL,
     };
+    // Lets the client check the body before it acknowledges the sync and the data
+    // is removed; the body still goes out if the digest cannot be computed.
+    let sha256 = match digest_unsynced(&session, DigestAlgorithm::Sha256, 0, 0) {
+        Ok(range) => CString::new(range.digest.to_hex()).ok(),
+        Err(e) => {
+            error!("sync_get: digest failed for session {}: {:?}", session, e);
+            None
+        }
+    };
 
     // Receiver may be gone (notify_status failure post-BLE-drop); must not
     // abort the GET handler before the body goes out.
@@ -453,6 +528,12 @@ extern "C" fn sync_get_handler(req: *mut httpd_req_t) -> esp_err_t {
             error!("sync_get: set_hdr Content-Disposition failed");
             return ESP_FAIL;
         }
+        if let Some(sha256) = &sha256 {
+            if httpd_resp_set_hdr(req, c"X-Content-SHA256".as_ptr(), sha256.as_ptr()) != ESP_OK {
+                error!("sync_get: set_hdr X-Content-SHA256 failed");
+                return ESP_FAIL;
+            }
+        }
     }
 
     let mut reader = BufReader::with_capacity(CHUNK_SIZE, file);
@@ -515,6 +596,7 @@ extern "C" fn sync_get_handler(req: *mut httpd_req_t) -> esp_err_t {
         }
     }
 
+    drop(files);
     let _ = ctx.tx.send(SyncStatus::Done);
     ESP_OK
 }
@@ -0,0 +1,316 @@
+use anyhow::{bail, Context};
+
+/// Block sizes tried when looking for the superblock; ESP-IDF uses 4096.
+const BLOCK_SIZES: [usize; 6] = [4096, 512, 1024, 2048, 8192, 16384];
+const SUPERBLOCK_MAGIC: &[u8; 8] = b"littlefs";
+
+// Tag types, see littlefs SPEC.md
+const TYPE_REG: u32 = 0x001;
+const TYPE_DIR: u32 = 0x002;
+const TYPE_SUPERBLOCK: u32 = 0x0ff;
+const TYPE_DIRSTRUCT: u32 = 0x200;
+const TYPE_INLINESTRUCT: u32 = 0x201;
+const TYPE_CTZSTRUCT: u32 = 0x202;
+const TYPE_CREATE: u32 = 0x401;
+const TYPE_DELETE: u32 = 0x4ff;
+const TYPE_CRC: u32 = 0x500;
+const TYPE_HARDTAIL: u32 = 0x601;
+
+/// Read-only view of a littlefs image, enough to pull the files out of a dump of the
+/// storage partition. Pending moves are not resolved, so a dump taken in the middle
+/// of a rename can show a file twice.
+pub struct Image<'a> {
+    data: &'a [u8],
+    block_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub kind: Kind,
+    content: Content,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Content {
+    #[default]
+    None,
+    Dir([u32; 2]),
+    Inline(Vec<u8>),
+    Ctz {
+        head: u32,
+        size: u32,
+    },
+}
+
+/// One metadata block as of its last intact commit.
+#[derive(Debug, Clone, Default)]
+struct Metadata {
+    entries: Vec<Option<(u32, String, Content)>>,
+    hardtail: Option<[u32; 2]>,
+}
+
+impl<'a> Image<'a> {
+    /// Whether `data` starts with a littlefs superblock.
+    pub fn detect(data: &[u8]) -> bool {
+        data.get(8..16) == Some(SUPERBLOCK_MAGIC)
+    }
+
+    pub fn open(data: &'a [u8]) -> anyhow::Result<Self> {
+        for block_size in BLOCK_SIZES {
+            if data.len() < 2 * block_size {
+                continue;
+            }
+            let image = Self { data, block_size };
+            let Ok(root) = image.fetch([0, 1]) else {
+                continue;
+            };
+            let superblock = root
+                .entries
+                .iter()
+                .flatten()
+                .find_map(|(kind, name, content)| match content {
+                    Content::Inline(data) if *kind == TYPE_SUPERBLOCK && name == "littlefs" => {
+                        Some(data.clone())
+                    }
+                    _ => None,
+                });
+            if let Some(superblock) = superblock.filter(|s| s.len() >= 12) {
+                let size = u32::from_le_bytes(superblock[4..8].try_into().unwrap()) as usize;
+                if size == block_size {
+                    return Ok(image);
+                }
+            }
+        }
+        bail!("no littlefs superblock found")
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block(&self, block: u32) -> anyhow::Result<&'a [u8]> {
+        let start = block as usize * self.block_size;
+        self.data
+            .get(start..start + self.block_size)
+            .with_context(|| format!("block {} is outside the image", block))
+    }
+
+    /// The entries of the directory at `path` ("/" for the root).
+    pub fn read_dir(&self, path: &str) -> anyhow::Result<Vec<Entry>> {
+        let mut pair = [0, 1];
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let entry = self
+                .dir_entries(pair)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .with_context(|| format!("{} not found", path))?;
+            match entry.content {
+                Content::Dir(next) => pair = next,
+                _ => bail!("{} is not a directory", path),
+            }
+        }
+        self.dir_entries(pair)
+    }
+
+    pub fn read_file(&self, entry: &Entry) -> anyhow::Result<Vec<u8>> {
+        match &entry.content {
+            Content::Inline(data) => Ok(data.clone()),
+            Content::Ctz { head, size } => self.read_ctz(*head, *size as usize),
+            Content::None => Ok(Vec::new()),
+            Content::Dir(_) => bail!("{} is a directory", entry.name),
+        }
+    }
+
+    /// Entries of a directory, following its hard tails.
+    fn dir_entries(&self, mut pair: [u32; 2]) -> anyhow::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for _ in 0..self.data.len() / self.block_size {
+            let metadata = self.fetch(pair)?;
+            for (kind, name, content) in metadata.entries.into_iter().flatten() {
+                let kind = match kind {
+                    TYPE_REG => Kind::File,
+                    TYPE_DIR => Kind::Dir,
+                    _ => continue,
+                };
+                entries.push(Entry {
+                    name,
+                    kind,
+                    content,
+                });
+            }
+            match metadata.hardtail {
+                Some(tail) => pair = tail,
+                None => return Ok(entries),
+            }
+        }
+        bail!("directory tail loop")
+    }
+
+    /// The newer of a metadata pair's blocks that holds an intact commit.
+    fn fetch(&self, pair: [u32; 2]) -> anyhow::Result<Metadata> {
+        let mut best: Option<(u32, Metadata)> = None;
+        for block in pair {
+            let Some((revision, metadata)) = parse_metadata(self.block(block)?) else {
+                continue;
+            };
+            // Revision counts wrap; compare them as a sequence.
+            if best
+                .as_ref()
+                .is_none_or(|(best, _)| (revision.wrapping_sub(*best) as i32) > 0)
+            {
+                best = Some((revision, metadata));
+            }
+        }
+        best.map(|(_, metadata)| metadata)
+            .with_context(|| format!("no valid metadata in blocks {:?}", pair))
+    }
+
+    /// A file stored as a CTZ skip-list: block `n` of the file starts with pointers to
+    /// the blocks `n - 2^k` for `k` up to the trailing zeros of `n`, the first of which
+    /// is block `n - 1`.
+    fn read_ctz(&self, head: u32, size: usize) -> anyhow::Result<Vec<u8>> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let mut blocks = vec![head];
+        let mut block = head;
+        for _ in 0..self.ctz_index(size - 1) {
+            block = u32::from_le_bytes(self.block(block)?[..4].try_into().unwrap());
+            blocks.push(block);
+        }
+        let mut data = Vec::with_capacity(size);
+        for (index, block) in blocks.into_iter().rev().enumerate() {
+            let skip = if index == 0 {
+                0
+            } else {
+                4 * (index.trailing_zeros() as usize + 1)
+            };
+            let take = (self.block_size - skip).min(size - data.len());
+            data.extend_from_slice(&self.block(block)?[skip..skip + take]);
+        }
+        Ok(data)
+    }
+
+    /// Index of the block of a CTZ list holding byte `offset` of the file.
+    fn ctz_index(&self, offset: usize) -> usize {
+        let b = self.block_size - 2 * 4;
+        let i = offset / b;
+        if i == 0 {
+            return 0;
+        }
+        (offset - 4 * ((i - 1).count_ones() as usize + 2)) / b
+    }
+}
+
+/// Replay the commits of a metadata block, keeping the state after the last one whose
+/// CRC matches. `None` if not even the first commit is intact.
+fn parse_metadata(block: &[u8]) -> Option<(u32, Metadata)> {
+    let revision = u32::from_le_bytes(block.get(..4)?.try_into().unwrap());
+    let mut crc = crc32(0xffff_ffff, &block[..4]);
+    let mut offset = 4;
+    let mut ptag = 0xffff_ffffu32;
+    let mut pending = Metadata::default();
+    let mut committed = None;
+    while offset + 4 <= block.len() {
+        let raw = &block[offset..offset + 4];
+        let tag = u32::from_be_bytes(raw.try_into().unwrap()) ^ ptag;
+        if tag & 0x8000_0000 != 0 {
+            break;
+        }
+        let size = match tag & 0x3ff {
+            0x3ff => 0,
+            size => size as usize,
+        };
+        let Some(data) = block.get(offset + 4..offset + 4 + size) else {
+            break;
+        };
+        crc = crc32(crc, raw);
+        let kind = (tag >> 20) & 0x7ff;
+        if kind & 0x780 == TYPE_CRC {
+            if data.len() < 4 || crc != u32::from_le_bytes(data[..4].try_into().unwrap()) {
+                break;
+            }
+            committed = Some(pending.clone());
+            ptag = tag ^ ((kind & 1) << 31);
+            crc = 0xffff_ffff;
+        } else {
+            crc = crc32(crc, data);
+            apply_tag(
+                &mut pending,
+                kind,
+                ((tag >> 10) & 0x3ff) as usize,
+                data,
+                tag,
+            );
+            ptag = tag;
+        }
+        offset += 4 + size;
+    }
+    Some((revision, committed?))
+}
+
+fn apply_tag(metadata: &mut Metadata, kind: u32, id: usize, data: &[u8], tag: u32) {
+    let deleted = tag & 0x3ff == 0x3ff;
+    let entries = &mut metadata.entries;
+    match kind {
+        TYPE_CREATE => entries.insert(id.min(entries.len()), None),
+        TYPE_DELETE if id < entries.len() => {
+            entries.remove(id);
+        }
+        TYPE_REG | TYPE_DIR | TYPE_SUPERBLOCK => {
+            if entries.len() <= id {
+                entries.resize(id + 1, None);
+            }
+            let name = String::from_utf8_lossy(data).into_owned();
+            let content = entries[id].take().map(|(_, _, c)| c).unwrap_or_default();
+            entries[id] = Some((kind, name, content));
+        }
+        TYPE_DIRSTRUCT | TYPE_INLINESTRUCT | TYPE_CTZSTRUCT if !deleted => {
+            let word = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            let content = match kind {
+                TYPE_DIRSTRUCT if data.len() >= 8 => Content::Dir([word(0), word(4)]),
+                TYPE_CTZSTRUCT if data.len() >= 8 => Content::Ctz {
+                    head: word(0),
+                    size: word(4),
+                },
+                TYPE_INLINESTRUCT => Content::Inline(data.to_vec()),
+                _ => return,
+            };
+            if entries.len() <= id {
+                entries.resize(id + 1, None);
+            }
+            match &mut entries[id] {
+                Some((_, _, c)) => *c = content,
+                slot => *slot = Some((0, String::new(), content)),
+            }
+        }
+        TYPE_HARDTAIL if data.len() >= 8 => {
+            let word = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            metadata.hardtail = Some([word(0), word(4)]);
+        }
+        kind if kind & 0x700 == 0x600 => metadata.hardtail = None,
+        _ => {}
+    }
+}
+
+/// CRC-32 as littlefs uses it: reflected 0x04C11DB7, no final XOR.
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,482 @@
+mod littlefs;
+
+// The firmware's storage format code, shared so the tool reads files exactly the way
+// the device does. Its host tests run here too, since the firmware only builds for the
+// ESP32-C3; `cargo test` adds the firmware modules that only they need.
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../../../src/aqi.rs"]
+mod aqi;
+#[allow(dead_code)]
+#[path = "../../../src/sensor"]
+mod sensor {
+    #[cfg(test)]
+    pub mod i2c_bus;
+    pub mod measurement;
+    #[cfg(test)]
+    pub mod scd4x;
+    pub mod sensor_parser;
+    #[cfg(test)]
+    pub mod sensor_registry;
+    #[cfg(test)]
+    pub mod sht4x;
+}
+#[allow(dead_code)]
+#[path = "../../../src/storage"]
+mod storage {
+    #[cfg(test)]
+    pub mod aggregation;
+    #[cfg(test)]
+    pub mod capacity;
+    #[cfg(test)]
+    pub mod digest;
+    pub mod file_header;
+    #[cfg(test)]
+    pub mod flush_policy;
+    #[cfg(test)]
+    pub mod journal;
+    pub mod line_format;
+    pub mod repair;
+    pub mod storage_iterator;
+}
+#[allow(dead_code)]
+#[path = "../../../src/wifi"]
+mod wifi {
+    pub mod upload_payload;
+}
+
+use anyhow::{bail, Context};
+use littlefs::{Image, Kind};
+use sensor::measurement::Measurement;
+use std::io::{BufWriter, Cursor, ErrorKind, Write};
+use storage::repair;
+use storage::storage_iterator::ForwardMeasurementIter;
+
+const USAGE: &str = "\
+Usage: abm-dump [OPTIONS] <FILE>
+
+FILE is a session file or psm.bin copied off the device, a /sync download, or an
+image of the storage partition (or of the whole flash).
+
+Options:
+  --format <csv|json|ndjson>  Output format for the records [default: csv]
+  --from <UNIX>               Leave out records before this time
+  --to <UNIX>                 Leave out records after this time
+  --session <UUID>            Only decode this session of an image
+  --summary                   Print counts, gaps and corrupt regions instead of records
+  --gap <SECONDS>             Shortest gap the summary lists [default: 300]
+  --upload <OUT>              Write the records as a fixed-session upload payload
+  --streams <INDICES>         Server sensor index per stream for --upload, e.g. 1,2
+  --stream-count <N>          Streams per record of headerless files [default: 2]
+  --offset <BYTES>            Offset of the storage partition in an image
+  -h, --help                  Print this help";
+
+/// Offset of the storage partition in a full flash dump, see partitions.csv.
+const STORAGE_PARTITION_OFFSET: usize = 0x208000;
+/// Gaps listed by the summary of each file; the count covers all of them.
+const MAX_LISTED_GAPS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+struct Args {
+    input: String,
+    format: Format,
+    from: Option<u32>,
+    to: Option<u32>,
+    session: Option<String>,
+    summary: bool,
+    gap: u32,
+    upload: Option<String>,
+    streams: Vec<u8>,
+    stream_count: usize,
+    offset: Option<usize>,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut args = Self {
+            input: String::new(),
+            format: Format::Csv,
+            from: None,
+            to: None,
+            session: None,
+            summary: false,
+            gap: 300,
+            upload: None,
+            streams: Vec::new(),
+            stream_count: 2,
+            offset: None,
+        };
+        let mut input = None;
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .with_context(|| format!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--format" => {
+                    args.format = match value()?.as_str() {
+                        "csv" => Format::Csv,
+                        "json" => Format::Json,
+                        "ndjson" => Format::Ndjson,
+                        other => bail!("unknown format {}", other),
+                    }
+                }
+                "--from" => args.from = Some(value()?.parse().context("--from")?),
+                "--to" => args.to = Some(value()?.parse().context("--to")?),
+                "--session" => args.session = Some(value()?.replace('-', "").to_lowercase()),
+                "--summary" => args.summary = true,
+                "--gap" => args.gap = value()?.parse().context("--gap")?,
+                "--upload" => args.upload = Some(value()?),
+                "--streams" => {
+                    args.streams = value()?
+                        .split(',')
+                        .map(|index| index.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .context("--streams")?
+                }
+                "--stream-count" => {
+                    args.stream_count = value()?.parse().context("--stream-count")?
+                }
+                "--offset" => args.offset = Some(parse_offset(&value()?)?),
+                _ if arg.starts_with('-') => bail!("unknown option {}\n\n{}", arg, USAGE),
+                _ if input.is_none() => input = Some(arg),
+                _ => bail!("more than one input file\n\n{}", USAGE),
+            }
+        }
+        args.input = input.with_context(|| format!("no input file\n\n{}", USAGE))?;
+        if args.upload.is_some() && args.streams.is_empty() {
+            bail!("--upload needs --streams");
+        }
+        Ok(args)
+    }
+}
+
+fn parse_offset(value: &str) -> anyhow::Result<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .context("--offset")
+}
+
+/// A measurement file pulled out of the input.
+struct StorageFile {
+    name: String,
+    data: Vec<u8>,
+}
+
+/// The files of the input: the measurement files of a littlefs image, or the input
+/// itself.
+fn storage_files(data: Vec<u8>, args: &Args) -> anyhow::Result<Vec<StorageFile>> {
+    let offset = match args.offset {
+        Some(offset) => Some(offset),
+        None if Image::detect(&data) => Some(0),
+        None if data
+            .get(STORAGE_PARTITION_OFFSET..)
+            .is_some_and(Image::detect) =>
+        {
+            Some(STORAGE_PARTITION_OFFSET)
+        }
+        None => None,
+    };
+    let Some(offset) = offset else {
+        return Ok(vec![StorageFile {
+            name: args.input.clone(),
+            data,
+        }]);
+    };
+    let image = Image::open(data.get(offset..).context("--offset is past the input")?)?;
+    eprintln!(
+        "littlefs image at {:#x}, block size {}",
+        offset,
+        image.block_size()
+    );
+
+    let mut files = Vec::new();
+    for entry in image.read_dir("/")? {
+        if entry.kind == Kind::File && (entry.name == "psm.bin" || entry.name == "psm.prev") {
+            files.push(StorageFile {
+                name: format!("/{}", entry.name),
+                data: image.read_file(&entry)?,
+            });
+        }
+    }
+    let sessions = match image.read_dir("/sessions") {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("no sessions directory: {}", e);
+            Vec::new()
+        }
+    };
+    for entry in sessions {
+        if entry.kind == Kind::File && entry.name.ends_with(".bin") {
+            files.push(StorageFile {
+                name: format!("/sessions/{}", entry.name),
+                data: image.read_file(&entry)?,
+            });
+        }
+    }
+    if let Some(session) = &args.session {
+        files.retain(|file| file.name.contains(session.as_str()));
+    }
+    Ok(files)
+}
+
+fn lines<'a>(
+    file: &'a StorageFile,
+    args: &Args,
+) -> anyhow::Result<ForwardMeasurementIter<Cursor<&'a [u8]>>> {
+    ForwardMeasurementIter::new(Cursor::new(&file.data[..]), args.stream_count, 0)
+        .with_context(|| format!("{}: unreadable file header", file.name))
+}
+
+fn in_range(measurement: &Measurement, args: &Args) -> bool {
+    args.from.is_none_or(|from| measurement.timestamp >= from)
+        && args.to.is_none_or(|to| measurement.timestamp <= to)
+}
+
+fn records(file: &StorageFile, args: &Args) -> anyhow::Result<Vec<Measurement>> {
+    Ok(lines(file, args)?
+        .flat_map(|line| line.measurements)
+        .filter(|measurement| in_range(measurement, args))
+        .collect())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+    let data = std::fs::read(&args.input).with_context(|| format!("reading {}", args.input))?;
+    let files = storage_files(data, &args)?;
+    if files.is_empty() {
+        bail!("no measurement files found");
+    }
+
+    if let Some(out) = &args.upload {
+        return write_upload(&files, out, &args);
+    }
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    let written = if args.summary {
+        files
+            .iter()
+            .try_for_each(|file| write_summary(&mut stdout, file, &args))
+    } else {
+        write_records(&mut stdout, &files, &args)
+    };
+    match written.and_then(|()| Ok(stdout.flush()?)) {
+        // Piped into head or less and closed early
+        Err(e) if is_broken_pipe(&e) => Ok(()),
+        result => result,
+    }
+}
+
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::BrokenPipe)
+}
+
+fn write_records(out: &mut impl Write, files: &[StorageFile], args: &Args) -> anyhow::Result<()> {
+    let mut first = true;
+    if args.format == Format::Json {
+        write!(out, "[")?;
+    }
+    for file in files {
+        let lines = lines(file, args)?;
+        let stream_count = lines.stream_count();
+        if args.format == Format::Csv && first {
+            write!(out, "file,timestamp,time,flags")?;
+            for stream in 0..stream_count {
+                write!(out, ",stream{}", stream)?;
+            }
+            writeln!(out)?;
+        }
+        for measurement in lines.flat_map(|line| line.measurements) {
+            if !in_range(&measurement, args) {
+                continue;
+            }
+            let values: Vec<String> = measurement.values().iter().map(u16::to_string).collect();
+            match args.format {
+                Format::Csv => writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    file.name,
+                    measurement.timestamp,
+                    iso_time(measurement.timestamp),
+                    measurement.flags,
+                    values.join(",")
+                )?,
+                Format::Json | Format::Ndjson => {
+                    if args.format == Format::Json {
+                        write!(out, "{}\n  ", if first { "" } else { "," })?;
+                    }
+                    write!(
+                        out,
+                        "{{\"file\":{},\"timestamp\":{},\"time\":\"{}\",\"flags\":{},\"values\":[{}]}}",
+                        json_string(&file.name),
+                        measurement.timestamp,
+                        iso_time(measurement.timestamp),
+                        measurement.flags,
+                        values.join(",")
+                    )?;
+                    if args.format == Format::Ndjson {
+                        writeln!(out)?;
+                    }
+                }
+            }
+            first = false;
+        }
+    }
+    if args.format == Format::Json {
+        writeln!(out, "{}]", if first { "" } else { "\n" })?;
+    }
+    Ok(())
+}
+
+fn write_summary(out: &mut impl Write, file: &StorageFile, args: &Args) -> anyhow::Result<()> {
+    writeln!(out, "{} ({} bytes)", file.name, file.data.len())?;
+    let lines = lines(file, args)?;
+    let data_start = match lines.header() {
+        Some(header) => {
+            writeln!(out, "  header: {}", header.describe())?;
+            writeln!(out, "  created: {}", iso_time(header.created))?;
+            header.size as u64
+        }
+        None => {
+            writeln!(
+                out,
+                "  no header, read with {} streams",
+                lines.stream_count()
+            )?;
+            0
+        }
+    };
+
+    let report = repair::scan(lines, data_start, &mut std::io::sink())?;
+    writeln!(
+        out,
+        "  lines: {}, records: {}, duplicates: {}, out of order: {}",
+        report.lines, report.records, report.duplicates, report.out_of_order
+    )?;
+    writeln!(
+        out,
+        "  corrupt: {} bytes in {}{} ranges",
+        report.corrupt_bytes,
+        if report.corrupt_ranges.len() == repair::MAX_REPORTED_RANGES {
+            "at least "
+        } else {
+            ""
+        },
+        report.corrupt_ranges.len()
+    )?;
+    for (offset, len) in &report.corrupt_ranges {
+        writeln!(
+            out,
+            "    {:#x}..{:#x} ({} bytes)",
+            offset,
+            offset + len,
+            len
+        )?;
+    }
+
+    let records = records(file, args)?;
+    let (Some(first), Some(last)) = (records.first(), records.last()) else {
+        writeln!(out, "  no records in range")?;
+        return Ok(());
+    };
+    writeln!(
+        out,
+        "  {} records in range, {} to {}",
+        records.len(),
+        iso_time(first.timestamp),
+        iso_time(last.timestamp)
+    )?;
+    let gaps: Vec<(u32, u32)> = records
+        .windows(2)
+        .map(|pair| (pair[0].timestamp, pair[1].timestamp))
+        .filter(|(before, after)| after.saturating_sub(*before) >= args.gap)
+        .collect();
+    writeln!(out, "  gaps of {}s or more: {}", args.gap, gaps.len())?;
+    for (before, after) in gaps.iter().take(MAX_LISTED_GAPS) {
+        writeln!(
+            out,
+            "    {} to {} ({}s)",
+            iso_time(*before),
+            iso_time(*after),
+            after - before
+        )?;
+    }
+    Ok(())
+}
+
+/// The records as the body of a fixed-session measurements POST. Each session is
+/// uploaded on its own, so an image must be narrowed down to one with `--session`.
+fn write_upload(files: &[StorageFile], out: &str, args: &Args) -> anyhow::Result<()> {
+    let [file] = files else {
+        bail!(
+            "{} measurement files found, pick one with --session",
+            files.len()
+        );
+    };
+    let records = records(file, args)?;
+    let payload = wifi::upload_payload::encode(&records, &args.streams)
+        .context("too many entries for one payload, narrow it down with --from and --to")?;
+    std::fs::write(out, &payload).with_context(|| format!("writing {}", out))?;
+    eprintln!(
+        "{}: {} records, {} bytes",
+        out,
+        records.len(),
+        payload.len()
+    );
+    Ok(())
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// `2024-05-01T12:00:00Z` for a unix timestamp.
+fn iso_time(timestamp: u32) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+    // Civil date from days since the epoch, after Howard Hinnant's days_from_civil.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}